@@ -0,0 +1,527 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tracing::{info, warn};
+
+use crate::config::{AuthConfig, AuthMode};
+use crate::middleware::{forbidden, unauthorized};
+use crate::oidc::{find_key, JwksCache, ALLOWED_ALGORITHMS};
+use crate::secret::hex_digest;
+use crate::AppState;
+
+/// 创建授权流程、查询和管理自己发起的流程
+pub const SCOPE_AUTH_CREATE: &str = "auth:create";
+/// 完成授权、用取回码换取令牌
+pub const SCOPE_AUTH_COMPLETE: &str = "auth:complete";
+/// 自省令牌、查询刷新状态
+pub const SCOPE_TOKENS_READ: &str = "tokens:read";
+/// 在服务端口上读取Prometheus指标
+pub const SCOPE_METRICS_READ: &str = "metrics:read";
+
+/// API密钥文件中的一项
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyEntry {
+    /// 密钥名称，用于日志和事件归属
+    pub name: String,
+    /// 密钥的SHA-256（十六进制），文件中不保存明文
+    pub key_sha256: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// 通过认证的调用方，保存在请求扩展中供处理函数使用
+#[derive(Debug, Clone, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// JWT的sub，创建的授权流程绑定到该用户
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
+
+/// 路由的访问要求
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Public,
+    Authenticated,
+    Scope(&'static str),
+}
+
+/// 调用方JWT中使用的声明
+#[derive(Debug, Deserialize)]
+struct CallerClaims {
+    sub: String,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+/// JWT签名公钥来源
+enum JwksSource {
+    Remote { url: String, cache: JwksCache },
+    File(JwkSet),
+}
+
+/// SSO签发的JWT校验器
+struct JwtVerifier {
+    issuer: String,
+    audience: String,
+    keys: JwksSource,
+    group_scopes: BTreeMap<String, Vec<String>>,
+    clock_skew_seconds: u64,
+}
+
+impl JwtVerifier {
+    fn new(config: &AuthConfig, jwks_cache_seconds: u64, clock_skew_seconds: u64) -> Result<Self> {
+        let issuer = config
+            .jwt_issuer
+            .clone()
+            .ok_or_else(|| anyhow!("AUTH_MODE=jwt 需要配置 JWT_ISSUER"))?;
+        let audience = config
+            .jwt_audience
+            .clone()
+            .ok_or_else(|| anyhow!("AUTH_MODE=jwt 需要配置 JWT_AUDIENCE"))?;
+        let keys = match (&config.jwt_jwks_url, &config.jwt_jwks_path) {
+            (Some(url), None) => JwksSource::Remote {
+                url: url.clone(),
+                cache: JwksCache::new(reqwest::Client::new(), jwks_cache_seconds),
+            },
+            (None, Some(path)) => {
+                let keys: JwkSet = serde_json::from_slice(&std::fs::read(path)?)
+                    .map_err(|e| anyhow!("无法解析JWKS文件 {}: {}", path, e))?;
+                info!("从 {} 加载了 {} 个JWT签名公钥", path, keys.keys.len());
+                JwksSource::File(keys)
+            }
+            _ => return Err(anyhow!("AUTH_MODE=jwt 需要且只能配置 JWT_JWKS_URL 或 JWT_JWKS_PATH 之一")),
+        };
+
+        Ok(Self {
+            issuer,
+            audience,
+            keys,
+            group_scopes: config.jwt_group_scopes.clone(),
+            clock_skew_seconds,
+        })
+    }
+
+    /// 校验签名、iss、aud和exp，按groups映射权限
+    async fn verify(&self, token: &str) -> Result<Principal> {
+        let header = decode_header(token).map_err(|e| anyhow!("无效的JWT: {}", e))?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(anyhow!("不支持的JWT签名算法: {:?}", header.alg));
+        }
+
+        let kid = header.kid.as_deref();
+        let decoding_key = match &self.keys {
+            JwksSource::Remote { url, cache } => cache.decoding_key(url, kid).await?,
+            JwksSource::File(keys) => find_key(keys, kid)
+                .unwrap_or_else(|| Err(anyhow!("JWKS中未找到签名密钥: {:?}", kid)))?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.clock_skew_seconds;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = decode::<CallerClaims>(token, &decoding_key, &validation)
+            .map_err(|e| anyhow!("JWT校验失败: {}", e))?
+            .claims;
+
+        let scopes: BTreeSet<&String> = claims
+            .groups
+            .iter()
+            .filter_map(|group| self.group_scopes.get(group))
+            .flatten()
+            .collect();
+        Ok(Principal {
+            name: claims.sub.clone(),
+            scopes: scopes.into_iter().cloned().collect(),
+            subject: Some(claims.sub),
+        })
+    }
+}
+
+/// 调用方认证
+pub struct Authenticator {
+    mode: AuthMode,
+    /// 密钥SHA-256 -> 密钥配置
+    api_keys: HashMap<String, ApiKeyEntry>,
+    jwt: Option<JwtVerifier>,
+}
+
+impl Authenticator {
+    /// 创建认证器，JWT模式沿用ID令牌校验的JWKS缓存时间和时钟偏差
+    pub fn new(config: &AuthConfig, jwks_cache_seconds: u64, clock_skew_seconds: u64) -> Result<Self> {
+        match config.mode {
+            AuthMode::None => Ok(Self::with_api_keys(AuthMode::None, Vec::new())),
+            AuthMode::ApiKey => {
+                let path = config
+                    .api_keys_path
+                    .as_ref()
+                    .ok_or_else(|| {
+                        anyhow!("AUTH_MODE=api_key 需要配置 API_KEYS_PATH，不需要认证时请显式设置 AUTH_MODE=none")
+                    })?;
+                let keys: Vec<ApiKeyEntry> = serde_json::from_slice(&std::fs::read(path)?)
+                    .map_err(|e| anyhow!("无法解析API密钥文件 {}: {}", path, e))?;
+                if keys.is_empty() {
+                    return Err(anyhow!("API密钥文件 {} 中没有配置任何密钥", path));
+                }
+                info!("从 {} 加载了 {} 个API密钥", path, keys.len());
+                Ok(Self::with_api_keys(AuthMode::ApiKey, keys))
+            }
+            AuthMode::Jwt => Ok(Self {
+                mode: AuthMode::Jwt,
+                api_keys: HashMap::new(),
+                jwt: Some(JwtVerifier::new(config, jwks_cache_seconds, clock_skew_seconds)?),
+            }),
+        }
+    }
+
+    pub fn with_api_keys(mode: AuthMode, api_keys: Vec<ApiKeyEntry>) -> Self {
+        Self {
+            mode,
+            api_keys: api_keys
+                .into_iter()
+                .map(|entry| (entry.key_sha256.to_ascii_lowercase(), entry))
+                .collect(),
+            jwt: None,
+        }
+    }
+
+    pub fn mode(&self) -> AuthMode {
+        self.mode
+    }
+
+    /// 校验请求携带的API密钥或JWT
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Principal> {
+        let credential = presented_credential(headers).ok_or_else(|| anyhow!("缺少调用方凭据"))?;
+        match &self.jwt {
+            Some(jwt) => jwt.verify(credential).await,
+            None => self.authenticate_api_key(credential),
+        }
+    }
+
+    fn authenticate_api_key(&self, key: &str) -> Result<Principal> {
+        let entry = self
+            .api_keys
+            .get(&hex_digest(key))
+            .ok_or_else(|| anyhow!("无效的API密钥"))?;
+        if entry.expires_at.is_some_and(|expires_at| Utc::now() >= expires_at) {
+            return Err(anyhow!("API密钥 {} 已过期", entry.name));
+        }
+        Ok(Principal {
+            name: entry.name.clone(),
+            scopes: entry.scopes.clone(),
+            subject: None,
+        })
+    }
+}
+
+/// 请求携带的凭据：`X-API-Key` 或 `Authorization: Bearer`
+pub fn presented_credential(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|value| value.to_str().ok()) {
+        return Some(key.trim()).filter(|key| !key.is_empty());
+    }
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credential) = authorization.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| credential.trim())
+        .filter(|credential| !credential.is_empty())
+}
+
+/// 路由需要的权限；浏览器回调由state保护，不要求API密钥
+fn required_access(path: &str) -> Access {
+    match path {
+        "/api/oauth/callback" => Access::Public,
+        "/api/auth-url" => Access::Scope(SCOPE_AUTH_CREATE),
+        "/api/complete-auth" | "/api/redeem" => Access::Scope(SCOPE_AUTH_COMPLETE),
+        "/api/introspect" => Access::Scope(SCOPE_TOKENS_READ),
+        "/metrics" => Access::Scope(SCOPE_METRICS_READ),
+        _ if path.starts_with("/api/auth-status/")
+            || path.starts_with("/api/auth-events/")
+            || path.starts_with("/api/auth/") =>
+        {
+            Access::Scope(SCOPE_AUTH_CREATE)
+        }
+        _ if path.starts_with("/api/tokens/") => Access::Scope(SCOPE_TOKENS_READ),
+        _ if path.starts_with("/api/") => Access::Authenticated,
+        _ => Access::Public,
+    }
+}
+
+/// 认证中间件，通过后把 [`Principal`] 放入请求扩展
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let access = required_access(request.uri().path());
+    if access == Access::Public || state.authenticator.mode() == AuthMode::None {
+        return next.run(request).await;
+    }
+
+    let principal = match state.authenticator.authenticate(request.headers()).await {
+        Ok(principal) => principal,
+        Err(e) => {
+            warn!("认证失败, 路径: {}, 原因: {}", request.uri().path(), e);
+            let mut response = unauthorized(e.to_string());
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return response;
+        }
+    };
+
+    if let Access::Scope(scope) = access {
+        if !principal.has_scope(scope) {
+            warn!("权限不足, 调用方: {}, 需要: {}", principal.name, scope);
+            return forbidden(format!("缺少权限: {}", scope));
+        }
+    }
+
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::tests::{spawn_jwks_server, TestSigner};
+    use axum::{body::Body, http::StatusCode, routing::get, Extension, Router};
+    use std::sync::Arc;
+    use tower::Service;
+
+    fn key(name: &str, secret: &str, scopes: &[&str], expires_at: Option<DateTime<Utc>>) -> ApiKeyEntry {
+        ApiKeyEntry {
+            name: name.to_string(),
+            key_sha256: hex_digest(secret),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_at,
+        }
+    }
+
+    fn app() -> Router {
+        let mut state = crate::handlers::tests::app_state();
+        state.authenticator = Arc::new(Authenticator::with_api_keys(
+            AuthMode::ApiKey,
+            vec![
+                key("creator", "secret-1", &[SCOPE_AUTH_CREATE], None),
+                key("reader", "secret-2", &[SCOPE_TOKENS_READ], None),
+                key("prometheus", "secret-4", &[SCOPE_METRICS_READ], None),
+                key(
+                    "retired",
+                    "secret-3",
+                    &[SCOPE_AUTH_CREATE],
+                    Some(Utc::now() - chrono::Duration::minutes(1)),
+                ),
+            ],
+        ));
+        let whoami = |principal: Option<Extension<Principal>>| async move {
+            principal.map(|Extension(principal)| principal.name).unwrap_or_default()
+        };
+        Router::new()
+            .route("/api/auth-url", get(whoami))
+            .route("/api/oauth/callback", get(whoami))
+            .route("/health", get(whoami))
+            .route("/metrics", get(whoami))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), authenticate))
+            .with_state(state)
+    }
+
+    async fn call(app: &mut Router, path: &str, headers: &[(&str, &str)]) -> (StatusCode, String) {
+        let mut request = Request::get(path);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = app.call(request.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_api_key_grants_scoped_access() {
+        let mut app = app();
+
+        let (status, name) = call(&mut app, "/api/auth-url", &[("x-api-key", "secret-1")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(name, "creator");
+
+        let (status, name) =
+            call(&mut app, "/api/auth-url", &[("authorization", "Bearer secret-1")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(name, "creator");
+
+        let (status, _) = call(&mut app, "/api/auth-url", &[("x-api-key", "secret-2")]).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_missing_invalid_or_expired_key_is_rejected() {
+        let mut app = app();
+
+        for headers in [
+            vec![],
+            vec![("x-api-key", "wrong")],
+            vec![("authorization", "Basic secret-1")],
+            vec![("x-api-key", "secret-3")],
+        ] {
+            let (status, _) = call(&mut app, "/api/auth-url", &headers).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_routes_skip_authentication() {
+        let mut app = app();
+
+        assert_eq!(call(&mut app, "/health", &[]).await.0, StatusCode::OK);
+        assert_eq!(call(&mut app, "/api/oauth/callback", &[]).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_requires_metrics_scope() {
+        let mut app = app();
+
+        assert_eq!(call(&mut app, "/metrics", &[]).await.0, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&mut app, "/metrics", &[("x-api-key", "secret-1")]).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, name) = call(&mut app, "/metrics", &[("x-api-key", "secret-4")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(name, "prometheus");
+    }
+
+    fn jwt_config() -> AuthConfig {
+        let mut config = crate::config::AppConfig::default().auth;
+        config.mode = AuthMode::Jwt;
+        config.jwt_issuer = Some("https://sso.example".to_string());
+        config.jwt_audience = Some("oauth-service".to_string());
+        config.jwt_group_scopes = [("apps".to_string(), vec![SCOPE_AUTH_CREATE.to_string()])].into();
+        config
+    }
+
+    fn jwt_app(authenticator: Authenticator) -> Router {
+        let mut state = crate::handlers::tests::app_state();
+        state.authenticator = Arc::new(authenticator);
+        let whoami = |Extension(principal): Extension<Principal>| async move {
+            format!("{}:{}", principal.subject.unwrap_or_default(), principal.scopes.join(","))
+        };
+        Router::new()
+            .route("/api/auth-url", get(whoami))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), authenticate))
+            .with_state(state)
+    }
+
+    fn caller_claims(overrides: serde_json::Value) -> serde_json::Value {
+        let now = Utc::now().timestamp();
+        let mut claims = serde_json::json!({
+            "iss": "https://sso.example",
+            "aud": "oauth-service",
+            "sub": "alice",
+            "groups": ["apps", "unmapped"],
+            "iat": now,
+            "exp": now + 300,
+        });
+        for (name, value) in overrides.as_object().unwrap() {
+            claims[name] = value.clone();
+        }
+        claims
+    }
+
+    #[tokio::test]
+    async fn test_jwt_maps_groups_to_scopes() {
+        let signer = TestSigner::generate("sso-1");
+        let path = std::env::temp_dir().join(format!("jwks-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, serde_json::json!({ "keys": [signer.jwk.clone()] }).to_string()).unwrap();
+        let mut config = jwt_config();
+        config.jwt_jwks_path = Some(path.to_string_lossy().to_string());
+        let mut app = jwt_app(Authenticator::new(&config, 3600, 60).unwrap());
+
+        let bearer = |claims: serde_json::Value| format!("Bearer {}", signer.sign(&caller_claims(claims)));
+
+        let (status, body) =
+            call(&mut app, "/api/auth-url", &[("authorization", &bearer(serde_json::json!({})))]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "alice:auth:create");
+
+        // 没有映射到所需权限的组
+        let token = bearer(serde_json::json!({ "groups": ["unmapped"] }));
+        let (status, _) = call(&mut app, "/api/auth-url", &[("authorization", &token)]).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let now = Utc::now().timestamp();
+        for claims in [
+            serde_json::json!({ "aud": "other-service" }),
+            serde_json::json!({ "iss": "https://evil.example" }),
+            serde_json::json!({ "exp": now - 600 }),
+        ] {
+            let token = bearer(claims.clone());
+            let (status, _) = call(&mut app, "/api/auth-url", &[("authorization", &token)]).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", claims);
+        }
+
+        let attacker = TestSigner::generate("sso-1");
+        let token = format!("Bearer {}", attacker.sign(&caller_claims(serde_json::json!({}))));
+        let (status, _) = call(&mut app, "/api/auth-url", &[("authorization", &token)]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_jwt_keys_from_jwks_url() {
+        let signer = TestSigner::generate("sso-1");
+        let addr = spawn_jwks_server(serde_json::json!({ "keys": [signer.jwk.clone()] })).await;
+        let mut config = jwt_config();
+        config.jwt_jwks_url = Some(format!("http://{}/jwks", addr));
+        let mut app = jwt_app(Authenticator::new(&config, 3600, 60).unwrap());
+
+        let token = format!("Bearer {}", signer.sign(&caller_claims(serde_json::json!({}))));
+        let (status, body) = call(&mut app, "/api/auth-url", &[("authorization", &token)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "alice:auth:create");
+    }
+
+    #[test]
+    fn test_jwt_mode_requires_single_key_source() {
+        assert!(Authenticator::new(&jwt_config(), 3600, 60).is_err());
+
+        let mut config = jwt_config();
+        config.jwt_jwks_url = Some("https://sso.example/jwks".to_string());
+        config.jwt_jwks_path = Some("/etc/jwks.json".to_string());
+        assert!(Authenticator::new(&config, 3600, 60).is_err());
+    }
+
+    #[test]
+    fn test_api_key_mode_requires_key_file() {
+        // 默认即为API密钥模式，未配置密钥时拒绝启动
+        let mut config = crate::config::AppConfig::default().auth;
+        assert_eq!(config.mode, AuthMode::ApiKey);
+        assert!(Authenticator::new(&config, 3600, 60).is_err());
+
+        let path = std::env::temp_dir().join(format!("api-keys-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[]").unwrap();
+        config.api_keys_path = Some(path.to_string_lossy().into_owned());
+        let result = Authenticator::new(&config, 3600, 60);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::models::CompleteAuthData;
+
+/// 完成授权失败时返回给所有等待方的错误
+#[derive(Debug, Clone)]
+pub struct CompletionFailure {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+/// 一次令牌交换的结果
+pub type CompletionResult = Result<CompleteAuthData, CompletionFailure>;
+
+/// 等待交换结果的接收端
+pub type CompletionReceiver = watch::Receiver<Option<CompletionResult>>;
+
+/// 开始完成授权时的判定结果
+#[derive(Debug)]
+pub enum Begin {
+    /// 首次提交，由当前请求交换令牌并在结束时调用 [`CompletionRegistry::finish`]
+    Leader(CompletionReceiver),
+    /// 相同的请求正在交换，等待它的结果
+    Follower(CompletionReceiver),
+    /// 相同请求已成功完成，直接返回之前的结果
+    Replay(Box<CompleteAuthData>),
+    /// 该state正在被其他请求处理或已经使用过
+    Conflict,
+}
+
+enum Completion {
+    InFlight(watch::Sender<Option<CompletionResult>>),
+    Succeeded(Box<CompleteAuthData>),
+    Failed,
+}
+
+struct CompletionEntry {
+    /// sha256(code, tenant_url)，用于识别相同的请求
+    fingerprint: Vec<u8>,
+    completion: Completion,
+    updated_at: DateTime<Utc>,
+}
+
+/// 完成授权请求登记表 (state -> 处理进度)
+///
+/// 保证每个state只交换一次令牌：相同 (state, code, tenant_url) 的并发请求合并为一次交换，
+/// 所有等待方得到同一个结果；其他针对同一state的提交得到确定的冲突结果，
+/// 与成功请求完全相同的重试在重放窗口内得到相同的响应。
+pub struct CompletionRegistry {
+    entries: DashMap<String, CompletionEntry>,
+    replay_window: Duration,
+}
+
+impl CompletionRegistry {
+    pub fn new(replay_seconds: u64) -> Self {
+        Self {
+            entries: DashMap::new(),
+            replay_window: Duration::seconds(replay_seconds as i64),
+        }
+    }
+
+    /// 原子地登记一次完成授权请求
+    pub fn begin(&self, state: &str, code: &str, tenant_url: &str) -> Begin {
+        let fingerprint = fingerprint(code, tenant_url);
+
+        match self.entries.entry(state.to_string()) {
+            Entry::Occupied(entry) => {
+                let entry = entry.get();
+                if entry.fingerprint != fingerprint {
+                    return Begin::Conflict;
+                }
+                match &entry.completion {
+                    Completion::InFlight(sender) => Begin::Follower(sender.subscribe()),
+                    Completion::Succeeded(data)
+                        if Utc::now() - entry.updated_at < self.replay_window =>
+                    {
+                        Begin::Replay(data.clone())
+                    }
+                    _ => Begin::Conflict,
+                }
+            }
+            Entry::Vacant(entry) => {
+                let (sender, receiver) = watch::channel(None);
+                entry.insert(CompletionEntry {
+                    fingerprint,
+                    completion: Completion::InFlight(sender),
+                    updated_at: Utc::now(),
+                });
+                Begin::Leader(receiver)
+            }
+        }
+    }
+
+    /// 记录交换结果并通知所有等待方
+    pub fn finish(&self, state: &str, result: CompletionResult) {
+        let Some(mut entry) = self.entries.get_mut(state) else {
+            return;
+        };
+
+        let completion = match &result {
+            Ok(data) => Completion::Succeeded(Box::new(data.clone())),
+            Err(_) => Completion::Failed,
+        };
+        if let Completion::InFlight(sender) = std::mem::replace(&mut entry.completion, completion) {
+            sender.send_replace(Some(result));
+        }
+        entry.updated_at = Utc::now();
+    }
+
+    /// 清理超过重放窗口的已结束记录
+    pub fn prune(&self) {
+        let cutoff = Utc::now() - self.replay_window;
+        self.entries.retain(|_, entry| {
+            matches!(entry.completion, Completion::InFlight(_)) || entry.updated_at > cutoff
+        });
+    }
+}
+
+/// 等待交换结果
+pub async fn wait(mut receiver: CompletionReceiver) -> CompletionResult {
+    match receiver.wait_for(Option::is_some).await {
+        Ok(result) => result.clone().expect("wait_for 保证结果存在"),
+        Err(_) => Err(CompletionFailure {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "授权处理意外中断，请重新获取授权链接".to_string(),
+        }),
+    }
+}
+
+fn fingerprint(code: &str, tenant_url: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(code.as_bytes());
+    hasher.update([0]);
+    hasher.update(tenant_url.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::completed_data;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_identical_concurrent_requests_share_one_result() {
+        let registry = Arc::new(CompletionRegistry::new(60));
+
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    let (leader, receiver) =
+                        match registry.begin("state-1", "code-1", "https://tenant.example/") {
+                            Begin::Leader(receiver) => (true, receiver),
+                            Begin::Follower(receiver) => (false, receiver),
+                            other => panic!("unexpected {:?}", other),
+                        };
+                    if leader {
+                        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                        registry.finish("state-1", Ok(completed_data()));
+                    }
+                    (leader, wait(receiver).await.unwrap().token)
+                })
+            })
+            .collect();
+
+        let mut leaders = 0;
+        for handle in handles {
+            let (leader, token) = handle.await.unwrap();
+            leaders += leader as usize;
+            assert_eq!(token.as_str(), "access-token");
+        }
+        assert_eq!(leaders, 1);
+    }
+
+    #[tokio::test]
+    async fn test_followers_receive_failure() {
+        let registry = CompletionRegistry::new(60);
+        let tenant_url = "https://tenant.example/";
+        let Begin::Leader(_) = registry.begin("state-1", "code-1", tenant_url) else {
+            panic!("expected leader");
+        };
+        let Begin::Follower(receiver) = registry.begin("state-1", "code-1", tenant_url) else {
+            panic!("expected follower");
+        };
+
+        registry.finish(
+            "state-1",
+            Err(CompletionFailure {
+                status: StatusCode::BAD_GATEWAY,
+                message: "upstream failed".to_string(),
+            }),
+        );
+
+        let failure = wait(receiver).await.unwrap_err();
+        assert_eq!(failure.status, StatusCode::BAD_GATEWAY);
+        // 失败后的重试不再交换
+        assert!(matches!(registry.begin("state-1", "code-1", tenant_url), Begin::Conflict));
+    }
+
+    #[test]
+    fn test_identical_retry_replays_success() {
+        let registry = CompletionRegistry::new(60);
+        let tenant_url = "https://tenant.example/";
+        assert!(matches!(registry.begin("state-1", "code-1", tenant_url), Begin::Leader(_)));
+
+        // 处理中提交不同的授权码
+        assert!(matches!(registry.begin("state-1", "code-2", tenant_url), Begin::Conflict));
+
+        let data = completed_data();
+        registry.finish("state-1", Ok(data.clone()));
+
+        match registry.begin("state-1", "code-1", tenant_url) {
+            Begin::Replay(replayed) => assert_eq!(replayed.token, data.token),
+            other => panic!("expected replay, got {:?}", other),
+        }
+        assert!(matches!(registry.begin("state-1", "code-2", tenant_url), Begin::Conflict));
+        assert!(matches!(registry.begin("state-1", "code-1", "https://other.example/"), Begin::Conflict));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_leader_does_not_hang_followers() {
+        let registry = CompletionRegistry::new(60);
+        let tenant_url = "https://tenant.example/";
+        let Begin::Leader(_) = registry.begin("state-1", "code-1", tenant_url) else {
+            panic!("expected leader");
+        };
+        let Begin::Follower(receiver) = registry.begin("state-1", "code-1", tenant_url) else {
+            panic!("expected follower");
+        };
+
+        registry.entries.clear();
+        assert!(wait(receiver).await.is_err());
+    }
+
+    #[test]
+    fn test_prune_keeps_in_flight_entries() {
+        let registry = CompletionRegistry::new(0);
+        let tenant_url = "https://tenant.example/";
+        registry.begin("state-1", "code-1", tenant_url);
+
+        registry.prune();
+        assert!(matches!(registry.begin("state-1", "code-1", tenant_url), Begin::Follower(_)));
+
+        registry.finish("state-1", Ok(completed_data()));
+        registry.prune();
+        assert!(matches!(registry.begin("state-1", "code-1", tenant_url), Begin::Leader(_)));
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::net::{SocketAddr, TcpListener};
 use tracing::{debug, info, warn};
 
+use crate::secret::Secret;
+
 /// 应用配置结构
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
@@ -11,6 +14,22 @@ pub struct AppConfig {
     pub server: ServerConfig,
     /// OAuth配置
     pub oauth: OAuthConfig,
+    /// 令牌后台刷新配置
+    pub refresh: RefreshConfig,
+    /// 持久化存储配置
+    pub storage: StorageConfig,
+    /// Webhook通知配置
+    pub webhook: WebhookConfig,
+    /// 请求限流配置
+    pub rate_limit: RateLimitConfig,
+    /// 调用方认证配置
+    pub auth: AuthConfig,
+    /// 跨域访问配置
+    pub cors: CorsConfig,
+    /// Prometheus指标配置
+    pub metrics: MetricsConfig,
+    /// 链路追踪配置
+    pub telemetry: TelemetryConfig,
 }
 
 /// 服务器配置
@@ -20,8 +39,33 @@ pub struct ServerConfig {
     pub host: String,
     /// 监听端口
     pub port: u16,
-    /// 日志级别
+    /// 日志过滤指令（EnvFilter语法），如 `info,augment_oauth_service=debug`
     pub log_level: String,
+    /// 日志输出格式
+    pub log_format: LogFormat,
+    /// 日志文件路径，设置后同时写入按周期轮转的日志文件
+    pub log_file: Option<String>,
+    /// 日志文件轮转周期
+    pub log_rotation: LogRotation,
+    /// HTTPS证书链路径（PEM格式），与私钥同时配置时启用TLS
+    pub tls_cert_path: Option<String>,
+    /// HTTPS私钥路径（PEM格式）
+    pub tls_key_path: Option<String>,
+    /// 检查证书文件变化的间隔（秒）
+    pub tls_reload_interval_seconds: u64,
+    /// HTTP到HTTPS重定向监听端口，未设置时不监听
+    pub http_redirect_port: Option<u16>,
+    /// 收到停止信号后等待进行中的请求和后台任务结束的最长时间（秒）
+    pub shutdown_timeout_seconds: u64,
+}
+
+/// 一个tenant的mTLS客户端证书
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientCertConfig {
+    /// 客户端证书路径（PEM格式）
+    pub cert_path: String,
+    /// 客户端私钥路径（PEM格式）
+    pub key_path: String,
 }
 
 /// OAuth配置
@@ -33,6 +77,227 @@ pub struct OAuthConfig {
     pub client_id: String,
     /// 状态过期时间（分钟）
     pub state_expire_minutes: u32,
+    /// 调用方可申请的最长状态有效期（分钟）
+    pub state_max_ttl_minutes: u32,
+    /// 授权流程结束后保留可查询的时间（分钟）
+    pub flow_retention_minutes: u32,
+    /// 相同的完成授权重试返回已有结果的时间窗口（秒）
+    pub completion_replay_seconds: u64,
+    /// 同时等待授权的OAuth状态上限，为0时不限制
+    pub max_pending_states: usize,
+    /// 单个用户同时等待授权的OAuth状态上限，为0时不限制
+    pub max_pending_states_per_user: usize,
+    /// 达到上限时的处理策略
+    pub state_overflow_policy: StateOverflowPolicy,
+    /// 允许的 return_url 源（scheme://host[:port]）
+    pub return_url_allowed_origins: Vec<String>,
+    /// 一次性取回码有效期（秒）
+    pub retrieval_code_ttl_seconds: u64,
+    /// 受信任的tenant地址，mTLS客户端证书只出示给这些tenant
+    pub trusted_tenants: Vec<String>,
+    /// 授权服务器元数据缓存时间（秒）
+    pub metadata_cache_seconds: u64,
+    /// 各tenant的mTLS客户端证书 (tenant_url -> 证书和私钥，RFC 8705)，tenant必须受信任
+    pub client_certs: BTreeMap<String, ClientCertConfig>,
+    /// 额外信任的CA证书路径（PEM格式）
+    pub ca_cert_path: Option<String>,
+    /// 是否申请DPoP绑定的令牌 (RFC 9449)
+    pub dpop_enabled: bool,
+    /// ID令牌的期望签发者，未设置时使用授权服务器元数据中的issuer
+    pub oidc_issuer: Option<String>,
+    /// JWKS地址，未设置时使用授权服务器元数据中的jwks_uri
+    pub oidc_jwks_url: Option<String>,
+    /// 校验ID令牌时允许的时钟偏差（秒）
+    pub id_token_clock_skew_seconds: u64,
+    /// JWKS缓存时间（秒）
+    pub jwks_cache_seconds: u64,
+    /// 令牌自省结果缓存时间（秒）
+    pub introspection_cache_seconds: u64,
+}
+
+/// 日志输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// 便于阅读的文本
+    Text,
+    /// 每行一个JSON对象，包含所在span的字段
+    Json,
+}
+
+/// 日志文件轮转周期
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    /// 不轮转，始终写入同一个文件
+    Never,
+}
+
+/// 等待授权的OAuth状态达到上限时的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateOverflowPolicy {
+    /// 拒绝新的授权请求
+    Reject,
+    /// 淘汰最早创建的状态
+    EvictOldest,
+}
+
+/// 令牌后台刷新配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshConfig {
+    /// 是否启用后台刷新
+    pub enabled: bool,
+    /// 扫描间隔（秒）
+    pub interval_seconds: u64,
+    /// 在过期前多少秒开始刷新
+    pub window_seconds: u64,
+    /// 刷新开始前的随机延迟上限（秒），避免集中请求授权服务器
+    pub jitter_seconds: u64,
+    /// 同时进行的刷新请求数上限
+    pub max_concurrency: usize,
+}
+
+/// 持久化存储配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageConfig {
+    /// token记录持久化文件，未设置时仅保存在内存中
+    pub token_store_path: Option<String>,
+}
+
+/// Webhook通知配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// 订阅地址，为空时不发送webhook
+    pub urls: Vec<String>,
+    /// HMAC签名密钥
+    pub secret: Option<Secret<String>>,
+    /// 订阅的事件类型，为空时订阅全部事件
+    pub events: Vec<String>,
+    /// 最大投递次数
+    pub max_attempts: u32,
+    /// 重试退避的基础间隔（秒）
+    pub retry_base_seconds: u64,
+    /// 单次投递超时（秒）
+    pub timeout_seconds: u64,
+    /// 投递队列持久化文件，未设置时仅保存在内存中
+    pub queue_path: Option<String>,
+}
+
+/// 请求限流配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// 是否启用限流
+    pub enabled: bool,
+    /// 受信任的反向代理（IP或CIDR），来自这些地址的请求按 X-Forwarded-For 识别客户端
+    pub trusted_proxies: Vec<String>,
+    /// 获取授权链接的限制
+    pub auth_url: RouteLimit,
+    /// 完成授权（含浏览器回调）的限制
+    pub complete_auth: RouteLimit,
+}
+
+/// 调用方认证配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// 认证方式
+    pub mode: AuthMode,
+    /// API密钥文件（JSON数组，只保存密钥的SHA-256）
+    pub api_keys_path: Option<String>,
+    /// 调用方JWT的签发者
+    pub jwt_issuer: Option<String>,
+    /// 调用方JWT的受众
+    pub jwt_audience: Option<String>,
+    /// JWT签名公钥地址
+    pub jwt_jwks_url: Option<String>,
+    /// JWT签名公钥文件（JWKS格式），与地址二选一
+    pub jwt_jwks_path: Option<String>,
+    /// JWT groups声明到权限的映射 (group -> scopes)
+    pub jwt_group_scopes: BTreeMap<String, Vec<String>>,
+}
+
+/// `/api/*` 的认证方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// 不认证
+    None,
+    /// 静态API密钥
+    ApiKey,
+    /// SSO签发的JWT
+    Jwt,
+}
+
+/// 跨域访问配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// 未单独配置的路由组使用的策略
+    pub default: CorsPolicy,
+    /// 按路由组覆盖的策略（`auth`、`tokens`）
+    pub groups: BTreeMap<String, CorsPolicy>,
+}
+
+/// 单个路由组的跨域策略
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorsPolicy {
+    /// 允许的源，支持 `https://*.example.com` 形式的子域名通配；`*` 表示允许任意源
+    pub allowed_origins: Vec<String>,
+    /// 允许的方法
+    pub allowed_methods: Vec<String>,
+    /// 允许的请求头
+    pub allowed_headers: Vec<String>,
+    /// 是否允许携带Cookie等凭据
+    pub allow_credentials: bool,
+    /// 预检结果缓存时间（秒）
+    pub max_age_seconds: u64,
+}
+
+/// Prometheus指标配置，需要启用 `metrics` 编译特性
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    /// 是否提供 `/metrics`
+    pub enabled: bool,
+    /// 管理端口，设置后 `/metrics` 只在该端口提供，不再挂在服务端口上
+    pub admin_port: Option<u16>,
+}
+
+/// 链路追踪配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    /// OTLP收集器地址，未设置时不导出链路
+    pub otlp_endpoint: Option<String>,
+    /// OTLP传输协议
+    pub otlp_protocol: OtlpProtocol,
+    /// 上报的服务名（`service.name`）
+    pub service_name: String,
+    /// 没有上游采样决定时的采样比例（0.0-1.0），调用方传入 `traceparent` 时沿用调用方的决定
+    pub sampling_ratio: f64,
+    /// 附加的资源属性，如 `deployment.environment`
+    pub resource_attributes: BTreeMap<String, String>,
+}
+
+/// OTLP传输协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtlpProtocol {
+    /// gRPC，收集器默认端口4317
+    Grpc,
+    /// HTTP + protobuf，收集器默认端口4318
+    HttpProtobuf,
+}
+
+/// 可以单独配置跨域策略的路由组
+pub const CORS_ROUTE_GROUPS: &[&str] = &["auth", "tokens"];
+
+/// 单个路由的令牌桶参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteLimit {
+    /// 每分钟补充的请求数，为0时不限制
+    pub per_minute: u32,
+    /// 允许的突发请求数
+    pub burst: u32,
 }
 
 impl Default for AppConfig {
@@ -42,11 +307,101 @@ impl Default for AppConfig {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
                 log_level: "info".to_string(),
+                log_format: LogFormat::Text,
+                log_file: None,
+                log_rotation: LogRotation::Daily,
+                tls_cert_path: None,
+                tls_key_path: None,
+                tls_reload_interval_seconds: 30,
+                http_redirect_port: None,
+                shutdown_timeout_seconds: 30,
             },
             oauth: OAuthConfig {
                 auth_url: "https://auth.augmentcode.com/authorize".to_string(),
                 client_id: "v".to_string(),
                 state_expire_minutes: 30,
+                state_max_ttl_minutes: 120,
+                flow_retention_minutes: 60,
+                completion_replay_seconds: 60,
+                max_pending_states: 10000,
+                max_pending_states_per_user: 20,
+                state_overflow_policy: StateOverflowPolicy::Reject,
+                return_url_allowed_origins: Vec::new(),
+                retrieval_code_ttl_seconds: 60,
+                trusted_tenants: Vec::new(),
+                metadata_cache_seconds: 3600,
+                client_certs: BTreeMap::new(),
+                ca_cert_path: None,
+                dpop_enabled: false,
+                oidc_issuer: None,
+                oidc_jwks_url: None,
+                id_token_clock_skew_seconds: 60,
+                jwks_cache_seconds: 3600,
+                introspection_cache_seconds: 30,
+            },
+            refresh: RefreshConfig {
+                enabled: true,
+                interval_seconds: 60,
+                window_seconds: 300,
+                jitter_seconds: 30,
+                max_concurrency: 4,
+            },
+            storage: StorageConfig {
+                token_store_path: None,
+            },
+            webhook: WebhookConfig {
+                urls: Vec::new(),
+                secret: None,
+                events: Vec::new(),
+                max_attempts: 8,
+                retry_base_seconds: 5,
+                timeout_seconds: 10,
+                queue_path: None,
+            },
+            rate_limit: RateLimitConfig {
+                enabled: true,
+                trusted_proxies: Vec::new(),
+                auth_url: RouteLimit {
+                    per_minute: 30,
+                    burst: 10,
+                },
+                complete_auth: RouteLimit {
+                    per_minute: 10,
+                    burst: 5,
+                },
+            },
+            auth: AuthConfig {
+                // 默认要求API密钥；不认证必须显式设置 AUTH_MODE=none
+                mode: AuthMode::ApiKey,
+                api_keys_path: None,
+                jwt_issuer: None,
+                jwt_audience: None,
+                jwt_jwks_url: None,
+                jwt_jwks_path: None,
+                jwt_group_scopes: BTreeMap::new(),
+            },
+            cors: CorsConfig {
+                default: CorsPolicy {
+                    allowed_origins: Vec::new(),
+                    allowed_methods: ["GET", "POST", "DELETE"].map(String::from).to_vec(),
+                    allowed_headers: ["content-type", "authorization", "x-api-key", "x-status-token"]
+                        .map(String::from)
+                        .to_vec(),
+                    allow_credentials: false,
+                    max_age_seconds: 600,
+                },
+                groups: BTreeMap::new(),
+            },
+            metrics: MetricsConfig {
+                enabled: true,
+                admin_port: None,
+            },
+            telemetry: TelemetryConfig {
+                otlp_endpoint: None,
+                otlp_protocol: OtlpProtocol::Grpc,
+                service_name: "augment-oauth-service".to_string(),
+                sampling_ratio: 1.0,
+                resource_attributes: BTreeMap::new(),
             },
         }
     }
@@ -94,6 +449,55 @@ impl AppConfig {
             self.server.log_level = log_level;
         }
 
+        if let Ok(format_str) = env::var("LOG_FORMAT") {
+            self.server.log_format = match format_str.trim().to_ascii_lowercase().as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => return Err(anyhow!("无效的日志格式 '{}'", format_str)),
+            };
+        }
+
+        if let Ok(log_file) = env::var("LOG_FILE") {
+            self.server.log_file = Some(log_file);
+        }
+
+        if let Ok(rotation_str) = env::var("LOG_ROTATION") {
+            self.server.log_rotation = match rotation_str.trim().to_ascii_lowercase().as_str() {
+                "hourly" => LogRotation::Hourly,
+                "daily" => LogRotation::Daily,
+                "never" => LogRotation::Never,
+                _ => return Err(anyhow!("无效的日志轮转周期 '{}'", rotation_str)),
+            };
+        }
+
+        if let Ok(cert_path) = env::var("TLS_CERT_PATH") {
+            self.server.tls_cert_path = Some(cert_path);
+        }
+
+        if let Ok(key_path) = env::var("TLS_KEY_PATH") {
+            self.server.tls_key_path = Some(key_path);
+        }
+
+        if let Ok(interval_str) = env::var("TLS_RELOAD_INTERVAL_SECONDS") {
+            self.server.tls_reload_interval_seconds = interval_str
+                .parse()
+                .map_err(|e| anyhow!("无效的证书检查间隔 '{}': {}", interval_str, e))?;
+        }
+
+        if let Ok(timeout_str) = env::var("SHUTDOWN_TIMEOUT_SECONDS") {
+            self.server.shutdown_timeout_seconds = timeout_str
+                .parse()
+                .map_err(|e| anyhow!("无效的停止等待时间 '{}': {}", timeout_str, e))?;
+        }
+
+        if let Ok(port_str) = env::var("HTTP_REDIRECT_PORT") {
+            self.server.http_redirect_port = Some(
+                port_str
+                    .parse()
+                    .map_err(|e| anyhow!("无效的重定向端口 '{}': {}", port_str, e))?,
+            );
+        }
+
         // OAuth配置
         if let Ok(auth_url) = env::var("OAUTH_AUTH_URL") {
             self.oauth.auth_url = auth_url;
@@ -109,6 +513,299 @@ impl AppConfig {
                 .map_err(|e| anyhow!("无效的过期时间 '{}': {}", expire_str, e))?;
         }
 
+        if let Ok(max_str) = env::var("STATE_MAX_TTL_MINUTES") {
+            self.oauth.state_max_ttl_minutes = max_str
+                .parse()
+                .map_err(|e| anyhow!("无效的最长有效期 '{}': {}", max_str, e))?;
+        }
+
+        if let Ok(retention_str) = env::var("FLOW_RETENTION_MINUTES") {
+            self.oauth.flow_retention_minutes = retention_str
+                .parse()
+                .map_err(|e| anyhow!("无效的流程保留时间 '{}': {}", retention_str, e))?;
+        }
+
+        if let Ok(replay_str) = env::var("COMPLETION_REPLAY_SECONDS") {
+            self.oauth.completion_replay_seconds = replay_str
+                .parse()
+                .map_err(|e| anyhow!("无效的重放窗口 '{}': {}", replay_str, e))?;
+        }
+
+        if let Ok(max_str) = env::var("MAX_PENDING_STATES") {
+            self.oauth.max_pending_states = max_str
+                .parse()
+                .map_err(|e| anyhow!("无效的OAuth状态上限 '{}': {}", max_str, e))?;
+        }
+
+        if let Ok(max_str) = env::var("MAX_PENDING_STATES_PER_USER") {
+            self.oauth.max_pending_states_per_user = max_str
+                .parse()
+                .map_err(|e| anyhow!("无效的单用户OAuth状态上限 '{}': {}", max_str, e))?;
+        }
+
+        if let Ok(policy_str) = env::var("STATE_OVERFLOW_POLICY") {
+            self.oauth.state_overflow_policy = match policy_str.trim().to_ascii_lowercase().as_str() {
+                "reject" => StateOverflowPolicy::Reject,
+                "evict_oldest" => StateOverflowPolicy::EvictOldest,
+                _ => return Err(anyhow!("无效的OAuth状态溢出策略 '{}'", policy_str)),
+            };
+        }
+
+        if let Ok(origins) = env::var("RETURN_URL_ALLOWED_ORIGINS") {
+            self.oauth.return_url_allowed_origins = parse_list(&origins);
+        }
+
+        if let Ok(ttl_str) = env::var("RETRIEVAL_CODE_TTL_SECONDS") {
+            self.oauth.retrieval_code_ttl_seconds = ttl_str
+                .parse()
+                .map_err(|e| anyhow!("无效的取回码有效期 '{}': {}", ttl_str, e))?;
+        }
+
+        if let Ok(tenants) = env::var("OAUTH_TRUSTED_TENANTS") {
+            self.oauth.trusted_tenants = parse_list(&tenants);
+        }
+
+        if let Ok(cache_str) = env::var("METADATA_CACHE_SECONDS") {
+            self.oauth.metadata_cache_seconds = cache_str
+                .parse()
+                .map_err(|e| anyhow!("无效的元数据缓存时间 '{}': {}", cache_str, e))?;
+        }
+
+        if let Ok(certs) = env::var("OAUTH_CLIENT_CERTS") {
+            self.oauth.client_certs = parse_client_certs(&certs)?;
+        }
+
+        if let Ok(ca_path) = env::var("OAUTH_CA_CERT_PATH") {
+            self.oauth.ca_cert_path = Some(ca_path);
+        }
+
+        if let Ok(dpop_str) = env::var("OAUTH_DPOP_ENABLED") {
+            self.oauth.dpop_enabled = parse_bool(&dpop_str)
+                .ok_or_else(|| anyhow!("无效的DPoP开关 '{}'", dpop_str))?;
+        }
+
+        if let Ok(issuer) = env::var("OIDC_ISSUER") {
+            self.oauth.oidc_issuer = Some(issuer);
+        }
+
+        if let Ok(jwks_url) = env::var("OIDC_JWKS_URL") {
+            self.oauth.oidc_jwks_url = Some(jwks_url);
+        }
+
+        if let Ok(skew_str) = env::var("ID_TOKEN_CLOCK_SKEW_SECONDS") {
+            self.oauth.id_token_clock_skew_seconds = skew_str
+                .parse()
+                .map_err(|e| anyhow!("无效的时钟偏差 '{}': {}", skew_str, e))?;
+        }
+
+        if let Ok(cache_str) = env::var("JWKS_CACHE_SECONDS") {
+            self.oauth.jwks_cache_seconds = cache_str
+                .parse()
+                .map_err(|e| anyhow!("无效的JWKS缓存时间 '{}': {}", cache_str, e))?;
+        }
+
+        if let Ok(cache_str) = env::var("INTROSPECTION_CACHE_SECONDS") {
+            self.oauth.introspection_cache_seconds = cache_str
+                .parse()
+                .map_err(|e| anyhow!("无效的自省缓存时间 '{}': {}", cache_str, e))?;
+        }
+
+        // 后台刷新配置
+        if let Ok(enabled_str) = env::var("TOKEN_REFRESH_ENABLED") {
+            self.refresh.enabled = parse_bool(&enabled_str)
+                .ok_or_else(|| anyhow!("无效的后台刷新开关 '{}'", enabled_str))?;
+        }
+
+        if let Ok(interval_str) = env::var("TOKEN_REFRESH_INTERVAL_SECONDS") {
+            self.refresh.interval_seconds = interval_str
+                .parse()
+                .map_err(|e| anyhow!("无效的刷新扫描间隔 '{}': {}", interval_str, e))?;
+        }
+
+        if let Ok(window_str) = env::var("TOKEN_REFRESH_WINDOW_SECONDS") {
+            self.refresh.window_seconds = window_str
+                .parse()
+                .map_err(|e| anyhow!("无效的刷新窗口 '{}': {}", window_str, e))?;
+        }
+
+        if let Ok(jitter_str) = env::var("TOKEN_REFRESH_JITTER_SECONDS") {
+            self.refresh.jitter_seconds = jitter_str
+                .parse()
+                .map_err(|e| anyhow!("无效的刷新抖动 '{}': {}", jitter_str, e))?;
+        }
+
+        if let Ok(concurrency_str) = env::var("TOKEN_REFRESH_CONCURRENCY") {
+            self.refresh.max_concurrency = concurrency_str
+                .parse()
+                .map_err(|e| anyhow!("无效的刷新并发数 '{}': {}", concurrency_str, e))?;
+        }
+
+        // 存储配置
+        if let Ok(store_path) = env::var("TOKEN_STORE_PATH") {
+            self.storage.token_store_path = Some(store_path);
+        }
+
+        // Webhook配置
+        if let Ok(urls) = env::var("WEBHOOK_URLS") {
+            self.webhook.urls = parse_list(&urls);
+        }
+
+        if let Ok(secret) = env::var("WEBHOOK_SECRET") {
+            self.webhook.secret = Some(Secret::new(secret));
+        }
+
+        if let Ok(events) = env::var("WEBHOOK_EVENTS") {
+            self.webhook.events = parse_list(&events);
+        }
+
+        if let Ok(attempts_str) = env::var("WEBHOOK_MAX_ATTEMPTS") {
+            self.webhook.max_attempts = attempts_str
+                .parse()
+                .map_err(|e| anyhow!("无效的webhook最大投递次数 '{}': {}", attempts_str, e))?;
+        }
+
+        if let Ok(base_str) = env::var("WEBHOOK_RETRY_BASE_SECONDS") {
+            self.webhook.retry_base_seconds = base_str
+                .parse()
+                .map_err(|e| anyhow!("无效的webhook重试间隔 '{}': {}", base_str, e))?;
+        }
+
+        if let Ok(timeout_str) = env::var("WEBHOOK_TIMEOUT_SECONDS") {
+            self.webhook.timeout_seconds = timeout_str
+                .parse()
+                .map_err(|e| anyhow!("无效的webhook超时时间 '{}': {}", timeout_str, e))?;
+        }
+
+        if let Ok(queue_path) = env::var("WEBHOOK_QUEUE_PATH") {
+            self.webhook.queue_path = Some(queue_path);
+        }
+
+        // 认证配置
+        if let Ok(mode_str) = env::var("AUTH_MODE") {
+            self.auth.mode = match mode_str.trim().to_ascii_lowercase().as_str() {
+                "none" => AuthMode::None,
+                "api_key" => AuthMode::ApiKey,
+                "jwt" => AuthMode::Jwt,
+                _ => return Err(anyhow!("无效的认证方式 '{}'", mode_str)),
+            };
+        }
+
+        if let Ok(keys_path) = env::var("API_KEYS_PATH") {
+            self.auth.api_keys_path = Some(keys_path);
+        }
+
+        if let Ok(issuer) = env::var("JWT_ISSUER") {
+            self.auth.jwt_issuer = Some(issuer);
+        }
+
+        if let Ok(audience) = env::var("JWT_AUDIENCE") {
+            self.auth.jwt_audience = Some(audience);
+        }
+
+        if let Ok(jwks_url) = env::var("JWT_JWKS_URL") {
+            self.auth.jwt_jwks_url = Some(jwks_url);
+        }
+
+        if let Ok(jwks_path) = env::var("JWT_JWKS_PATH") {
+            self.auth.jwt_jwks_path = Some(jwks_path);
+        }
+
+        if let Ok(mapping) = env::var("JWT_GROUP_SCOPES") {
+            self.auth.jwt_group_scopes = parse_group_scopes(&mapping)?;
+        }
+
+        // 跨域配置：CORS_* 为默认策略，CORS_<GROUP>_* 在默认策略基础上覆盖单个路由组
+        load_cors_policy("CORS_", &mut self.cors.default)?;
+        for group in CORS_ROUTE_GROUPS {
+            let prefix = format!("CORS_{}_", group.to_ascii_uppercase());
+            let mut policy = self
+                .cors
+                .groups
+                .get(*group)
+                .cloned()
+                .unwrap_or_else(|| self.cors.default.clone());
+            if load_cors_policy(&prefix, &mut policy)? {
+                self.cors.groups.insert(group.to_string(), policy);
+            }
+        }
+
+        // 限流配置
+        if let Ok(enabled_str) = env::var("RATE_LIMIT_ENABLED") {
+            self.rate_limit.enabled = parse_bool(&enabled_str)
+                .ok_or_else(|| anyhow!("无效的限流开关 '{}'", enabled_str))?;
+        }
+
+        if let Ok(proxies) = env::var("RATE_LIMIT_TRUSTED_PROXIES") {
+            self.rate_limit.trusted_proxies = parse_list(&proxies);
+        }
+
+        if let Ok(rate_str) = env::var("RATE_LIMIT_AUTH_URL_PER_MINUTE") {
+            self.rate_limit.auth_url.per_minute = rate_str
+                .parse()
+                .map_err(|e| anyhow!("无效的授权链接限流速率 '{}': {}", rate_str, e))?;
+        }
+
+        if let Ok(burst_str) = env::var("RATE_LIMIT_AUTH_URL_BURST") {
+            self.rate_limit.auth_url.burst = burst_str
+                .parse()
+                .map_err(|e| anyhow!("无效的授权链接突发上限 '{}': {}", burst_str, e))?;
+        }
+
+        if let Ok(rate_str) = env::var("RATE_LIMIT_COMPLETE_AUTH_PER_MINUTE") {
+            self.rate_limit.complete_auth.per_minute = rate_str
+                .parse()
+                .map_err(|e| anyhow!("无效的完成授权限流速率 '{}': {}", rate_str, e))?;
+        }
+
+        if let Ok(burst_str) = env::var("RATE_LIMIT_COMPLETE_AUTH_BURST") {
+            self.rate_limit.complete_auth.burst = burst_str
+                .parse()
+                .map_err(|e| anyhow!("无效的完成授权突发上限 '{}': {}", burst_str, e))?;
+        }
+
+        // 指标配置
+        if let Ok(enabled_str) = env::var("METRICS_ENABLED") {
+            self.metrics.enabled = parse_bool(&enabled_str)
+                .ok_or_else(|| anyhow!("无效的指标开关 '{}'", enabled_str))?;
+        }
+
+        if let Ok(port_str) = env::var("METRICS_ADMIN_PORT") {
+            self.metrics.admin_port = Some(
+                port_str
+                    .parse()
+                    .map_err(|e| anyhow!("无效的管理端口 '{}': {}", port_str, e))?,
+            );
+        }
+
+        // 链路追踪配置，沿用OpenTelemetry的标准环境变量名
+        if let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+
+        if let Ok(protocol_str) = env::var("OTEL_EXPORTER_OTLP_PROTOCOL") {
+            self.telemetry.otlp_protocol = match protocol_str.trim().to_ascii_lowercase().as_str() {
+                "grpc" => OtlpProtocol::Grpc,
+                "http/protobuf" => OtlpProtocol::HttpProtobuf,
+                _ => return Err(anyhow!("无效的OTLP协议 '{}'", protocol_str)),
+            };
+        }
+
+        if let Ok(service_name) = env::var("OTEL_SERVICE_NAME") {
+            self.telemetry.service_name = service_name;
+        }
+
+        if let Ok(ratio_str) = env::var("TRACE_SAMPLING_RATIO") {
+            self.telemetry.sampling_ratio = ratio_str
+                .parse()
+                .ok()
+                .filter(|ratio| (0.0..=1.0).contains(ratio))
+                .ok_or_else(|| anyhow!("无效的采样比例 '{}'，取值范围 0.0-1.0", ratio_str))?;
+        }
+
+        if let Ok(attributes) = env::var("OTEL_RESOURCE_ATTRIBUTES") {
+            self.telemetry.resource_attributes = parse_key_values(&attributes)?;
+        }
+
         Ok(())
     }
 
@@ -127,11 +824,116 @@ impl AppConfig {
 
         Err(anyhow!("未找到配置文件"))
     }
+}
+
+/// 解析布尔类型的配置值
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// 解析逗号分隔的列表配置，忽略空项
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// 从带前缀的环境变量加载跨域策略，返回是否设置了任何一项
+fn load_cors_policy(prefix: &str, policy: &mut CorsPolicy) -> Result<bool> {
+    let var = |name: &str| env::var(format!("{}{}", prefix, name)).ok();
+    let mut loaded = false;
+
+    if let Some(origins) = var("ALLOWED_ORIGINS") {
+        policy.allowed_origins = parse_list(&origins);
+        loaded = true;
+    }
 
-    /// 获取服务器监听地址
-    pub fn server_addr(&self) -> String {
-        format!("{}:{}", self.server.host, self.server.port)
+    if let Some(methods) = var("ALLOWED_METHODS") {
+        policy.allowed_methods = parse_list(&methods);
+        loaded = true;
     }
+
+    if let Some(headers) = var("ALLOWED_HEADERS") {
+        policy.allowed_headers = parse_list(&headers);
+        loaded = true;
+    }
+
+    if let Some(credentials_str) = var("ALLOW_CREDENTIALS") {
+        policy.allow_credentials = parse_bool(&credentials_str)
+            .ok_or_else(|| anyhow!("无效的跨域凭据开关 '{}'", credentials_str))?;
+        loaded = true;
+    }
+
+    if let Some(max_age_str) = var("MAX_AGE_SECONDS") {
+        policy.max_age_seconds = max_age_str
+            .parse()
+            .map_err(|e| anyhow!("无效的预检缓存时间 '{}': {}", max_age_str, e))?;
+        loaded = true;
+    }
+
+    Ok(loaded)
+}
+
+/// 解析 `key=value,key2=value2` 格式的键值对
+fn parse_key_values(value: &str) -> Result<BTreeMap<String, String>> {
+    parse_list(value)
+        .into_iter()
+        .map(|item| {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow!("无效的键值对 '{}'，格式为 key=value", item))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// 解析 `group=scope|scope,group2=scope` 格式的权限映射
+fn parse_group_scopes(value: &str) -> Result<BTreeMap<String, Vec<String>>> {
+    parse_list(value)
+        .into_iter()
+        .map(|item| {
+            let (group, scopes) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow!("无效的权限映射 '{}'，格式为 group=scope|scope", item))?;
+            let scopes = scopes
+                .split('|')
+                .map(str::trim)
+                .filter(|scope| !scope.is_empty())
+                .map(str::to_string)
+                .collect();
+            Ok((group.trim().to_string(), scopes))
+        })
+        .collect()
+}
+
+/// 解析 `tenant_url=证书路径|私钥路径` 形式的mTLS客户端证书列表，逗号分隔
+fn parse_client_certs(value: &str) -> Result<BTreeMap<String, ClientCertConfig>> {
+    parse_list(value)
+        .into_iter()
+        .map(|item| {
+            let invalid = || anyhow!("无效的mTLS客户端证书配置 '{}'，格式为 tenant_url=cert.pem|key.pem", item);
+            let (tenant_url, paths) = item.split_once('=').ok_or_else(invalid)?;
+            let (cert_path, key_path) = paths.split_once('|').ok_or_else(invalid)?;
+            let (tenant_url, cert_path, key_path) = (tenant_url.trim(), cert_path.trim(), key_path.trim());
+            if tenant_url.is_empty() || cert_path.is_empty() || key_path.is_empty() {
+                return Err(invalid());
+            }
+            Ok((
+                tenant_url.to_string(),
+                ClientCertConfig {
+                    cert_path: cert_path.to_string(),
+                    key_path: key_path.to_string(),
+                },
+            ))
+        })
+        .collect()
 }
 
 /// 检查端口是否可用
@@ -202,18 +1004,117 @@ mod tests {
         assert_eq!(config.server.host, "0.0.0.0");
         assert_eq!(config.server.port, 3000);
         assert_eq!(config.server.log_level, "info");
+        assert_eq!(config.server.log_format, LogFormat::Text);
+        assert!(config.server.log_file.is_none());
+        assert_eq!(config.server.log_rotation, LogRotation::Daily);
+        assert!(config.server.tls_cert_path.is_none());
+        assert!(config.server.tls_key_path.is_none());
+        assert_eq!(config.server.tls_reload_interval_seconds, 30);
+        assert!(config.server.http_redirect_port.is_none());
+        assert_eq!(config.server.shutdown_timeout_seconds, 30);
         assert_eq!(
             config.oauth.auth_url,
             "https://auth.augmentcode.com/authorize"
         );
         assert_eq!(config.oauth.client_id, "v");
         assert_eq!(config.oauth.state_expire_minutes, 30);
+        assert_eq!(config.oauth.state_max_ttl_minutes, 120);
+        assert_eq!(config.oauth.flow_retention_minutes, 60);
+        assert_eq!(config.oauth.completion_replay_seconds, 60);
+        assert_eq!(config.oauth.max_pending_states, 10000);
+        assert_eq!(config.oauth.max_pending_states_per_user, 20);
+        assert_eq!(config.oauth.state_overflow_policy, StateOverflowPolicy::Reject);
+        assert!(config.oauth.return_url_allowed_origins.is_empty());
+        assert_eq!(config.oauth.retrieval_code_ttl_seconds, 60);
+        assert!(config.oauth.trusted_tenants.is_empty());
+        assert_eq!(config.oauth.metadata_cache_seconds, 3600);
+        assert!(config.oauth.client_certs.is_empty());
+        assert!(config.oauth.ca_cert_path.is_none());
+        assert!(!config.oauth.dpop_enabled);
+        assert_eq!(config.oauth.id_token_clock_skew_seconds, 60);
+        assert_eq!(config.oauth.jwks_cache_seconds, 3600);
+        assert_eq!(config.oauth.introspection_cache_seconds, 30);
+        assert!(config.refresh.enabled);
+        assert_eq!(config.refresh.interval_seconds, 60);
+        assert_eq!(config.refresh.window_seconds, 300);
+        assert_eq!(config.refresh.jitter_seconds, 30);
+        assert_eq!(config.refresh.max_concurrency, 4);
+        assert!(config.storage.token_store_path.is_none());
+        assert!(config.webhook.urls.is_empty());
+        assert_eq!(config.webhook.max_attempts, 8);
+        assert!(config.rate_limit.enabled);
+        assert!(config.rate_limit.trusted_proxies.is_empty());
+        assert_eq!(config.rate_limit.auth_url.per_minute, 30);
+        assert_eq!(config.rate_limit.auth_url.burst, 10);
+        assert_eq!(config.rate_limit.complete_auth.per_minute, 10);
+        assert_eq!(config.rate_limit.complete_auth.burst, 5);
+        assert_eq!(config.auth.mode, AuthMode::ApiKey);
+        assert!(config.auth.api_keys_path.is_none());
+        assert!(config.auth.jwt_issuer.is_none());
+        assert!(config.auth.jwt_audience.is_none());
+        assert!(config.auth.jwt_jwks_url.is_none());
+        assert!(config.auth.jwt_jwks_path.is_none());
+        assert!(config.auth.jwt_group_scopes.is_empty());
+        assert!(config.cors.default.allowed_origins.is_empty());
+        assert_eq!(config.cors.default.allowed_methods, vec!["GET", "POST", "DELETE"]);
+        assert_eq!(config.cors.default.allowed_headers.len(), 4);
+        assert!(!config.cors.default.allow_credentials);
+        assert_eq!(config.cors.default.max_age_seconds, 600);
+        assert!(config.cors.groups.is_empty());
+        assert!(config.metrics.enabled);
+        assert!(config.metrics.admin_port.is_none());
+        assert!(config.telemetry.otlp_endpoint.is_none());
+        assert_eq!(config.telemetry.otlp_protocol, OtlpProtocol::Grpc);
+        assert_eq!(config.telemetry.service_name, "augment-oauth-service");
+        assert_eq!(config.telemetry.sampling_ratio, 1.0);
+        assert!(config.telemetry.resource_attributes.is_empty());
     }
 
     #[test]
-    fn test_server_addr() {
-        let config = AppConfig::default();
-        assert_eq!(config.server_addr(), "0.0.0.0:3000");
+    fn test_parse_key_values() {
+        let attributes = parse_key_values("deployment.environment=prod, region = cn-east").unwrap();
+        assert_eq!(attributes["deployment.environment"], "prod");
+        assert_eq!(attributes["region"], "cn-east");
+        assert!(parse_key_values("prod").is_err());
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            parse_list(" https://a.example/hook, ,https://b.example/hook "),
+            vec!["https://a.example/hook", "https://b.example/hook"]
+        );
+        assert!(parse_list("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_group_scopes() {
+        let mapping = parse_group_scopes("apps=auth:create|auth:complete, ops = tokens:read").unwrap();
+        assert_eq!(mapping["apps"], vec!["auth:create", "auth:complete"]);
+        assert_eq!(mapping["ops"], vec!["tokens:read"]);
+        assert!(parse_group_scopes("apps").is_err());
+    }
+
+    #[test]
+    fn test_parse_client_certs() {
+        let certs = parse_client_certs(
+            "https://a.example=/etc/a.pem|/etc/a.key, https://b.example/ = /etc/b.pem | /etc/b.key",
+        )
+        .unwrap();
+        assert_eq!(certs["https://a.example"].cert_path, "/etc/a.pem");
+        assert_eq!(certs["https://a.example"].key_path, "/etc/a.key");
+        assert_eq!(certs["https://b.example/"].key_path, "/etc/b.key");
+        assert!(parse_client_certs("https://a.example=/etc/a.pem").is_err());
+        assert!(parse_client_certs("https://a.example").is_err());
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert_eq!(parse_bool("true"), Some(true));
+        assert_eq!(parse_bool(" YES "), Some(true));
+        assert_eq!(parse_bool("0"), Some(false));
+        assert_eq!(parse_bool("off"), Some(false));
+        assert_eq!(parse_bool("maybe"), None);
     }
 
     #[test]
@@ -237,6 +1138,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::manual_range_contains)]
     fn test_find_available_port() {
         // 从一个高端口开始查找
         let result = find_available_port("127.0.0.1", 65000, 10);
@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::collections::HashMap;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::warn;
+
+use crate::config::{CorsConfig, CorsPolicy, CORS_ROUTE_GROUPS};
+use crate::request_id::REQUEST_ID_HEADER;
+
+/// 允许的源
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OriginPattern {
+    Exact(String),
+    /// `https://*.example.com`：scheme为 `https://`，后缀为 `.example.com`（含端口）
+    Subdomain { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    fn parse(value: &str) -> Result<Self> {
+        let value = value.trim().trim_end_matches('/').to_ascii_lowercase();
+        let (scheme, host) = value
+            .split_once("://")
+            .ok_or_else(|| anyhow!("无效的跨域源 '{}'，需要包含 scheme", value))?;
+        if host.is_empty() || host.contains('/') {
+            return Err(anyhow!("无效的跨域源 '{}'", value));
+        }
+
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => Ok(Self::Subdomain {
+                scheme: format!("{}://", scheme),
+                suffix: suffix.to_string(),
+            }),
+            Some(_) => Err(anyhow!("无效的跨域源 '{}'，通配符只能用于子域名", value)),
+            None if host.contains('*') => Err(anyhow!("无效的跨域源 '{}'，通配符只能用于子域名", value)),
+            None => Ok(Self::Exact(value)),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            Self::Exact(allowed) => *allowed == origin,
+            Self::Subdomain { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && subdomain
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                }),
+        }
+    }
+}
+
+/// 按策略构建CORS中间件
+fn cors_layer(group: &str, policy: &CorsPolicy) -> Result<CorsLayer> {
+    let permissive = policy.allowed_origins.iter().any(|origin| origin.trim() == "*");
+    if permissive && policy.allow_credentials {
+        return Err(anyhow!("路由组 {} 的跨域策略不能同时允许任意源和携带凭据", group));
+    }
+
+    let allow_origin = if permissive {
+        warn!("路由组 {} 的跨域策略允许任意源，任何网站都可以在用户浏览器中调用这些接口", group);
+        AllowOrigin::any()
+    } else {
+        let patterns = policy
+            .allowed_origins
+            .iter()
+            .map(|origin| OriginPattern::parse(origin))
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| patterns.iter().any(|pattern| pattern.matches(origin)))
+        })
+    };
+
+    let methods = policy
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                .map_err(|e| anyhow!("无效的跨域方法 '{}': {}", method, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .expose_headers([REQUEST_ID_HEADER.clone()])
+        .allow_credentials(policy.allow_credentials)
+        .max_age(std::time::Duration::from_secs(policy.max_age_seconds));
+
+    if policy.allowed_headers.iter().any(|header| header.trim() == "*") {
+        if policy.allow_credentials {
+            return Err(anyhow!("路由组 {} 的跨域策略不能同时允许任意请求头和携带凭据", group));
+        }
+        layer = layer.allow_headers(Any);
+    } else {
+        let headers = policy
+            .allowed_headers
+            .iter()
+            .map(|header| {
+                HeaderName::from_bytes(header.trim().to_ascii_lowercase().as_bytes())
+                    .map_err(|e| anyhow!("无效的跨域请求头 '{}': {}", header, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        layer = layer.allow_headers(headers);
+    }
+
+    Ok(layer)
+}
+
+/// 各路由组的跨域策略
+pub struct CorsPolicies {
+    default: CorsLayer,
+    groups: HashMap<String, CorsLayer>,
+}
+
+impl CorsPolicies {
+    pub fn new(config: &CorsConfig) -> Result<Self> {
+        let default = cors_layer("default", &config.default)?;
+        let groups = config
+            .groups
+            .iter()
+            .map(|(group, policy)| {
+                if !CORS_ROUTE_GROUPS.contains(&group.as_str()) {
+                    return Err(anyhow!("未知的跨域路由组: {}", group));
+                }
+                Ok((group.clone(), cors_layer(group, policy)?))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { default, groups })
+    }
+
+    /// 路由组使用的CORS中间件，未单独配置时使用默认策略
+    pub fn layer(&self, group: &str) -> CorsLayer {
+        self.groups.get(group).unwrap_or(&self.default).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use axum::{body::Body, extract::Request, http::StatusCode, routing::post, Router};
+    use tower::Service;
+
+    #[test]
+    fn test_origin_patterns() {
+        let exact = OriginPattern::parse("https://app.example.com/").unwrap();
+        assert!(exact.matches("https://app.example.com"));
+        assert!(!exact.matches("http://app.example.com"));
+        assert!(!exact.matches("https://app.example.com:8443"));
+
+        let wildcard = OriginPattern::parse("https://*.example.com").unwrap();
+        assert!(wildcard.matches("https://app.example.com"));
+        assert!(wildcard.matches("https://a.b.example.com"));
+        assert!(!wildcard.matches("https://example.com"));
+        assert!(!wildcard.matches("https://evilexample.com"));
+        assert!(!wildcard.matches("https://app.example.com.evil.test"));
+        assert!(!wildcard.matches("http://app.example.com"));
+
+        assert!(OriginPattern::parse("app.example.com").is_err());
+        assert!(OriginPattern::parse("https://app.*.com").is_err());
+        assert!(OriginPattern::parse("https://*example.com").is_err());
+    }
+
+    #[test]
+    fn test_any_origin_with_credentials_is_rejected() {
+        let mut config = AppConfig::default().cors;
+        config.default.allowed_origins = vec!["*".to_string()];
+        config.default.allow_credentials = true;
+        assert!(CorsPolicies::new(&config).is_err());
+
+        let mut config = AppConfig::default().cors;
+        config.groups.insert("admin".to_string(), config.default.clone());
+        assert!(CorsPolicies::new(&config).is_err());
+    }
+
+    async fn preflight(app: &mut Router, path: &str, origin: &str) -> Option<String> {
+        let request = Request::options(path)
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_route_groups_use_their_own_policy() {
+        let mut config = AppConfig::default().cors;
+        config.default.allowed_origins = vec!["https://*.example.com".to_string()];
+        let mut tokens = config.default.clone();
+        tokens.allowed_origins = vec!["https://admin.example.com".to_string()];
+        config.groups.insert("tokens".to_string(), tokens);
+        let policies = CorsPolicies::new(&config).unwrap();
+
+        let handler = || async { "ok" };
+        let mut app = Router::new()
+            .merge(Router::new().route("/api/auth-url", post(handler)).layer(policies.layer("auth")))
+            .merge(Router::new().route("/api/introspect", post(handler)).layer(policies.layer("tokens")));
+
+        assert_eq!(
+            preflight(&mut app, "/api/auth-url", "https://app.example.com").await.as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(preflight(&mut app, "/api/auth-url", "https://evil.test").await, None);
+        assert_eq!(preflight(&mut app, "/api/introspect", "https://app.example.com").await, None);
+        assert_eq!(
+            preflight(&mut app, "/api/introspect", "https://admin.example.com").await.as_deref(),
+            Some("https://admin.example.com")
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// DPoP密钥 (RFC 9449)，每条token记录绑定一把P-256密钥
+#[derive(Clone)]
+pub struct DpopKey {
+    signing_key: SigningKey,
+}
+
+impl DpopKey {
+    /// 生成新的P-256密钥
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::random(&mut rand::rngs::OsRng),
+        }
+    }
+
+    /// 从私钥JWK加载密钥
+    pub fn from_jwk(jwk: &Value) -> Result<Self> {
+        if jwk["kty"] != "EC" || jwk["crv"] != "P-256" {
+            return Err(anyhow!("DPoP密钥必须是P-256 EC密钥"));
+        }
+
+        let d = jwk["d"]
+            .as_str()
+            .ok_or_else(|| anyhow!("DPoP密钥缺少私钥参数 d"))?;
+        let d = general_purpose::URL_SAFE_NO_PAD.decode(d)?;
+        let signing_key =
+            SigningKey::from_slice(&d).map_err(|e| anyhow!("无效的DPoP私钥: {}", e))?;
+
+        Ok(Self { signing_key })
+    }
+
+    /// 公钥JWK
+    pub fn public_jwk(&self) -> Value {
+        let point = self.signing_key.verifying_key().to_encoded_point(false);
+        let encode = |bytes: Option<&p256::FieldBytes>| {
+            general_purpose::URL_SAFE_NO_PAD.encode(bytes.map(|b| b.as_slice()).unwrap_or_default())
+        };
+
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": encode(point.x()),
+            "y": encode(point.y()),
+        })
+    }
+
+    /// 私钥JWK，供下游调用方使用DPoP绑定的令牌
+    pub fn private_jwk(&self) -> Value {
+        let mut jwk = self.public_jwk();
+        jwk["d"] = Value::String(
+            general_purpose::URL_SAFE_NO_PAD.encode(self.signing_key.to_bytes()),
+        );
+        jwk
+    }
+
+    /// JWK指纹 (RFC 7638)，即令牌中 `cnf.jkt` 的值
+    pub fn thumbprint(&self) -> String {
+        let jwk = self.public_jwk();
+        // 成员按字典序排列且无空白
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            jwk["x"].as_str().unwrap_or_default(),
+            jwk["y"].as_str().unwrap_or_default()
+        );
+        general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+    }
+
+    /// 生成DPoP证明JWT
+    ///
+    /// `htu` 不能包含查询参数和片段；携带访问令牌调用资源时传入 `access_token` 以生成 `ath`。
+    pub fn proof(
+        &self,
+        htm: &str,
+        htu: &str,
+        nonce: Option<&str>,
+        access_token: Option<&str>,
+    ) -> Result<String> {
+        let header = json!({
+            "typ": "dpop+jwt",
+            "alg": "ES256",
+            "jwk": self.public_jwk(),
+        });
+
+        let mut claims = json!({
+            "jti": Uuid::new_v4().to_string(),
+            "htm": htm,
+            "htu": htu,
+            "iat": Utc::now().timestamp(),
+        });
+        if let Some(nonce) = nonce {
+            claims["nonce"] = Value::String(nonce.to_string());
+        }
+        if let Some(access_token) = access_token {
+            claims["ath"] = Value::String(
+                general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes())),
+            );
+        }
+
+        let signing_input = format!(
+            "{}.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature: Signature = self.signing_key.sign(signing_input.as_bytes());
+
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            general_purpose::URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+}
+
+impl std::fmt::Debug for DpopKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DpopKey")
+            .field("jkt", &self.thumbprint())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Verifier, VerifyingKey};
+
+    fn decode_segment(segment: &str) -> Value {
+        serde_json::from_slice(&general_purpose::URL_SAFE_NO_PAD.decode(segment).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_private_jwk_extends_public_jwk() {
+        let key = DpopKey::generate();
+        let public = key.public_jwk();
+        let private = key.private_jwk();
+
+        assert!(public.get("d").is_none());
+        assert_eq!(private["x"], public["x"]);
+        assert_eq!(private["y"], public["y"]);
+        assert_eq!(
+            general_purpose::URL_SAFE_NO_PAD
+                .decode(private["d"].as_str().unwrap())
+                .unwrap()
+                .len(),
+            32
+        );
+        assert_eq!(key.thumbprint().len(), 43);
+    }
+
+    #[test]
+    fn test_jwk_roundtrip_keeps_thumbprint() {
+        let key = DpopKey::generate();
+        let loaded = DpopKey::from_jwk(&key.private_jwk()).unwrap();
+
+        assert_eq!(key.thumbprint(), loaded.thumbprint());
+        assert!(DpopKey::from_jwk(&key.public_jwk()).is_err());
+    }
+
+    #[test]
+    fn test_from_jwk_rejects_other_curves() {
+        let jwk = json!({"kty": "EC", "crv": "P-384", "d": "AA"});
+        assert!(DpopKey::from_jwk(&jwk).is_err());
+    }
+
+    #[test]
+    fn test_proof_is_signed_by_embedded_jwk() {
+        let key = DpopKey::generate();
+        let proof = key
+            .proof("POST", "https://tenant.example/token", Some("nonce-1"), Some("at"))
+            .unwrap();
+
+        let parts: Vec<&str> = proof.split('.').collect();
+        assert_eq!(parts.len(), 3);
+
+        let header = decode_segment(parts[0]);
+        let claims = decode_segment(parts[1]);
+        assert_eq!(header["typ"], "dpop+jwt");
+        assert_eq!(header["alg"], "ES256");
+        assert_eq!(claims["htm"], "POST");
+        assert_eq!(claims["htu"], "https://tenant.example/token");
+        assert_eq!(claims["nonce"], "nonce-1");
+        assert!(claims["ath"].is_string());
+
+        let jwk = &header["jwk"];
+        let mut sec1 = vec![0x04];
+        for coordinate in ["x", "y"] {
+            sec1.extend(
+                general_purpose::URL_SAFE_NO_PAD
+                    .decode(jwk[coordinate].as_str().unwrap())
+                    .unwrap(),
+            );
+        }
+        let verifying_key = VerifyingKey::from_sec1_bytes(&sec1).unwrap();
+        let signature = Signature::from_slice(
+            &general_purpose::URL_SAFE_NO_PAD.decode(parts[2]).unwrap(),
+        )
+        .unwrap();
+        verifying_key
+            .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)
+            .unwrap();
+    }
+}
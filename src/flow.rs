@@ -0,0 +1,486 @@
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use tokio::sync::watch;
+
+use crate::models::{FlowMetadata, OAuthState};
+
+/// 日志中用于标识授权流程的state摘要，state本身是敏感值，不能写入日志
+pub fn state_id(state: &str) -> String {
+    Sha256::digest(state.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// 授权流程状态
+///
+/// `created → code_received → exchanging → completed`，未结束前任何阶段都可能进入
+/// `failed`、`expired` 或 `cancelled`；四个结果状态都是终态。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowStatus {
+    /// 已生成授权链接，等待用户授权
+    Created,
+    /// 已收到授权码
+    CodeReceived,
+    /// 正在向授权服务器交换令牌
+    Exchanging,
+    Completed,
+    Failed,
+    Expired,
+    Cancelled,
+}
+
+impl FlowStatus {
+    /// 是否为终态
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Self::Completed | Self::Failed | Self::Expired | Self::Cancelled
+        )
+    }
+
+    /// 是否允许从当前状态迁移到 `next`
+    pub fn can_transition_to(&self, next: FlowStatus) -> bool {
+        match (self, next) {
+            (Self::Created, Self::CodeReceived)
+            | (Self::CodeReceived, Self::Exchanging)
+            | (Self::Exchanging, Self::Completed) => true,
+            (current, Self::Failed | Self::Expired | Self::Cancelled) => !current.is_terminal(),
+            _ => false,
+        }
+    }
+
+    /// 是否可以用新的state续期
+    pub fn can_renew(&self) -> bool {
+        matches!(self, Self::Created | Self::Failed | Self::Expired)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::CodeReceived => "code_received",
+            Self::Exchanging => "exchanging",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Expired => "expired",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// 一次状态迁移
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowTransition {
+    pub status: FlowStatus,
+    pub at: DateTime<Utc>,
+}
+
+/// 对外返回的流程状态快照
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowSnapshot {
+    pub state: String,
+    pub status: FlowStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// state的过期时间
+    pub expires_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "FlowMetadata::is_empty")]
+    pub metadata: FlowMetadata,
+    /// 完成授权后签发的token_id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    /// 失败原因
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// 续期前的state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renewed_from: Option<String>,
+    /// 续期后的state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renewed_to: Option<String>,
+    /// 按时间顺序的状态迁移记录
+    pub history: Vec<FlowTransition>,
+}
+
+/// 访问流程时的错误
+#[derive(Debug, PartialEq, Eq)]
+pub enum FlowAccessError {
+    NotFound,
+    Forbidden,
+    /// 流程当前状态不允许该操作
+    Conflict,
+}
+
+struct FlowEntry {
+    /// 发起方凭据的SHA-256
+    status_token_hash: Vec<u8>,
+    sender: watch::Sender<FlowSnapshot>,
+}
+
+/// 授权流程状态跟踪
+///
+/// 每个流程对应一个watch通道，状态变化时立即通知长轮询和SSE订阅方；
+/// 只有持有生成授权链接时返回的 `status_token` 的发起方可以查询。
+/// 进入终态的流程在保留期内仍可查询，之后由 [`FlowTracker::prune`] 清除。
+pub struct FlowTracker {
+    flows: DashMap<String, FlowEntry>,
+}
+
+impl FlowTracker {
+    pub fn new() -> Self {
+        Self {
+            flows: DashMap::new(),
+        }
+    }
+
+    /// 登记新流程，返回发起方用于查询状态的凭据
+    pub fn register(&self, oauth_state: &OAuthState) -> String {
+        let status_token = generate_status_token();
+        self.insert(
+            oauth_state,
+            Sha256::digest(status_token.as_bytes()).to_vec(),
+            None,
+        );
+        status_token
+    }
+
+    /// 订阅流程状态变化
+    pub fn subscribe(
+        &self,
+        state: &str,
+        status_token: &str,
+    ) -> Result<watch::Receiver<FlowSnapshot>, FlowAccessError> {
+        let entry = self.flows.get(state).ok_or(FlowAccessError::NotFound)?;
+        if Sha256::digest(status_token.as_bytes()).as_slice() != entry.status_token_hash.as_slice() {
+            return Err(FlowAccessError::Forbidden);
+        }
+        Ok(entry.sender.subscribe())
+    }
+
+    /// 流程当前状态，不校验 `status_token`，仅供服务端内部使用
+    pub fn snapshot(&self, state: &str) -> Option<FlowSnapshot> {
+        self.flows.get(state).map(|entry| entry.sender.borrow().clone())
+    }
+
+    pub fn mark_code_received(&self, state: &str) -> bool {
+        self.transition(state, FlowStatus::CodeReceived, |_| {})
+    }
+
+    pub fn mark_exchanging(&self, state: &str) -> bool {
+        self.transition(state, FlowStatus::Exchanging, |_| {})
+    }
+
+    pub fn mark_completed(&self, state: &str, token_id: &str) -> bool {
+        self.transition(state, FlowStatus::Completed, |snapshot| {
+            snapshot.token_id = Some(token_id.to_string());
+        })
+    }
+
+    pub fn mark_failed(&self, state: &str, error: &str) -> bool {
+        self.transition(state, FlowStatus::Failed, |snapshot| {
+            snapshot.error = Some(error.to_string());
+        })
+    }
+
+    pub fn mark_expired(&self, state: &str) -> bool {
+        self.transition(state, FlowStatus::Expired, |_| {})
+    }
+
+    pub fn mark_cancelled(&self, state: &str) -> bool {
+        self.transition(state, FlowStatus::Cancelled, |_| {})
+    }
+
+    /// 以新的state延续同一个逻辑流程
+    ///
+    /// 只有等待授权、失败或过期且尚未续期的流程可以续期；旧流程未结束时标记为 `cancelled`，
+    /// 新流程沿用旧流程的 `status_token`。
+    pub fn renew(&self, old_state: &str, new_state: &OAuthState) -> Result<(), FlowAccessError> {
+        let status_token_hash = {
+            let entry = self.flows.get(old_state).ok_or(FlowAccessError::NotFound)?;
+
+            let mut renewed = false;
+            entry.sender.send_if_modified(|snapshot| {
+                if snapshot.renewed_to.is_some() || !snapshot.status.can_renew() {
+                    return false;
+                }
+                if snapshot.status.can_transition_to(FlowStatus::Cancelled) {
+                    apply(snapshot, FlowStatus::Cancelled);
+                }
+                snapshot.renewed_to = Some(new_state.state.expose().clone());
+                renewed = true;
+                true
+            });
+            if !renewed {
+                return Err(FlowAccessError::Conflict);
+            }
+
+            entry.status_token_hash.clone()
+        };
+
+        self.insert(new_state, status_token_hash, Some(old_state.to_string()));
+
+        Ok(())
+    }
+
+    /// 各状态的流程数量
+    pub fn counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.flows.iter() {
+            *counts.entry(entry.sender.borrow().status.as_str()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// 移除进入终态超过保留时间的流程，订阅方的流随之结束
+    pub fn prune(&self, retention: Duration) {
+        let cutoff = Utc::now() - retention;
+        self.flows.retain(|_, entry| {
+            let snapshot = entry.sender.borrow();
+            !(snapshot.status.is_terminal() && snapshot.updated_at < cutoff)
+        });
+    }
+
+    fn insert(
+        &self,
+        oauth_state: &OAuthState,
+        status_token_hash: Vec<u8>,
+        renewed_from: Option<String>,
+    ) {
+        let now = oauth_state.creation_time;
+        let (sender, _) = watch::channel(FlowSnapshot {
+            state: oauth_state.state.expose().clone(),
+            status: FlowStatus::Created,
+            user_id: oauth_state.user_id.clone(),
+            created_at: now,
+            updated_at: now,
+            expires_at: oauth_state.expires_at,
+            metadata: oauth_state.metadata.clone(),
+            token_id: None,
+            error: None,
+            renewed_from,
+            renewed_to: None,
+            history: vec![FlowTransition {
+                status: FlowStatus::Created,
+                at: now,
+            }],
+        });
+
+        self.flows.insert(
+            oauth_state.state.expose().clone(),
+            FlowEntry {
+                status_token_hash,
+                sender,
+            },
+        );
+    }
+
+    /// 按状态机迁移，非法迁移（包括流程不存在）返回 `false`
+    fn transition<F>(&self, state: &str, status: FlowStatus, update: F) -> bool
+    where
+        F: FnOnce(&mut FlowSnapshot),
+    {
+        let Some(entry) = self.flows.get(state) else {
+            return false;
+        };
+
+        entry.sender.send_if_modified(|snapshot| {
+            if !snapshot.status.can_transition_to(status) {
+                return false;
+            }
+            apply(snapshot, status);
+            update(snapshot);
+            true
+        })
+    }
+}
+
+/// 记录一次状态迁移
+fn apply(snapshot: &mut FlowSnapshot, status: FlowStatus) {
+    let now = Utc::now();
+    snapshot.status = status;
+    snapshot.updated_at = now;
+    snapshot.history.push(FlowTransition { status, at: now });
+}
+
+impl Default for FlowTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 等待流程状态发生变化，超时后返回当前状态
+pub async fn wait_for_change(
+    receiver: &mut watch::Receiver<FlowSnapshot>,
+    timeout: std::time::Duration,
+) -> FlowSnapshot {
+    let initial = receiver.borrow_and_update().status;
+    if !initial.is_terminal() {
+        let _ = tokio::time::timeout(
+            timeout,
+            receiver.wait_for(|snapshot| snapshot.status != initial),
+        )
+        .await;
+    }
+    receiver.borrow().clone()
+}
+
+fn generate_status_token() -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    use rand::Rng;
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oauth_state(state: &str, user_id: Option<&str>) -> OAuthState {
+        let mut oauth_state = OAuthState::new(
+            user_id.map(str::to_string),
+            Duration::minutes(30),
+            FlowMetadata::default(),
+        );
+        oauth_state.state = state.into();
+        oauth_state
+    }
+
+    fn status(tracker: &FlowTracker, state: &str, status_token: &str) -> FlowSnapshot {
+        tracker.subscribe(state, status_token).unwrap().borrow().clone()
+    }
+
+    #[test]
+    fn test_only_requester_can_subscribe() {
+        let tracker = FlowTracker::new();
+        let status_token = tracker.register(&oauth_state("state-1", None));
+
+        assert!(tracker.subscribe("state-1", &status_token).is_ok());
+        assert_eq!(
+            tracker.subscribe("state-1", "guess").unwrap_err(),
+            FlowAccessError::Forbidden
+        );
+        assert_eq!(
+            tracker.subscribe("unknown", &status_token).unwrap_err(),
+            FlowAccessError::NotFound
+        );
+    }
+
+    #[test]
+    fn test_successful_flow_records_history() {
+        let tracker = FlowTracker::new();
+        let status_token = tracker.register(&oauth_state("state-1", Some("user-1")));
+
+        assert!(tracker.mark_code_received("state-1"));
+        assert!(tracker.mark_exchanging("state-1"));
+        assert!(tracker.mark_completed("state-1", "token-1"));
+
+        let snapshot = status(&tracker, "state-1", &status_token);
+        assert_eq!(snapshot.status, FlowStatus::Completed);
+        assert_eq!(snapshot.token_id.as_deref(), Some("token-1"));
+        assert_eq!(snapshot.user_id.as_deref(), Some("user-1"));
+        let history: Vec<FlowStatus> = snapshot.history.iter().map(|t| t.status).collect();
+        assert_eq!(
+            history,
+            vec![
+                FlowStatus::Created,
+                FlowStatus::CodeReceived,
+                FlowStatus::Exchanging,
+                FlowStatus::Completed
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_transitions() {
+        let tracker = FlowTracker::new();
+        let status_token = tracker.register(&oauth_state("state-1", None));
+
+        // 不能跳过阶段
+        assert!(!tracker.mark_completed("state-1", "token-1"));
+        assert!(!tracker.mark_exchanging("state-1"));
+
+        assert!(tracker.mark_code_received("state-1"));
+        assert!(tracker.mark_failed("state-1", "exchange failed"));
+
+        // 终态之后不再迁移
+        assert!(!tracker.mark_expired("state-1"));
+        assert!(!tracker.mark_code_received("state-1"));
+
+        let snapshot = status(&tracker, "state-1", &status_token);
+        assert_eq!(snapshot.status, FlowStatus::Failed);
+        assert_eq!(snapshot.error.as_deref(), Some("exchange failed"));
+        assert_eq!(snapshot.history.len(), 3);
+        assert!(!tracker.mark_failed("unknown", "missing"));
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_returns_on_change() {
+        let tracker = std::sync::Arc::new(FlowTracker::new());
+        let status_token = tracker.register(&oauth_state("state-1", None));
+        let mut receiver = tracker.subscribe("state-1", &status_token).unwrap();
+
+        let completer = tracker.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            completer.mark_code_received("state-1");
+        });
+
+        let snapshot = wait_for_change(&mut receiver, std::time::Duration::from_secs(5)).await;
+        assert_eq!(snapshot.status, FlowStatus::CodeReceived);
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_times_out_without_change() {
+        let tracker = FlowTracker::new();
+        let status_token = tracker.register(&oauth_state("state-1", None));
+        let mut receiver = tracker.subscribe("state-1", &status_token).unwrap();
+
+        let snapshot = wait_for_change(&mut receiver, std::time::Duration::from_millis(10)).await;
+        assert_eq!(snapshot.status, FlowStatus::Created);
+    }
+
+    #[test]
+    fn test_renew_continues_flow_with_same_status_token() {
+        let tracker = FlowTracker::new();
+        let status_token = tracker.register(&oauth_state("state-1", Some("user-1")));
+
+        tracker.renew("state-1", &oauth_state("state-2", None)).unwrap();
+
+        let old = status(&tracker, "state-1", &status_token);
+        assert_eq!(old.status, FlowStatus::Cancelled);
+        assert_eq!(old.renewed_to.as_deref(), Some("state-2"));
+
+        let new = status(&tracker, "state-2", &status_token);
+        assert_eq!(new.status, FlowStatus::Created);
+        assert_eq!(new.renewed_from.as_deref(), Some("state-1"));
+
+        // 已续期或已完成的流程不能再续期
+        assert_eq!(tracker.renew("state-1", &oauth_state("state-3", None)), Err(FlowAccessError::Conflict));
+        tracker.mark_code_received("state-2");
+        assert_eq!(tracker.renew("state-2", &oauth_state("state-3", None)), Err(FlowAccessError::Conflict));
+        assert_eq!(tracker.renew("unknown", &oauth_state("state-3", None)), Err(FlowAccessError::NotFound));
+    }
+
+    #[test]
+    fn test_prune_keeps_flows_within_retention() {
+        let tracker = FlowTracker::new();
+        let expired_token = tracker.register(&oauth_state("expired", None));
+        let pending_token = tracker.register(&oauth_state("pending", None));
+        tracker.mark_expired("expired");
+
+        tracker.prune(Duration::minutes(5));
+        assert!(tracker.subscribe("expired", &expired_token).is_ok());
+        assert_eq!(tracker.counts().get("expired"), Some(&1));
+
+        tracker.prune(Duration::seconds(-1));
+        assert!(tracker.subscribe("expired", &expired_token).is_err());
+        assert!(tracker.subscribe("pending", &pending_token).is_ok());
+    }
+}
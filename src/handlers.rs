@@ -1,15 +1,35 @@
 use axum::{
-    extract::{Query, State},
-    response::{IntoResponse, Json, Response},
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Extension,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Redirect, Response,
+    },
 };
+use futures::stream::{self, Stream, StreamExt};
 use serde::Deserialize;
-use tracing::info;
-use uuid::Uuid;
-use chrono::Utc;
+use std::convert::Infallible;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use url::Url;
 
 use crate::{
-    models::{ApiResponse, AuthUrlData, CompleteAuthRequest, CompleteAuthData, TokenInfo},
-    middleware::{bad_request, internal_server_error},
+    auth::Principal,
+    completion::{self, Begin, CompletionFailure, CompletionResult},
+    flow::{self, state_id, FlowAccessError, FlowSnapshot, FlowStatus},
+    metrics,
+    webhook::WebhookEventType,
+    models::{
+        ApiResponse, AuthUrlData, CompleteAuthRequest, CompleteAuthData, FlowMetadata,
+        IntrospectRequest, OAuthState, RedeemRequest, TokenRefreshStatus,
+    },
+    middleware::{
+        bad_request, conflict, create_error_response, forbidden, internal_server_error, not_found,
+        service_unavailable, unauthorized,
+    },
+    oauth::{GeneratedAuthUrl, StateCapacityError},
+    request_id,
     AppState,
 };
 
@@ -17,86 +37,1230 @@ use crate::{
 #[derive(Debug, Deserialize)]
 pub struct AuthUrlQuery {
     pub user_id: Option<String>,
+    /// state有效期（秒），不能超过配置的上限
+    pub ttl_seconds: Option<u64>,
+    pub return_url: Option<String>,
+    pub client_reference: Option<String>,
+}
+
+/// 创建授权流程的请求体
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthUrlRequest {
+    pub user_id: Option<String>,
+    /// state有效期（秒），不能超过配置的上限
+    pub ttl_seconds: Option<u64>,
+    #[serde(default)]
+    pub metadata: FlowMetadata,
+}
+
+impl From<AuthUrlQuery> for AuthUrlRequest {
+    fn from(query: AuthUrlQuery) -> Self {
+        Self {
+            user_id: query.user_id,
+            ttl_seconds: query.ttl_seconds,
+            metadata: FlowMetadata {
+                labels: Default::default(),
+                return_url: query.return_url,
+                client_reference: query.client_reference,
+            },
+        }
+    }
 }
 
 /// 获取OAuth授权链接
 pub async fn get_auth_url(
     Query(query): Query<AuthUrlQuery>,
     State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
 ) -> Response {
-    info!("收到获取授权链接请求, user_id: {:?}", query.user_id);
+    create_auth_flow(&state, query.into(), principal.as_deref())
+}
 
-    match state.oauth_service.generate_auth_url() {
-        Ok((auth_url, state_param)) => {
-            info!("授权链接生成成功: {}", auth_url);
+/// 创建OAuth授权流程，可以附加标签等元数据
+pub async fn post_auth_url(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Json(request): Json<AuthUrlRequest>,
+) -> Response {
+    create_auth_flow(&state, request, principal.as_deref())
+}
+
+fn create_auth_flow(
+    state: &AppState,
+    mut request: AuthUrlRequest,
+    principal: Option<&Principal>,
+) -> Response {
+    // JWT调用方创建的流程绑定到令牌主体
+    if let Some(subject) = principal.and_then(|principal| principal.subject.as_ref()) {
+        if request.user_id.as_ref().is_some_and(|user_id| user_id != subject) {
+            return forbidden("user_id 必须与调用方令牌的主体一致".to_string());
+        }
+        request.user_id = Some(subject.clone());
+    }
+
+    let requested_by = principal.map(|principal| principal.name.as_str());
+    info!(
+        "收到获取授权链接请求, user_id: {:?}, 调用方: {:?}",
+        request.user_id, requested_by
+    );
+
+    let ttl = match state.oauth_service.state_ttl(request.ttl_seconds) {
+        Ok(ttl) => ttl,
+        Err(e) => return bad_request(e.to_string()),
+    };
+    if let Err(message) = request.metadata.validate() {
+        return bad_request(message);
+    }
+    if let Some(return_url) = &request.metadata.return_url {
+        if let Err(e) = state.oauth_service.validate_return_url(return_url) {
+            return bad_request(e.to_string());
+        }
+    }
+
+    match state
+        .oauth_service
+        .generate_auth_url(request.user_id, ttl, request.metadata)
+    {
+        Ok(GeneratedAuthUrl {
+            authorize_url: auth_url,
+            oauth_state,
+            evicted,
+        }) => {
+            info!(
+                "授权链接生成成功, state_id: {}, user_id: {:?}",
+                state_id(oauth_state.state.as_str()),
+                oauth_state.user_id
+            );
+            cancel_evicted(state, evicted);
+
+            state.webhooks.emit(
+                WebhookEventType::AuthUrlCreated,
+                serde_json::json!({
+                    "state": oauth_state.state,
+                    "user_id": oauth_state.user_id,
+                    "expires_at": oauth_state.expires_at,
+                    "metadata": oauth_state.metadata,
+                    "requested_by": requested_by,
+                }),
+            );
+
+            let status_token = state.flows.register(&oauth_state);
 
             let data = AuthUrlData {
                 authorize_url: auth_url,
-                state: state_param,
+                state: oauth_state.state,
+                status_token: status_token.into(),
             };
 
             Json(ApiResponse::success_with_message(data, "授权链接生成成功".to_string())).into_response()
         }
-        Err(e) => {
-            internal_server_error(format!("获取授权链接失败: {}", e))
-        }
+        Err(e) => auth_url_error(e),
+    }
+}
+
+/// 生成授权链接失败的响应，容量已满时返回503
+fn auth_url_error(e: anyhow::Error) -> Response {
+    match e.downcast_ref::<StateCapacityError>() {
+        Some(capacity) => service_unavailable(capacity.to_string()),
+        None => internal_server_error(format!("获取授权链接失败: {}", e)),
+    }
+}
+
+/// 因容量被淘汰的流程标记为已取消，并通知订阅方
+fn cancel_evicted(state: &AppState, evicted: Vec<OAuthState>) {
+    for oauth_state in evicted {
+        state.flows.mark_cancelled(oauth_state.state.as_str());
+        state.webhooks.emit(
+            WebhookEventType::AuthCancelled,
+            serde_json::json!({
+                "state": oauth_state.state,
+                "user_id": oauth_state.user_id,
+                "reason": "evicted",
+                "metadata": oauth_state.metadata,
+            }),
+        );
     }
 }
 
 /// 完成OAuth授权，获取访问令牌
 pub async fn complete_auth(
     State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
     Json(request): Json<CompleteAuthRequest>,
 ) -> Response {
     info!(
-        "收到完成授权请求, code: {}, state: {}, tenant_url: {}",
-        if request.code.is_empty() { "empty" } else { "present" },
-        request.state,
-        request.tenant_url
+        "收到完成授权请求, state_id: {}, tenant_url: {}, 调用方: {:?}",
+        state_id(request.state.as_str()),
+        request.tenant_url,
+        principal.as_ref().map(|principal| principal.name.as_str())
     );
 
     // 验证必需参数
-    if request.code.is_empty() || request.state.is_empty() || request.tenant_url.is_empty() {
+    if request.code.as_str().is_empty()
+        || request.state.as_str().is_empty()
+        || request.tenant_url.is_empty()
+    {
         return bad_request("无效的请求数据: code, state, tenant_url 都是必需的".to_string());
     }
 
-    // 获取OAuth状态
-    let oauth_state = match state.oauth_service.get_oauth_state(&request.state) {
+    // JWT调用方只能完成绑定到自己的流程
+    if let Some(subject) = principal.as_ref().and_then(|principal| principal.subject.as_ref()) {
+        if let Some(snapshot) = state.flows.snapshot(request.state.as_str()) {
+            if snapshot.user_id.as_ref() != Some(subject) {
+                return forbidden("该授权流程不属于当前调用方".to_string());
+            }
+        }
+    }
+
+    match complete(&state, request).await {
+        Ok(data) => {
+            Json(ApiResponse::success_with_message(data, "OAuth授权完成成功".to_string())).into_response()
+        }
+        Err(failure) => create_error_response(failure.status, failure.message),
+    }
+}
+
+/// 完成授权
+///
+/// 同一state只允许交换一次：相同的并发请求合并，完全相同的重试直接返回之前的结果。
+async fn complete(state: &AppState, request: CompleteAuthRequest) -> CompletionResult {
+    let receiver = match state
+        .completions
+        .begin(request.state.as_str(), request.code.as_str(), &request.tenant_url)
+    {
+        Begin::Leader(receiver) => {
+            // 在独立任务中交换，客户端断开连接也不会中断，等待方总能得到结果
+            let leader_state = state.clone();
+            tokio::spawn(request_id::inherit(async move {
+                let result = perform_completion(&leader_state, &request).await;
+                leader_state.completions.finish(request.state.as_str(), result);
+            }));
+            receiver
+        }
+        Begin::Follower(receiver) => {
+            info!("合并相同的完成授权请求, state_id: {}", state_id(request.state.as_str()));
+            metrics::completion("merged", "");
+            receiver
+        }
+        Begin::Replay(data) => {
+            info!(
+                "重复的完成授权请求，返回已有结果, state_id: {}",
+                state_id(request.state.as_str())
+            );
+            metrics::completion("replayed", "");
+            return Ok(*data);
+        }
+        Begin::Conflict => {
+            metrics::completion("conflict", "");
+            return Err(CompletionFailure {
+                status: StatusCode::CONFLICT,
+                message: "该授权流程正在处理或已完成，请勿重复提交".to_string(),
+            });
+        }
+    };
+
+    completion::wait(receiver).await
+}
+
+/// 浏览器授权回调的参数
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub tenant_url: Option<String>,
+    /// 用户拒绝授权等情况下授权服务器返回的错误码
+    pub error: Option<String>,
+}
+
+/// 浏览器授权回调
+///
+/// 完成授权后不向浏览器暴露令牌：流程附带了允许的 `return_url` 时重定向回应用并携带一次性取回码，
+/// 否则直接返回取回码；应用在服务端通过 `/api/redeem` 换取令牌。
+pub async fn oauth_callback(
+    Query(query): Query<CallbackQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(state_param) = query.state.filter(|state_param| !state_param.is_empty()) else {
+        return bad_request("无效的回调参数: 缺少 state".to_string());
+    };
+    info!("收到授权回调, state_id: {}", state_id(&state_param));
+
+    // state被消费后流程记录仍在保留期内，失败时也能找到返回地址
+    let return_url = state
+        .flows
+        .snapshot(&state_param)
+        .and_then(|snapshot| snapshot.metadata.return_url);
+
+    let result = match (query.error, query.code, query.tenant_url) {
+        (Some(error), _, _) => Err(CompletionFailure {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("授权服务器返回错误: {}", error),
+        }),
+        (None, Some(code), Some(tenant_url)) if !code.is_empty() && !tenant_url.is_empty() => {
+            let request = CompleteAuthRequest {
+                code: code.into(),
+                state: state_param.as_str().into(),
+                tenant_url,
+            };
+            complete(&state, request).await
+        }
+        _ => Err(CompletionFailure {
+            status: StatusCode::BAD_REQUEST,
+            message: "无效的回调参数: code, state, tenant_url 都是必需的".to_string(),
+        }),
+    };
+
+    // 重放窗口内重复的回调得到同一个结果，每个流程只签发一个取回码
+    let result = result.and_then(|data| {
+        let return_url = data.metadata.return_url.clone();
+        match state.retrievals.issue(data) {
+            Some(retrieval_code) => Ok((retrieval_code, return_url)),
+            None => Err(CompletionFailure {
+                status: StatusCode::CONFLICT,
+                message: "该授权流程已签发取回码，请勿重复回调".to_string(),
+            }),
+        }
+    });
+
+    match result {
+        Ok((retrieval_code, return_url)) => {
+            match return_url.and_then(|url| allowed_return_url(&state, &url)) {
+                Some(mut url) => {
+                    url.query_pairs_mut()
+                        .append_pair("retrieval_code", &retrieval_code)
+                        .append_pair("state", &state_param);
+                    Redirect::to(url.as_str()).into_response()
+                }
+                None => {
+                    let data = serde_json::json!({
+                        "status": "success",
+                        "state": state_param,
+                        "retrieval_code": retrieval_code,
+                    });
+                    Json(ApiResponse::success_with_message(data, "OAuth授权完成成功".to_string()))
+                        .into_response()
+                }
+            }
+        }
+        Err(failure) => match return_url.and_then(|url| allowed_return_url(&state, &url)) {
+            Some(mut url) => {
+                let error = if failure.status == StatusCode::CONFLICT {
+                    "already_completed"
+                } else {
+                    "auth_failed"
+                };
+                url.query_pairs_mut()
+                    .append_pair("error", error)
+                    .append_pair("state", &state_param);
+                Redirect::to(url.as_str()).into_response()
+            }
+            None => create_error_response(failure.status, failure.message),
+        },
+    }
+}
+
+/// 重定向前再次校验返回地址
+fn allowed_return_url(state: &AppState, url: &str) -> Option<Url> {
+    match state.oauth_service.validate_return_url(url) {
+        Ok(url) => Some(url),
+        Err(e) => {
+            warn!("忽略不允许的返回地址 {}: {}", url, e);
+            None
+        }
+    }
+}
+
+/// 用一次性取回码换取令牌
+pub async fn redeem(
+    State(state): State<AppState>,
+    Json(request): Json<RedeemRequest>,
+) -> Response {
+    if request.retrieval_code.as_str().is_empty() {
+        return bad_request("无效的请求数据: retrieval_code 是必需的".to_string());
+    }
+
+    match state.retrievals.redeem(request.retrieval_code.as_str()) {
+        Some(data) => {
+            Json(ApiResponse::success_with_message(data, "OAuth授权完成成功".to_string())).into_response()
+        }
+        None => not_found("取回码无效、已使用或已过期".to_string()),
+    }
+}
+
+/// 取出OAuth状态并交换令牌，每个state只会执行一次
+async fn perform_completion(state: &AppState, request: &CompleteAuthRequest) -> CompletionResult {
+    // 原子地取出OAuth状态
+    let oauth_state = match state.oauth_service.take_oauth_state(request.state.as_str()) {
         Ok(state) => state,
         Err(e) => {
-            return bad_request(e.to_string());
+            metrics::completion("failed", "invalid_state");
+            // 只为确实发起过的流程发布失败事件，随意构造的state不会写入webhook队列
+            if state.flows.snapshot(request.state.as_str()).is_some() {
+                emit_auth_failed(state, request, None, &e.to_string());
+            }
+            return Err(CompletionFailure {
+                status: StatusCode::BAD_REQUEST,
+                message: e.to_string(),
+            });
         }
     };
+    state.flows.mark_code_received(request.state.as_str());
 
     // 使用授权码交换访问令牌
-    let access_token = match state
+    state.flows.mark_exchanging(request.state.as_str());
+    let token_record = match state
         .oauth_service
-        .exchange_token(&request.tenant_url, &oauth_state.code_verifier, &request.code)
+        .exchange_token(&request.tenant_url, &oauth_state, request.code.as_str())
         .await
     {
-        Ok(token) => token,
+        Ok(record) => record,
         Err(e) => {
-            return internal_server_error(format!("Token交换失败: {}", e));
+            let message = format!("Token交换失败: {}", e);
+            error!("{}, state_id: {}", message, state_id(request.state.as_str()));
+            metrics::completion("failed", metrics::exchange_error_code(&e));
+            emit_auth_failed(state, request, Some(&oauth_state), &message);
+            state.flows.mark_failed(request.state.as_str(), &message);
+            return Err(CompletionFailure {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                message,
+            });
         }
     };
 
-    // 清除已使用的OAuth状态
-    state.oauth_service.clear_oauth_state(&request.state);
+    // 保存token记录（含DPoP密钥绑定）
+    state.token_store.insert(token_record.clone());
+    state.flows.mark_completed(request.state.as_str(), &token_record.id);
+    metrics::completion("success", "");
 
-    // 生成token信息
-    let token_info = TokenInfo {
-        id: Uuid::new_v4().to_string(),
-        created_at: Utc::now(),
-    };
+    info!(
+        "OAuth授权完成成功, state_id: {}, token_id: {}, token_type: {}",
+        state_id(request.state.as_str()),
+        token_record.id,
+        token_record.token_type
+    );
 
-    info!("OAuth授权完成成功, token_id: {}", token_info.id);
+    state.webhooks.emit(
+        WebhookEventType::AuthCompleted,
+        serde_json::json!({
+            "state": request.state,
+            "user_id": token_record.user_id,
+            "tenant_url": token_record.tenant_url,
+            "token_id": token_record.id,
+            "token_type": token_record.token_type,
+            "expires_at": token_record.expires_at,
+            "identity": token_record.identity,
+            "metadata": oauth_state.metadata,
+        }),
+    );
 
-    let data = CompleteAuthData {
+    Ok(CompleteAuthData {
         status: "success".to_string(),
-        token: access_token,
-        tenant_url: request.tenant_url,
-        token_info,
+        token_info: token_record.token_info(),
+        token: token_record.access_token,
+        tenant_url: token_record.tenant_url,
+        dpop_key: token_record.dpop_key,
+        identity: token_record.identity,
+        metadata: oauth_state.metadata,
+    })
+}
+
+/// 长轮询的最长等待时间（秒）
+const MAX_STATUS_WAIT_SECONDS: u64 = 60;
+
+/// 查询流程状态的参数
+#[derive(Debug, Deserialize)]
+pub struct AuthStatusQuery {
+    /// 发起方凭据，也可以通过 `X-Status-Token` 请求头传递
+    pub status_token: Option<String>,
+    /// 长轮询：状态发生变化前最多等待的秒数
+    pub wait: Option<u64>,
+}
+
+/// 查询授权流程状态，支持长轮询
+pub async fn get_auth_status(
+    Path(state_param): Path<String>,
+    Query(query): Query<AuthStatusQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let mut receiver = match subscribe_flow(&state, &state_param, query.status_token.as_deref(), &headers) {
+        Ok(receiver) => receiver,
+        Err(response) => return *response,
     };
 
-    Json(ApiResponse::success_with_message(data, "OAuth授权完成成功".to_string())).into_response()
+    let snapshot = match query.wait.map(|wait| wait.min(MAX_STATUS_WAIT_SECONDS)) {
+        Some(wait) if wait > 0 => {
+            flow::wait_for_change(&mut receiver, std::time::Duration::from_secs(wait)).await
+        }
+        _ => receiver.borrow().clone(),
+    };
+
+    Json(ApiResponse::success_with_message(snapshot, "查询授权状态成功".to_string())).into_response()
+}
+
+/// 通过SSE推送授权流程状态，流程进入终态后结束
+pub async fn auth_events(
+    Path(state_param): Path<String>,
+    Query(query): Query<AuthStatusQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    match subscribe_flow(&state, &state_param, query.status_token.as_deref(), &headers) {
+        // 停止服务时结束事件流，避免长连接拖住优雅停止
+        Ok(receiver) => Sse::new(flow_events(receiver).take_until(state.shutdown.wait()))
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+        Err(response) => *response,
+    }
+}
+
+/// 校验发起方凭据并订阅流程状态
+fn subscribe_flow(
+    state: &AppState,
+    state_param: &str,
+    query_token: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<watch::Receiver<FlowSnapshot>, Box<Response>> {
+    let status_token = status_token(query_token, headers)
+        .ok_or_else(|| Box::new(unauthorized("缺少 status_token".to_string())))?;
+
+    state
+        .flows
+        .subscribe(state_param, status_token)
+        .map_err(|e| Box::new(flow_error_response(e)))
+}
+
+/// 发起方凭据，请求头优先于查询参数
+fn status_token<'a>(query_token: Option<&'a str>, headers: &'a HeaderMap) -> Option<&'a str> {
+    headers
+        .get("X-Status-Token")
+        .and_then(|value| value.to_str().ok())
+        .or(query_token)
+}
+
+fn flow_error_response(error: FlowAccessError) -> Response {
+    match error {
+        FlowAccessError::NotFound => not_found("未找到授权流程".to_string()),
+        FlowAccessError::Forbidden => forbidden("status_token 无效".to_string()),
+        FlowAccessError::Conflict => conflict("授权流程正在处理或已结束".to_string()),
+    }
+}
+
+/// 操作授权流程的参数
+#[derive(Debug, Deserialize)]
+pub struct FlowTokenQuery {
+    /// 发起方凭据，也可以通过 `X-Status-Token` 请求头传递
+    pub status_token: Option<String>,
+}
+
+/// 取消等待授权的流程
+pub async fn cancel_auth(
+    Path(state_param): Path<String>,
+    Query(query): Query<FlowTokenQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let receiver = match subscribe_flow(&state, &state_param, query.status_token.as_deref(), &headers) {
+        Ok(receiver) => receiver,
+        Err(response) => return *response,
+    };
+
+    // 以移除OAuth状态为准，避免与正在进行的完成授权竞争
+    let cancelled = receiver.borrow().status == FlowStatus::Created
+        && state.oauth_service.cancel_oauth_state(&state_param).is_some();
+    if !cancelled {
+        return flow_error_response(FlowAccessError::Conflict);
+    }
+    state.flows.mark_cancelled(&state_param);
+
+    let snapshot = receiver.borrow().clone();
+    info!("授权流程已取消, state_id: {}", state_id(&state_param));
+    state.webhooks.emit(
+        WebhookEventType::AuthCancelled,
+        serde_json::json!({
+            "state": state_param,
+            "user_id": snapshot.user_id,
+            "reason": "cancelled",
+            "metadata": snapshot.metadata,
+        }),
+    );
+
+    Json(ApiResponse::success_with_message(snapshot, "授权流程已取消".to_string())).into_response()
+}
+
+/// 为等待授权、失败或过期的流程生成新的授权链接
+///
+/// 新流程使用新的PKCE参数和state，沿用原流程的用户绑定和 `status_token`。
+pub async fn renew_auth(
+    Path(state_param): Path<String>,
+    Query(query): Query<FlowTokenQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let receiver = match subscribe_flow(&state, &state_param, query.status_token.as_deref(), &headers) {
+        Ok(receiver) => receiver,
+        Err(response) => return *response,
+    };
+    let snapshot = receiver.borrow().clone();
+
+    if snapshot.renewed_to.is_some() || !snapshot.status.can_renew() {
+        return flow_error_response(FlowAccessError::Conflict);
+    }
+    // 等待中的流程先放弃原state，腾出的容量留给新state
+    let cancelled = if snapshot.status == FlowStatus::Created {
+        match state.oauth_service.cancel_oauth_state(&state_param) {
+            Some(oauth_state) => Some(oauth_state),
+            None => return flow_error_response(FlowAccessError::Conflict),
+        }
+    } else {
+        None
+    };
+
+    let generated = match state.oauth_service.generate_auth_url(
+        snapshot.user_id.clone(),
+        snapshot.expires_at - snapshot.created_at,
+        snapshot.metadata.clone(),
+    ) {
+        Ok(generated) => generated,
+        Err(e) => {
+            if let Some(oauth_state) = cancelled {
+                state.oauth_service.restore_oauth_state(oauth_state);
+            }
+            return auth_url_error(e);
+        }
+    };
+    cancel_evicted(&state, generated.evicted);
+    let (auth_url, new_state) = (generated.authorize_url, generated.oauth_state);
+    if let Err(e) = state.flows.renew(&state_param, &new_state) {
+        state.oauth_service.cancel_oauth_state(new_state.state.as_str());
+        return flow_error_response(e);
+    }
+
+    info!(
+        "授权流程已续期, state_id: {} -> {}",
+        state_id(&state_param),
+        state_id(new_state.state.as_str())
+    );
+    if snapshot.status == FlowStatus::Created {
+        state.webhooks.emit(
+            WebhookEventType::AuthCancelled,
+            serde_json::json!({
+                "state": state_param,
+                "user_id": snapshot.user_id,
+                "reason": "renewed",
+                "renewed_to": new_state.state,
+                "metadata": snapshot.metadata,
+            }),
+        );
+    }
+    state.webhooks.emit(
+        WebhookEventType::AuthUrlCreated,
+        serde_json::json!({
+            "state": new_state.state,
+            "user_id": new_state.user_id,
+            "expires_at": new_state.expires_at,
+            "metadata": new_state.metadata,
+            "renewed_from": state_param,
+        }),
+    );
+
+    let data = AuthUrlData {
+        authorize_url: auth_url,
+        state: new_state.state,
+        status_token: status_token(query.status_token.as_deref(), &headers)
+            .unwrap_or_default()
+            .into(),
+    };
+
+    Json(ApiResponse::success_with_message(data, "授权链接生成成功".to_string())).into_response()
+}
+
+/// 先推送当前状态，之后每次变化推送一次，终态后结束
+fn flow_events(
+    receiver: watch::Receiver<FlowSnapshot>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold((receiver, true, false), |(mut receiver, first, done)| async move {
+        if done || (!first && receiver.changed().await.is_err()) {
+            return None;
+        }
+
+        let snapshot = receiver.borrow_and_update().clone();
+        let terminal = snapshot.status.is_terminal();
+        let event = Event::default()
+            .event("status")
+            .json_data(&snapshot)
+            .unwrap_or_default();
+
+        Some((Ok(event), (receiver, false, terminal)))
+    })
+}
+
+/// 发布授权失败事件
+fn emit_auth_failed(
+    state: &AppState,
+    request: &CompleteAuthRequest,
+    oauth_state: Option<&OAuthState>,
+    reason: &str,
+) {
+    state.webhooks.emit(
+        WebhookEventType::AuthFailed,
+        serde_json::json!({
+            "state": request.state,
+            "user_id": oauth_state.and_then(|oauth_state| oauth_state.user_id.as_deref()),
+            "tenant_url": request.tenant_url,
+            "reason": reason,
+            "metadata": oauth_state.map(|oauth_state| &oauth_state.metadata),
+        }),
+    );
+}
+
+/// 令牌自省
+pub async fn introspect(
+    State(state): State<AppState>,
+    Json(request): Json<IntrospectRequest>,
+) -> Response {
+    if request.token.as_str().is_empty() {
+        return bad_request("无效的请求数据: token 是必需的".to_string());
+    }
+
+    let data = state
+        .introspection
+        .introspect(request.token.as_str(), request.tenant_url.as_deref())
+        .await;
+
+    info!(
+        "令牌自省完成, active: {}, source: {}",
+        data.active, data.source
+    );
+
+    Json(ApiResponse::success_with_message(data, "令牌自省成功".to_string())).into_response()
+}
+
+/// 刷新状态的查询参数
+#[derive(Debug, Deserialize)]
+pub struct RefreshStatusQuery {
+    /// 只返回需要重新授权的记录
+    pub needs_reauth: Option<bool>,
+}
+
+/// 查询令牌后台刷新状态，便于运维定位需要重新授权的账号
+pub async fn refresh_status(
+    Query(query): Query<RefreshStatusQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let mut statuses: Vec<TokenRefreshStatus> = state
+        .token_store
+        .list()
+        .iter()
+        .map(|record| record.refresh_status())
+        .filter(|status| query.needs_reauth.is_none_or(|needs_reauth| status.needs_reauth == needs_reauth))
+        .collect();
+    statuses.sort_by_key(|status| status.expires_at);
+
+    Json(ApiResponse::success_with_message(statuses, "查询刷新状态成功".to_string())).into_response()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        completion::CompletionRegistry,
+        auth::Authenticator,
+        config::{AppConfig, AuthMode, StateOverflowPolicy},
+        flow::FlowTracker,
+        introspection::IntrospectionService, oauth::OAuthService,
+        rate_limit::{MemoryRateLimitStore, RateLimiter}, retrieval::RetrievalCodes,
+        shutdown::Shutdown, test_support::spawn_server, token_store::TokenStore,
+        webhook::WebhookDispatcher,
+    };
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    pub(crate) fn app_state() -> AppState {
+        let mut config = AppConfig::default();
+        config.oauth.return_url_allowed_origins = vec!["https://app.example.com".to_string()];
+        let oauth_service = Arc::new(OAuthService::new(config.oauth).unwrap());
+        let token_store = Arc::new(TokenStore::new());
+        AppState {
+            introspection: Arc::new(IntrospectionService::new(
+                oauth_service.clone(),
+                token_store.clone(),
+                30,
+            )),
+            oauth_service,
+            token_store,
+            webhooks: Arc::new(WebhookDispatcher::new(config.webhook).unwrap()),
+            flows: Arc::new(FlowTracker::new()),
+            completions: Arc::new(CompletionRegistry::new(60)),
+            retrievals: Arc::new(RetrievalCodes::new(60, 60)),
+            authenticator: Arc::new(Authenticator::with_api_keys(AuthMode::None, Vec::new())),
+            rate_limiter: Arc::new(
+                RateLimiter::new(config.rate_limit, Arc::new(MemoryRateLimitStore::default()))
+                    .unwrap(),
+            ),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// 启动较慢的token端点，返回地址和调用次数
+    async fn spawn_slow_token_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        use axum::{routing::post, Router};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route(
+            "/token",
+            post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                Json(serde_json::json!({ "access_token": "access-1", "expires_in": 3600 }))
+            }),
+        );
+        (spawn_server(app).await, calls)
+    }
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn auth_url_request(return_url: Option<&str>) -> AuthUrlRequest {
+        AuthUrlRequest {
+            user_id: Some("user-1".to_string()),
+            ttl_seconds: Some(600),
+            metadata: FlowMetadata {
+                labels: [("team".to_string(), "growth".to_string())].into(),
+                return_url: return_url.map(str::to_string),
+                client_reference: Some("ref-1".to_string()),
+            },
+        }
+    }
+
+    /// 通过接口生成授权链接，返回 (state, status_token)
+    async fn start_flow(state: &AppState) -> (String, String) {
+        start_flow_with_return_url(state, None).await
+    }
+
+    async fn start_flow_with_return_url(
+        state: &AppState,
+        return_url: Option<&str>,
+    ) -> (String, String) {
+        let request = auth_url_request(return_url);
+        let body = body_json(post_auth_url(State(state.clone()), None, Json(request)).await).await;
+        (
+            body["data"]["state"].as_str().unwrap().to_string(),
+            body["data"]["status_token"].as_str().unwrap().to_string(),
+        )
+    }
+
+    fn token_query(status_token: &str) -> Query<FlowTokenQuery> {
+        Query(FlowTokenQuery {
+            status_token: Some(status_token.to_string()),
+        })
+    }
+
+    fn request(state_param: &str, code: &str, addr: SocketAddr) -> CompleteAuthRequest {
+        CompleteAuthRequest {
+            code: code.into(),
+            state: state_param.into(),
+            tenant_url: format!("http://{}/", addr),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_identical_completions_exchange_once() {
+        let (addr, calls) = spawn_slow_token_server().await;
+        let state = app_state();
+        let (state_param, _) = start_flow(&state).await;
+
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let state = state.clone();
+                let request = request(&state_param, "code-1", addr);
+                tokio::spawn(async move { complete_auth(State(state), None, Json(request)).await })
+            })
+            .collect();
+
+        for handle in handles {
+            let response = handle.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = body_json(response).await;
+            assert_eq!(body["data"]["token"], "access-1");
+            assert_eq!(body["data"]["metadata"]["client_reference"], "ref-1");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(state.token_store.len(), 1);
+
+        // 同一state换用其他授权码会被拒绝
+        let response = complete_auth(State(state.clone()), None, Json(request(&state_param, "code-2", addr))).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_state_does_not_emit_auth_failed() {
+        let mut state = app_state();
+        let mut config = AppConfig::default().webhook;
+        config.urls = vec!["http://127.0.0.1:9/hook".to_string()];
+        config.secret = Some("webhook-secret".into());
+        state.webhooks = Arc::new(WebhookDispatcher::new(config).unwrap());
+        let tenant: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        let response = complete_auth(State(state.clone()), None, Json(request("random-state", "code", tenant))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.webhooks.pending_count(), 0);
+
+        // 发起过但已取消的流程仍然发布失败事件
+        let (state_param, _) = start_flow(&state).await;
+        state.oauth_service.cancel_oauth_state(&state_param);
+        let pending = state.webhooks.pending_count();
+        let response = complete_auth(State(state.clone()), None, Json(request(&state_param, "code", tenant))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.webhooks.pending_count(), pending + 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_pending_flow() {
+        let state = app_state();
+        let (state_param, status_token) = start_flow(&state).await;
+
+        let response = cancel_auth(
+            Path(state_param.clone()),
+            token_query("wrong-token"),
+            HeaderMap::new(),
+            State(state.clone()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = cancel_auth(
+            Path(state_param.clone()),
+            token_query(&status_token),
+            HeaderMap::new(),
+            State(state.clone()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["data"]["status"], "cancelled");
+        assert_eq!(state.oauth_service.active_states_count(), 0);
+
+        // 已取消的流程不能再取消或续期
+        let response = cancel_auth(
+            Path(state_param.clone()),
+            token_query(&status_token),
+            HeaderMap::new(),
+            State(state.clone()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = renew_auth(
+            Path(state_param),
+            token_query(&status_token),
+            HeaderMap::new(),
+            State(state),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_renew_issues_new_state_for_same_flow() {
+        let state = app_state();
+        let (state_param, status_token) = start_flow(&state).await;
+
+        let response = renew_auth(
+            Path(state_param.clone()),
+            token_query(&status_token),
+            HeaderMap::new(),
+            State(state.clone()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = body_json(response).await["data"].clone();
+        let new_state = data["state"].as_str().unwrap();
+        assert_ne!(new_state, state_param);
+        assert_eq!(data["status_token"], status_token.as_str());
+
+        // 原state已失效，新state可用于完成授权
+        assert!(state.oauth_service.take_oauth_state(&state_param).is_err());
+        let oauth_state = state.oauth_service.take_oauth_state(new_state).unwrap();
+        assert_eq!(oauth_state.user_id.as_deref(), Some("user-1"));
+        assert_eq!(oauth_state.metadata.labels["team"], "growth");
+        assert_eq!(
+            oauth_state.expires_at - oauth_state.creation_time,
+            chrono::Duration::seconds(600)
+        );
+
+        let renewed = state.flows.subscribe(new_state, &status_token).unwrap();
+        assert_eq!(renewed.borrow().renewed_from.as_deref(), Some(state_param.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_auth_url_rejects_ttl_above_limit() {
+        let state = app_state();
+        let query = AuthUrlQuery {
+            user_id: None,
+            ttl_seconds: Some(24 * 3600),
+            return_url: None,
+            client_reference: None,
+        };
+
+        let response = get_auth_url(Query(query), State(state.clone()), None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.oauth_service.active_states_count(), 0);
+    }
+
+    fn callback_query(state_param: &str, code: &str, addr: SocketAddr) -> Query<CallbackQuery> {
+        Query(CallbackQuery {
+            code: Some(code.to_string()),
+            state: Some(state_param.to_string()),
+            tenant_url: Some(format!("http://{}/", addr)),
+            error: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_callback_redirects_with_one_time_retrieval_code() {
+        let (addr, _) = spawn_slow_token_server().await;
+        let state = app_state();
+        let (state_param, _) =
+            start_flow_with_return_url(&state, Some("https://app.example.com/done?tab=1")).await;
+
+        let response =
+            oauth_callback(callback_query(&state_param, "code-1", addr), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers()["location"].to_str().unwrap();
+        assert!(!location.contains("access-1"));
+
+        let location = Url::parse(location).unwrap();
+        assert_eq!(location.origin().ascii_serialization(), "https://app.example.com");
+        let params: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(params["tab"], "1");
+        assert_eq!(params["state"], state_param);
+
+        // 重放窗口内的相同回调不会签发第二个取回码
+        let response =
+            oauth_callback(callback_query(&state_param, "code-1", addr), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let replayed = response.headers()["location"].to_str().unwrap();
+        assert!(replayed.contains("error=already_completed"));
+        assert!(!replayed.contains("retrieval_code"));
+
+        let redeem_request = || RedeemRequest {
+            retrieval_code: params["retrieval_code"].clone().into(),
+        };
+        let response = redeem(State(state.clone()), Json(redeem_request())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["data"]["token"], "access-1");
+        assert_eq!(body["data"]["metadata"]["client_reference"], "ref-1");
+
+        // 取回码只能使用一次
+        let response = redeem(State(state.clone()), Json(redeem_request())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // 重复回调被重定向回应用并带上错误
+        let response =
+            oauth_callback(callback_query(&state_param, "code-2", addr), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(response.headers()["location"]
+            .to_str()
+            .unwrap()
+            .contains("error=already_completed"));
+    }
+
+    #[tokio::test]
+    async fn test_return_url_outside_allowlist_is_rejected() {
+        let state = app_state();
+
+        for return_url in [
+            "https://evil.example.com/",
+            "https://app.example.com.evil.example.com/",
+            "//evil.example.com/",
+        ] {
+            let request = auth_url_request(Some(return_url));
+            let response = post_auth_url(State(state.clone()), None, Json(request)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", return_url);
+        }
+        assert_eq!(state.oauth_service.active_states_count(), 0);
+    }
+
+    fn with_state_cap(mut state: AppState, policy: StateOverflowPolicy) -> AppState {
+        let mut config = AppConfig::default().oauth;
+        config.max_pending_states_per_user = 1;
+        config.state_overflow_policy = policy;
+        state.oauth_service = Arc::new(OAuthService::new(config).unwrap());
+        state
+    }
+
+    #[tokio::test]
+    async fn test_full_state_capacity_returns_503() {
+        let state = with_state_cap(app_state(), StateOverflowPolicy::Reject);
+        start_flow(&state).await;
+
+        let response = post_auth_url(State(state.clone()), None, Json(auth_url_request(None))).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.oauth_service.active_states_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_evicted_flow_is_cancelled() {
+        let state = with_state_cap(app_state(), StateOverflowPolicy::EvictOldest);
+        let (first_state, first_token) = start_flow(&state).await;
+        let (second_state, _) = start_flow(&state).await;
+
+        let first = state.flows.subscribe(&first_state, &first_token).unwrap();
+        assert_eq!(first.borrow().status, FlowStatus::Cancelled);
+        assert!(state.oauth_service.take_oauth_state(&first_state).is_err());
+        assert!(state.oauth_service.take_oauth_state(&second_state).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_jwt_subject_is_bound_to_flow() {
+        let state = app_state();
+        let principal = |subject: &str| {
+            Some(Extension(Principal {
+                name: subject.to_string(),
+                scopes: Vec::new(),
+                subject: Some(subject.to_string()),
+            }))
+        };
+
+        // 不能替其他用户创建流程
+        let response =
+            post_auth_url(State(state.clone()), principal("alice"), Json(auth_url_request(None))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let mut unbound = auth_url_request(None);
+        unbound.user_id = None;
+        let body = body_json(post_auth_url(State(state.clone()), principal("alice"), Json(unbound)).await).await;
+        let state_param = body["data"]["state"].as_str().unwrap().to_string();
+        assert_eq!(state.flows.snapshot(&state_param).unwrap().user_id.as_deref(), Some("alice"));
+
+        // 其他主体不能完成该流程
+        let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let response = complete_auth(
+            State(state.clone()),
+            principal("bob"),
+            Json(request(&state_param, "code-1", addr)),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.oauth_service.active_states_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_token_material_in_logs() {
+        use axum::{routing::post, Router};
+
+        let logs = crate::logging::tests::CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .finish();
+        let _logging = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new().route(
+            "/token",
+            post(|| async {
+                Json(serde_json::json!({
+                    "access_token": "secret-access-token",
+                    "refresh_token": "secret-refresh-token",
+                    "expires_in": 3600,
+                }))
+            }),
+        );
+        let addr = spawn_server(app).await;
+
+        let state = app_state();
+        let (state_param, status_token) =
+            start_flow_with_return_url(&state, Some("https://app.example.com/done")).await;
+        let response =
+            oauth_callback(callback_query(&state_param, "secret-code", addr), State(state.clone())).await;
+        let location = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        let retrieval_code = location
+            .query_pairs()
+            .find(|(name, _)| name == "retrieval_code")
+            .unwrap()
+            .1
+            .into_owned();
+        let response = redeem(
+            State(state.clone()),
+            Json(RedeemRequest {
+                retrieval_code: retrieval_code.as_str().into(),
+            }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = introspect(
+            State(state.clone()),
+            Json(IntrospectRequest {
+                token: "secret-access-token".into(),
+                tenant_url: None,
+            }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut config = AppConfig::default();
+        config.webhook.secret = Some("secret-webhook-key".into());
+        info!("配置加载完成: {:?}", config);
+
+        let output = logs.contents();
+        assert!(output.contains("OAuth授权完成成功"), "{}", output);
+        for secret in [
+            "secret-access-token",
+            "secret-refresh-token",
+            "secret-code",
+            "secret-webhook-key",
+            state_param.as_str(),
+            status_token.as_str(),
+            retrieval_code.as_str(),
+        ] {
+            assert!(!output.contains(secret), "日志中出现了敏感值 {}:\n{}", secret, output);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_forwarded_to_token_endpoint() {
+        use axum::{body::Body, extract::Request, routing::post, Router};
+        use tower::Service;
+
+        let seen = Arc::new(std::sync::Mutex::new(None));
+        let recorder = seen.clone();
+        let tenant = Router::new().route(
+            "/token",
+            post(move |headers: HeaderMap| async move {
+                *recorder.lock().unwrap() = headers
+                    .get("x-request-id")
+                    .map(|value| value.to_str().unwrap().to_string());
+                Json(serde_json::json!({ "access_token": "access-1", "expires_in": 3600 }))
+            }),
+        );
+        let addr = spawn_server(tenant).await;
+
+        let state = app_state();
+        let (state_param, _) = start_flow(&state).await;
+        let mut app = Router::new()
+            .route("/api/complete-auth", post(complete_auth))
+            .layer(axum::middleware::from_fn(request_id::propagate))
+            .with_state(state);
+
+        let body = serde_json::json!({
+            "code": "code-1",
+            "state": state_param,
+            "tenant_url": format!("http://{}/", addr),
+        });
+        let request = Request::post("/api/complete-auth")
+            .header("content-type", "application/json")
+            .header("x-request-id", "req-flow-1")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-request-id"], "req-flow-1");
+        assert_eq!(seen.lock().unwrap().as_deref(), Some("req-flow-1"));
+    }
 }
@@ -0,0 +1,266 @@
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::models::{IntrospectionData, TokenRecord};
+use crate::oauth::OAuthService;
+use crate::secret::hex_digest;
+use crate::token_store::TokenStore;
+
+/// 缓存的自省结果
+struct CachedIntrospection {
+    data: IntrospectionData,
+    cached_at: DateTime<Utc>,
+}
+
+/// 令牌自省服务
+///
+/// 授权服务器提供自省端点时代理查询，否则根据本地token记录回答；结果短时间缓存。
+/// 只代理到令牌记录所属的tenant或受信任的tenant，调用方指定的其他tenant不会收到令牌。
+pub struct IntrospectionService {
+    oauth_service: Arc<OAuthService>,
+    token_store: Arc<TokenStore>,
+    cache_ttl: Duration,
+    /// 自省结果缓存 ((tenant_url, sha256(token)) -> 结果)
+    cache: DashMap<(Option<String>, String), CachedIntrospection>,
+}
+
+impl IntrospectionService {
+    pub fn new(
+        oauth_service: Arc<OAuthService>,
+        token_store: Arc<TokenStore>,
+        cache_seconds: u64,
+    ) -> Self {
+        Self {
+            oauth_service,
+            token_store,
+            cache_ttl: Duration::seconds(cache_seconds as i64),
+            cache: DashMap::new(),
+        }
+    }
+
+    /// 查询令牌状态
+    pub async fn introspect(&self, token: &str, tenant_url: Option<&str>) -> IntrospectionData {
+        let record = self.token_store.find_by_access_token(token);
+        let tenant_url = match &record {
+            Some(record) => Some(record.tenant_url.clone()),
+            None => tenant_url
+                .filter(|tenant_url| self.oauth_service.is_trusted_tenant(tenant_url))
+                .map(str::to_string),
+        };
+
+        let cache_key = (tenant_url.clone(), hex_digest(token));
+        if let Some(cached) = self.cache.get(&cache_key) {
+            if Utc::now() - cached.cached_at < self.cache_ttl {
+                // 缓存期间被吊销的令牌立即视为无效
+                let mut data = cached.data.clone();
+                if record.as_ref().is_some_and(|record| record.revoked) {
+                    data.active = false;
+                    data.revoked = true;
+                }
+                return data;
+            }
+        }
+
+        let mut data = None;
+        if let Some(tenant_url) = &tenant_url {
+            match self.oauth_service.introspect_token(tenant_url, token).await {
+                Ok(Some(response)) => {
+                    data = Some(from_provider(response, record.as_ref(), tenant_url));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("授权服务器自省失败，使用本地记录回答: {}", e),
+            }
+        }
+        let data = data.unwrap_or_else(|| from_local(record.as_ref()));
+
+        self.cache.insert(
+            cache_key,
+            CachedIntrospection {
+                data: data.clone(),
+                cached_at: Utc::now(),
+            },
+        );
+
+        data
+    }
+
+    /// 清理过期的缓存结果
+    pub fn prune(&self) {
+        let now = Utc::now();
+        self.cache.retain(|_, cached| now - cached.cached_at < self.cache_ttl);
+    }
+}
+
+/// 根据授权服务器的自省结果生成回答，本地已吊销的令牌始终视为无效
+fn from_provider(
+    response: serde_json::Value,
+    record: Option<&TokenRecord>,
+    tenant_url: &str,
+) -> IntrospectionData {
+    let revoked = record.is_some_and(|record| record.revoked);
+
+    IntrospectionData {
+        active: response["active"].as_bool().unwrap_or(false) && !revoked,
+        source: "provider".to_string(),
+        exp: response["exp"]
+            .as_i64()
+            .or_else(|| record.and_then(|record| record.expires_at).map(|t| t.timestamp())),
+        user_id: record.and_then(|record| record.user_id.clone()),
+        tenant_url: Some(tenant_url.to_string()),
+        revoked,
+        provider_response: Some(response),
+    }
+}
+
+/// 根据本地token记录生成回答，未知令牌视为无效
+fn from_local(record: Option<&TokenRecord>) -> IntrospectionData {
+    IntrospectionData {
+        active: record.is_some_and(TokenRecord::is_active),
+        source: "local".to_string(),
+        exp: record.and_then(|record| record.expires_at).map(|t| t.timestamp()),
+        user_id: record.and_then(|record| record.user_id.clone()),
+        tenant_url: record.map(|record| record.tenant_url.clone()),
+        revoked: record.is_some_and(|record| record.revoked),
+        provider_response: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::test_support::spawn_server;
+    use crate::token_store::tests::record;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 启动授权服务器，`with_introspection` 决定元数据是否声明自省端点
+    async fn spawn_provider(with_introspection: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        use axum::{http::{header, HeaderMap}, routing::{get, post}, Json, Router};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        let app = Router::new()
+            .route(
+                "/.well-known/oauth-authorization-server",
+                get(move |headers: HeaderMap| async move {
+                    if !with_introspection {
+                        return Json(serde_json::json!({}));
+                    }
+                    let host = headers[header::HOST].to_str().unwrap().to_string();
+                    Json(serde_json::json!({ "introspection_endpoint": format!("http://{}/introspect", host) }))
+                }),
+            )
+            .route(
+                "/introspect",
+                post(move || async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Json(serde_json::json!({ "active": true, "exp": 4102444800i64, "sub": "provider-sub" }))
+                }),
+            );
+
+        (spawn_server(app).await, calls)
+    }
+
+    fn service(cache_seconds: u64, trusted_tenant: Option<&str>) -> (IntrospectionService, Arc<TokenStore>) {
+        let mut config = AppConfig::default().oauth;
+        config.trusted_tenants = trusted_tenant.map(str::to_string).into_iter().collect();
+        let oauth_service = Arc::new(OAuthService::new(config).unwrap());
+        let token_store = Arc::new(TokenStore::new());
+        (
+            IntrospectionService::new(oauth_service, token_store.clone(), cache_seconds),
+            token_store,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_answers_from_local_vault_without_provider_endpoint() {
+        let (addr, _) = spawn_provider(false).await;
+        let (service, token_store) = service(30, None);
+        let tenant_url = format!("http://{}/", addr);
+        token_store.insert(record(&tenant_url, "local-token"));
+
+        let data = service.introspect("local-token", None).await;
+        assert!(data.active);
+        assert_eq!(data.source, "local");
+        assert_eq!(data.user_id.as_deref(), Some("user-1"));
+        assert_eq!(data.tenant_url.as_deref(), Some(tenant_url.as_str()));
+        assert!(!data.revoked);
+
+        let unknown = service.introspect("unknown-token", None).await;
+        assert!(!unknown.active);
+    }
+
+    #[tokio::test]
+    async fn test_local_answer_for_revoked_and_expired_tokens() {
+        let (addr, _) = spawn_provider(false).await;
+        let (service, token_store) = service(30, None);
+        let tenant_url = format!("http://{}/", addr);
+
+        let mut revoked = record(&tenant_url, "revoked-token");
+        revoked.revoked = true;
+        token_store.insert(revoked);
+        let mut expired = record(&tenant_url, "expired-token");
+        expired.expires_at = Some(Utc::now() - Duration::minutes(1));
+        token_store.insert(expired);
+
+        let data = service.introspect("revoked-token", None).await;
+        assert!(!data.active);
+        assert!(data.revoked);
+        assert!(!service.introspect("expired-token", None).await.active);
+    }
+
+    #[tokio::test]
+    async fn test_proxies_to_provider_and_caches_result() {
+        let (addr, calls) = spawn_provider(true).await;
+        let tenant_url = format!("http://{}", addr);
+        let (service, _) = service(30, Some(&tenant_url));
+
+        let data = service.introspect("remote-token", Some(&tenant_url)).await;
+        assert!(data.active);
+        assert_eq!(data.source, "provider");
+        assert_eq!(data.exp, Some(4102444800));
+        assert_eq!(data.provider_response.unwrap()["sub"], "provider-sub");
+
+        service.introspect("remote-token", Some(&tenant_url)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_expires_after_ttl() {
+        let (addr, calls) = spawn_provider(true).await;
+        let tenant_url = format!("http://{}", addr);
+        let (service, _) = service(0, Some(&tenant_url));
+
+        service.introspect("remote-token", Some(&tenant_url)).await;
+        service.introspect("remote-token", Some(&tenant_url)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        service.prune();
+        assert!(service.cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_tenant_is_not_proxied_or_shared() {
+        let (trusted_addr, trusted_calls) = spawn_provider(true).await;
+        let (other_addr, other_calls) = spawn_provider(true).await;
+        let trusted_url = format!("http://{}", trusted_addr);
+        let (service, _) = service(30, Some(&trusted_url));
+
+        // 调用方指定的不受信任tenant不会收到令牌
+        let other_url = format!("http://{}", other_addr);
+        let data = service.introspect("remote-token", Some(&other_url)).await;
+        assert!(!data.active);
+        assert_eq!(data.source, "local");
+        assert_eq!(other_calls.load(Ordering::SeqCst), 0);
+
+        // 受信任tenant的结果不会被其他tenant的查询复用
+        assert!(service.introspect("remote-token", Some(&trusted_url)).await.active);
+        assert!(!service.introspect("remote-token", Some(&other_url)).await.active);
+        assert!(!service.introspect("remote-token", None).await.active);
+        assert_eq!(trusted_calls.load(Ordering::SeqCst), 1);
+    }
+}
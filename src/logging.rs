@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use opentelemetry_sdk::trace::TracerProvider;
+use std::path::Path;
+use tracing::subscriber::DefaultGuard;
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    fmt::{self, MakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::config::{LogFormat, LogRotation, ServerConfig};
+use crate::telemetry;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// 加载配置期间使用的临时日志
+///
+/// 正式的日志配置来自 [`ServerConfig`]，在此之前按 `RUST_LOG`（默认 `info`）输出到标准错误，
+/// 保证配置加载过程中的日志不会丢失。返回值被丢弃后临时日志失效。
+pub fn bootstrap() -> DefaultGuard {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(std::io::stderr).with_filter(filter))
+        .set_default()
+}
+
+/// 按配置初始化全局日志
+///
+/// 启用链路导出时span同时导出到OTLP收集器。
+/// 配置了日志文件时返回后台写入任务的句柄，需要保持到进程退出，否则缓冲中的日志会丢失。
+pub fn init(config: &ServerConfig, tracer_provider: Option<&TracerProvider>) -> Result<Option<WorkerGuard>> {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .map_err(|e| anyhow!("无效的日志过滤指令 '{}': {}", config.log_level, e))?;
+
+    let mut layers = vec![format_layer(config.log_format, std::io::stdout, true)];
+    let guard = match &config.log_file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(file_appender(path, config.log_rotation)?);
+            layers.push(format_layer(config.log_format, writer, false));
+            Some(guard)
+        }
+        None => None,
+    };
+    if let Some(provider) = tracer_provider {
+        layers.push(telemetry::layer(provider).boxed());
+    }
+
+    // `log` 记录的转发已在 bootstrap 中安装，这里只设置全局subscriber
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layers).with(filter))
+        .map_err(|e| anyhow!("日志初始化失败: {}", e))?;
+    Ok(guard)
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// 日志文件写入器，轮转后的文件名追加日期（和小时）后缀
+fn file_appender(path: &str, rotation: LogRotation) -> Result<rolling::RollingFileAppender> {
+    let path = Path::new(path);
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("无效的日志文件路径 '{}'", path.display()))?;
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let rotation = match rotation {
+        LogRotation::Hourly => rolling::Rotation::HOURLY,
+        LogRotation::Daily => rolling::Rotation::DAILY,
+        LogRotation::Never => rolling::Rotation::NEVER,
+    };
+
+    rolling::RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name.to_string_lossy())
+        .build(directory)
+        .map_err(|e| anyhow!("无法创建日志文件 '{}': {}", path.display(), e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// 收集日志输出，供测试检查
+    #[derive(Clone, Default)]
+    pub(crate) struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl CapturedLogs {
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'w> MakeWriter<'w> for CapturedLogs {
+        type Writer = Self;
+
+        fn make_writer(&'w self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_json_logs_include_span_fields() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(vec![format_layer(LogFormat::Json, logs.clone(), false)])
+            .with(EnvFilter::new("info"));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", request_id = "req-1");
+            let _entered = span.enter();
+            tracing::info!(user_id = "u1", "授权完成");
+            tracing::debug!("被过滤的日志");
+        });
+
+        let output = logs.contents();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", output);
+
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "授权完成");
+        assert_eq!(line["fields"]["user_id"], "u1");
+        assert_eq!(line["span"]["request_id"], "req-1");
+        assert_eq!(line["spans"][0]["name"], "request");
+    }
+
+    #[test]
+    fn test_init_after_bootstrap() {
+        // 与main中的顺序一致：临时日志期间加载配置，之后按配置初始化
+        let bootstrap_logging = bootstrap();
+        let mut config = crate::config::AppConfig::default().server;
+        config.log_level = "off".to_string();
+        drop(bootstrap_logging);
+
+        assert!(init(&config, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_filter_is_rejected() {
+        let mut config = crate::config::AppConfig::default().server;
+        config.log_level = "augment_oauth_service=loud".to_string();
+        assert!(init(&config, None).is_err());
+    }
+
+    #[test]
+    fn test_file_appender_writes_to_configured_path() {
+        let dir = std::env::temp_dir().join(format!("oauth-logs-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("service.log");
+
+        let mut appender = file_appender(path.to_str().unwrap(), LogRotation::Never).unwrap();
+        std::io::Write::write_all(&mut appender, b"hello\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello\n");
+
+        assert!(file_appender("/", LogRotation::Daily).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,40 +1,97 @@
 use axum::{
+    extract::State,
     response::Json,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use std::sync::Arc;
-use tower_http::cors::CorsLayer;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+mod auth;
+mod completion;
 mod config;
+mod cors;
+mod dpop;
+mod flow;
 mod handlers;
+mod introspection;
+mod logging;
+mod metrics;
 mod middleware;
 mod models;
 mod oauth;
+mod oidc;
+mod rate_limit;
+mod refresher;
+mod request_id;
+mod retrieval;
+mod secret;
+mod shutdown;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod token_store;
+mod webhook;
 
-use config::{get_available_server_addr, AppConfig};
+use auth::Authenticator;
+use config::{get_available_server_addr, AppConfig, AuthMode};
+use completion::CompletionRegistry;
+use cors::CorsPolicies;
+use flow::FlowTracker;
+use introspection::IntrospectionService;
 use models::ApiResponse;
 use oauth::OAuthService;
+use rate_limit::{MemoryRateLimitStore, RateLimiter};
+use refresher::TokenRefresher;
+use retrieval::RetrievalCodes;
+use shutdown::Shutdown;
+use token_store::TokenStore;
+use webhook::{WebhookDispatcher, WebhookEventType};
 
 #[derive(Clone)]
 pub struct AppState {
     oauth_service: Arc<OAuthService>,
+    token_store: Arc<TokenStore>,
+    introspection: Arc<IntrospectionService>,
+    webhooks: Arc<WebhookDispatcher>,
+    flows: Arc<FlowTracker>,
+    completions: Arc<CompletionRegistry>,
+    retrievals: Arc<RetrievalCodes>,
+    rate_limiter: Arc<RateLimiter>,
+    authenticator: Arc<Authenticator>,
+    shutdown: Shutdown,
 }
 
 #[tokio::main]
 async fn main() {
-    // 加载配置
+    // 加载配置，期间使用临时日志
+    let bootstrap_logging = logging::bootstrap();
     let mut config = match AppConfig::load() {
         Ok(config) => config,
         Err(e) => {
-            eprintln!("配置加载失败: {}", e);
+            error!("配置加载失败: {}", e);
             std::process::exit(1);
         }
     };
+    // 链路导出，退出前导出剩余的span
+    let tracer_provider = match telemetry::init(&config.telemetry) {
+        Ok(provider) => provider,
+        Err(e) => {
+            error!("链路导出初始化失败: {}", e);
+            std::process::exit(1);
+        }
+    };
+    drop(bootstrap_logging);
 
-    // 初始化日志
-    tracing_subscriber::fmt::init();
+    // 按配置初始化日志，文件日志的后台写入句柄需要保持到进程退出
+    let _log_guard = match logging::init(&config.server, tracer_provider.as_ref()) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     info!("Augment OAuth Service 正在启动...");
     info!(
@@ -52,18 +109,201 @@ async fn main() {
     };
 
     // 创建OAuth服务
-    let oauth_service = Arc::new(OAuthService::new());
+    let oauth_service = match OAuthService::new(config.oauth.clone()) {
+        Ok(service) => Arc::new(service),
+        Err(e) => {
+            error!("OAuth服务初始化失败: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // 创建应用状态
-    let app_state = AppState { oauth_service };
+    // 创建webhook投递器
+    let webhooks = match WebhookDispatcher::new(config.webhook.clone()) {
+        Ok(dispatcher) => Arc::new(dispatcher),
+        Err(e) => {
+            error!("Webhook初始化失败: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // 停止通知，收到SIGTERM或SIGINT时触发
+    let shutdown = Shutdown::new();
+    let mut background = vec![webhooks.clone().spawn(shutdown.clone())];
 
-    // 创建路由
-    let app = Router::new()
-        .route("/api/auth-url", get(handlers::get_auth_url))
+    // 授权流程状态跟踪
+    let flows = Arc::new(FlowTracker::new());
+    // 完成授权请求去重
+    let completions = Arc::new(CompletionRegistry::new(config.oauth.completion_replay_seconds));
+    // 浏览器回调的一次性取回码
+    let retrievals = Arc::new(RetrievalCodes::new(
+        config.oauth.retrieval_code_ttl_seconds,
+        config.oauth.completion_replay_seconds,
+    ));
+
+    // 请求限流
+    let rate_limiter = match RateLimiter::new(
+        config.rate_limit.clone(),
+        Arc::new(MemoryRateLimitStore::default()),
+    ) {
+        Ok(limiter) => Arc::new(limiter),
+        Err(e) => {
+            error!("限流配置无效: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // 调用方认证
+    let authenticator = match Authenticator::new(
+        &config.auth,
+        config.oauth.jwks_cache_seconds,
+        config.oauth.id_token_clock_skew_seconds,
+    ) {
+        Ok(authenticator) => Arc::new(authenticator),
+        Err(e) => {
+            error!("认证配置无效: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if authenticator.mode() == AuthMode::None {
+        warn!("未启用调用方认证，/api 路由对所有能访问端口的客户端开放");
+    }
+
+    // 创建token存储
+    let token_store = match &config.storage.token_store_path {
+        Some(path) => match TokenStore::open(path) {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                error!("无法打开token存储 {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => Arc::new(TokenStore::new()),
+    };
+
+    // 启动令牌后台刷新
+    if config.refresh.enabled {
+        background.push(
+            Arc::new(TokenRefresher::new(
+                oauth_service.clone(),
+                token_store.clone(),
+                webhooks.clone(),
+                config.refresh.clone(),
+            ))
+            .spawn(shutdown.clone()),
+        );
+    }
+
+    let introspection = Arc::new(IntrospectionService::new(
+        oauth_service.clone(),
+        token_store.clone(),
+        config.oauth.introspection_cache_seconds,
+    ));
+
+    // 定期清理过期的OAuth状态
+    let reaper_service = oauth_service.clone();
+    let reaper_webhooks = webhooks.clone();
+    let reaper_flows = flows.clone();
+    let reaper_completions = completions.clone();
+    let reaper_retrievals = retrievals.clone();
+    let reaper_introspection = introspection.clone();
+    let reaper_rate_limiter = rate_limiter.clone();
+    let flow_retention = chrono::Duration::minutes(config.oauth.flow_retention_minutes as i64);
+    let reaper_shutdown = shutdown.clone();
+    background.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = reaper_shutdown.wait() => break,
+            }
+            let expired_states = reaper_service.cleanup_expired_states();
+            metrics::reaper_sweep(expired_states.len());
+            for expired in expired_states {
+                reaper_flows.mark_expired(expired.state.as_str());
+                reaper_webhooks.emit(
+                    WebhookEventType::StateExpired,
+                    serde_json::json!({
+                        "state": expired.state,
+                        "user_id": expired.user_id,
+                        "created_at": expired.creation_time,
+                        "metadata": expired.metadata,
+                    }),
+                );
+            }
+            reaper_flows.prune(flow_retention);
+            reaper_completions.prune();
+            reaper_retrievals.prune();
+            reaper_introspection.prune();
+            reaper_rate_limiter.prune();
+        }
+    }));
+
+    let app_state = AppState {
+        oauth_service,
+        token_store,
+        introspection,
+        webhooks,
+        flows,
+        completions,
+        retrievals,
+        rate_limiter,
+        authenticator,
+        shutdown: shutdown.clone(),
+    };
+    // 停止前写盘
+    let stores = (app_state.token_store.clone(), app_state.webhooks.clone());
+
+    // 跨域策略
+    let cors = match CorsPolicies::new(&config.cors) {
+        Ok(cors) => cors,
+        Err(e) => {
+            error!("跨域配置无效: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // 创建路由：每个路由组使用自己的跨域策略，CORS在最外层以便预检请求和错误响应都带上跨域头
+    let guard = |routes: Router<AppState>, group: &str| {
+        routes
+            // 后添加的层先执行：先认证调用方，再按认证结果限流
+            .layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                rate_limit::enforce,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::authenticate,
+            ))
+            .layer(cors.layer(group))
+    };
+    let auth_routes = Router::new()
+        .route(
+            "/api/auth-url",
+            get(handlers::get_auth_url).post(handlers::post_auth_url),
+        )
         .route("/api/complete-auth", post(handlers::complete_auth))
-        .route("/health", get(health_check))
-        .layer(CorsLayer::permissive())
-        .with_state(app_state);
+        .route("/api/oauth/callback", get(handlers::oauth_callback))
+        .route("/api/redeem", post(handlers::redeem))
+        .route("/api/auth-status/:state", get(handlers::get_auth_status))
+        .route("/api/auth-events/:state", get(handlers::auth_events))
+        .route("/api/auth/:state", delete(handlers::cancel_auth))
+        .route("/api/auth/:state/renew", post(handlers::renew_auth));
+    let token_routes = Router::new()
+        .route("/api/introspect", post(handlers::introspect))
+        .route("/api/tokens/refresh-status", get(handlers::refresh_status));
+    let app = Router::new()
+        .merge(guard(auth_routes, "auth"))
+        .merge(guard(token_routes, "tokens"))
+        .route("/health", get(health_check).layer(cors.layer("default")));
+    #[cfg(feature = "metrics")]
+    let app = metrics::instrument(app, &config.metrics, &app_state);
+    #[cfg(not(feature = "metrics"))]
+    if config.metrics.admin_port.is_some() {
+        warn!("未启用 metrics 编译特性，忽略 METRICS_ADMIN_PORT");
+    }
+    let app = app
+        // 最外层分配请求ID，认证、限流等中间件的日志和错误响应也带上请求ID
+        .layer(axum::middleware::from_fn(request_id::propagate))
+        .with_state(app_state.clone());
 
     // 启动服务器
     let listener = match tokio::net::TcpListener::bind(server_addr).await {
@@ -74,22 +314,147 @@ async fn main() {
         }
     };
 
+    // 管理端口和HTTP重定向等附属监听，停止时与主服务一起等待
+    let mut listeners = Vec::new();
+
+    // 管理端口只提供指标，始终使用HTTP，不要对外暴露
+    #[cfg(feature = "metrics")]
+    if let (true, Some(admin_port)) = (config.metrics.enabled, config.metrics.admin_port) {
+        let admin_addr = format!("{}:{}", config.server.host, admin_port);
+        match tokio::net::TcpListener::bind(&admin_addr).await {
+            Ok(admin_listener) => {
+                info!("指标端点: http://{}/metrics", admin_addr);
+                let admin = Router::new()
+                    .route("/metrics", get(metrics::render))
+                    .with_state(app_state.clone());
+                let serve = axum::serve(admin_listener, admin).with_graceful_shutdown(shutdown.wait());
+                listeners.push(tokio::spawn(async move {
+                    if let Err(e) = serve.await {
+                        error!("管理端口运行错误: {}", e);
+                    }
+                }));
+            }
+            Err(e) => {
+                error!("无法绑定到管理地址 {}: {}", admin_addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // 同时配置证书和私钥时启用HTTPS
+    let tls = match (&config.server.tls_cert_path, &config.server.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => match tls::TlsReloader::new(cert_path, key_path) {
+            Ok(reloader) => {
+                let reloader = Arc::new(reloader);
+                reloader.spawn_watcher(std::time::Duration::from_secs(
+                    config.server.tls_reload_interval_seconds,
+                ));
+                Some(reloader)
+            }
+            Err(e) => {
+                error!("TLS证书加载失败: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("TLS_CERT_PATH 和 TLS_KEY_PATH 必须同时配置");
+            std::process::exit(1);
+        }
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
     info!("Augment OAuth Service 启动成功！");
-    info!("服务地址: http://{}", server_addr);
-    info!("健康检查: http://{}/health", server_addr);
-    info!("获取授权链接: http://{}/api/auth-url", server_addr);
-    info!("完成授权: http://{}/api/complete-auth", server_addr);
-
-    if let Err(e) = axum::serve(listener, app).await {
-        error!("服务器运行错误: {}", e);
-        std::process::exit(1);
+    info!("服务地址: {}://{}", scheme, server_addr);
+    info!("健康检查: {}://{}/health", scheme, server_addr);
+    info!("获取授权链接: {}://{}/api/auth-url", scheme, server_addr);
+    info!("完成授权: {}://{}/api/complete-auth", scheme, server_addr);
+    info!("令牌自省: {}://{}/api/introspect", scheme, server_addr);
+
+    let mut server = match tls {
+        Some(reloader) => {
+            if let Some(redirect_port) = config.server.http_redirect_port {
+                let redirect_addr = format!("{}:{}", config.server.host, redirect_port);
+                match tokio::net::TcpListener::bind(&redirect_addr).await {
+                    Ok(redirect_listener) => {
+                        info!("HTTP重定向: http://{} -> https", redirect_addr);
+                        let https_port = server_addr.port();
+                        let redirect_shutdown = shutdown.clone();
+                        listeners.push(tokio::spawn(async move {
+                            if let Err(e) =
+                                tls::serve_redirect(redirect_listener, https_port, redirect_shutdown).await
+                            {
+                                error!("HTTP重定向服务运行错误: {}", e);
+                            }
+                        }));
+                    }
+                    Err(e) => {
+                        error!("无法绑定到重定向地址 {}: {}", redirect_addr, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            tokio::spawn(tls::serve_tls(listener, app, reloader, shutdown.clone()))
+        }
+        None => {
+            if config.server.http_redirect_port.is_some() {
+                warn!("未启用HTTPS，忽略 HTTP_REDIRECT_PORT");
+            }
+            // 限流需要客户端的连接地址
+            let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+            let serve = axum::serve(listener, app).with_graceful_shutdown(shutdown.wait());
+            tokio::spawn(async move { serve.await.map_err(anyhow::Error::from) })
+        }
+    };
+
+    tokio::select! {
+        result = &mut server => {
+            match result {
+                Ok(Err(e)) => error!("服务器运行错误: {}", e),
+                Err(e) => error!("服务器运行错误: {}", e),
+                Ok(Ok(())) => error!("服务器意外停止"),
+            }
+            std::process::exit(1);
+        }
+        _ = shutdown::signal() => {}
+    }
+
+    // 停止接受新连接，等待进行中的请求和后台任务结束
+    let timeout = std::time::Duration::from_secs(config.server.shutdown_timeout_seconds);
+    info!("收到停止信号，最多等待 {} 秒处理完进行中的请求", timeout.as_secs());
+    shutdown.trigger();
+    let drained = tokio::time::timeout(timeout, async {
+        let _ = server.await;
+        for listener in listeners {
+            let _ = listener.await;
+        }
+        for task in background {
+            let _ = task.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!("等待超时，仍有未完成的请求或后台任务，强制停止");
+    }
+
+    let (token_store, webhooks) = stores;
+    token_store.flush();
+    webhooks.flush();
+    info!("Augment OAuth Service 已停止");
+    if let Some(provider) = tracer_provider {
+        telemetry::shutdown(provider).await;
     }
 }
 
-async fn health_check() -> Json<ApiResponse<serde_json::Value>> {
+async fn health_check(State(state): State<AppState>) -> Json<ApiResponse<serde_json::Value>> {
     let data = serde_json::json!({
         "status": "ok",
         "service": "augment-oauth-service",
+        "active_states": state.oauth_service.active_states_count(),
+        "state_capacity": state.oauth_service.state_capacity(),
+        "flows": state.flows.counts(),
+        "stored_tokens": state.token_store.len(),
+        "pending_webhooks": state.webhooks.pending_count(),
         "timestamp": chrono::Utc::now()
     });
 
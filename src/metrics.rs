@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+pub use exporter::{instrument, render};
+
+/// 已知的OAuth错误码 (RFC 6749 5.2)，其他取值统一记为 `token_endpoint_error`，避免标签数量失控
+const OAUTH_ERROR_CODES: &[&str] = &[
+    "invalid_request",
+    "invalid_client",
+    "invalid_grant",
+    "unauthorized_client",
+    "unsupported_grant_type",
+    "invalid_scope",
+    "invalid_dpop_proof",
+];
+
+/// 记录一次生成的授权链接
+pub fn auth_url_generated() {
+    #[cfg(feature = "metrics")]
+    exporter::METRICS.auth_urls_generated.inc();
+}
+
+/// 记录一次完成授权请求的结果
+///
+/// `outcome` 为 `success`、`failed`、`merged`、`replayed`、`conflict` 之一，失败时 `error_code` 说明原因，其余为空。
+pub fn completion(outcome: &str, error_code: &str) {
+    #[cfg(feature = "metrics")]
+    exporter::METRICS
+        .completions
+        .with_label_values(&[outcome, error_code])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (outcome, error_code);
+}
+
+/// 令牌交换失败的错误码：token端点返回的标准OAuth错误码，或者按失败阶段归类
+pub fn exchange_error_code(error: &anyhow::Error) -> &'static str {
+    match error.downcast_ref::<crate::oauth::TokenEndpointError>() {
+        Some(endpoint_error) => endpoint_error
+            .error
+            .as_deref()
+            .and_then(|code| OAUTH_ERROR_CODES.iter().find(|known| **known == code))
+            .copied()
+            .unwrap_or("token_endpoint_error"),
+        None => "exchange_error",
+    }
+}
+
+/// 记录一次对租户token端点的请求耗时，`host` 为受信任租户的主机名或 `other`
+pub fn upstream_token_request(host: &str, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    exporter::METRICS
+        .upstream_token_duration
+        .with_label_values(&[host])
+        .observe(elapsed.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = (host, elapsed);
+}
+
+/// 记录一次过期状态清理
+pub fn reaper_sweep(expired_states: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        exporter::METRICS.reaper_sweeps.inc();
+        exporter::METRICS.expired_states.inc_by(expired_states as u64);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = expired_states;
+}
+
+#[cfg(feature = "metrics")]
+mod exporter {
+    use axum::{
+        extract::{MatchedPath, Request, State},
+        http::header,
+        middleware::Next,
+        response::{IntoResponse, Response},
+        routing::get,
+        Router,
+    };
+    use prometheus::{
+        Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+        Registry, TextEncoder,
+    };
+    use std::{sync::LazyLock, time::Instant};
+
+    use crate::{config::MetricsConfig, middleware::internal_server_error, oauth::StateCapacity, AppState};
+
+    pub(super) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+    pub(super) struct Metrics {
+        registry: Registry,
+        http_requests: IntCounterVec,
+        http_request_duration: HistogramVec,
+        pub(super) auth_urls_generated: IntCounter,
+        pub(super) completions: IntCounterVec,
+        pub(super) upstream_token_duration: HistogramVec,
+        active_states: IntGauge,
+        max_pending_states: IntGauge,
+        max_pending_states_per_user: IntGauge,
+        state_utilization: Gauge,
+        states_evicted: IntCounter,
+        states_rejected: IntCounter,
+        pub(super) reaper_sweeps: IntCounter,
+        pub(super) expired_states: IntCounter,
+    }
+
+    impl Metrics {
+        fn new() -> Self {
+            let registry = Registry::new();
+            let http_requests = IntCounterVec::new(
+                Opts::new("http_requests_total", "按路由和状态码统计的请求数"),
+                &["method", "route", "status"],
+            )
+            .unwrap();
+            let http_request_duration = HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "按路由和状态码统计的请求耗时"),
+                &["method", "route", "status"],
+            )
+            .unwrap();
+            let auth_urls_generated = IntCounter::new(
+                "oauth_auth_urls_generated_total",
+                "生成的授权链接数",
+            )
+            .unwrap();
+            let completions = IntCounterVec::new(
+                Opts::new("oauth_completions_total", "按结果和错误码统计的完成授权请求数"),
+                &["outcome", "error_code"],
+            )
+            .unwrap();
+            let upstream_token_duration = HistogramVec::new(
+                HistogramOpts::new(
+                    "oauth_upstream_token_request_duration_seconds",
+                    "按租户主机统计的token端点请求耗时",
+                ),
+                &["host"],
+            )
+            .unwrap();
+            let active_states = IntGauge::new("oauth_active_states", "等待授权的OAuth状态数").unwrap();
+            let max_pending_states =
+                IntGauge::new("oauth_max_pending_states", "等待授权的OAuth状态上限，0表示不限制").unwrap();
+            let max_pending_states_per_user = IntGauge::new(
+                "oauth_max_pending_states_per_user",
+                "单用户等待授权的OAuth状态上限，0表示不限制",
+            )
+            .unwrap();
+            let state_utilization =
+                Gauge::new("oauth_state_utilization", "等待授权的OAuth状态占全局上限的比例").unwrap();
+            let states_evicted =
+                IntCounter::new("oauth_states_evicted_total", "因容量被淘汰的OAuth状态数").unwrap();
+            let states_rejected =
+                IntCounter::new("oauth_states_rejected_total", "因容量被拒绝的授权请求数").unwrap();
+            let reaper_sweeps = IntCounter::new("oauth_reaper_sweeps_total", "过期状态清理次数").unwrap();
+            let expired_states =
+                IntCounter::new("oauth_states_expired_total", "清理掉的过期OAuth状态数").unwrap();
+
+            registry.register(Box::new(http_requests.clone())).unwrap();
+            registry.register(Box::new(http_request_duration.clone())).unwrap();
+            registry.register(Box::new(auth_urls_generated.clone())).unwrap();
+            registry.register(Box::new(completions.clone())).unwrap();
+            registry.register(Box::new(upstream_token_duration.clone())).unwrap();
+            registry.register(Box::new(active_states.clone())).unwrap();
+            registry.register(Box::new(max_pending_states.clone())).unwrap();
+            registry.register(Box::new(max_pending_states_per_user.clone())).unwrap();
+            registry.register(Box::new(state_utilization.clone())).unwrap();
+            registry.register(Box::new(states_evicted.clone())).unwrap();
+            registry.register(Box::new(states_rejected.clone())).unwrap();
+            registry.register(Box::new(reaper_sweeps.clone())).unwrap();
+            registry.register(Box::new(expired_states.clone())).unwrap();
+            // 进程指标（CPU、内存、文件描述符等）
+            #[cfg(target_os = "linux")]
+            registry
+                .register(Box::new(prometheus::process_collector::ProcessCollector::for_self()))
+                .unwrap();
+
+            Self {
+                registry,
+                http_requests,
+                http_request_duration,
+                auth_urls_generated,
+                completions,
+                upstream_token_duration,
+                active_states,
+                max_pending_states,
+                max_pending_states_per_user,
+                state_utilization,
+                states_evicted,
+                states_rejected,
+                reaper_sweeps,
+                expired_states,
+            }
+        }
+
+        /// 以Prometheus文本格式输出全部指标
+        fn encode(&self, capacity: &StateCapacity) -> Result<String, prometheus::Error> {
+            self.active_states.set(capacity.pending as i64);
+            self.max_pending_states.set(capacity.max_pending as i64);
+            self.max_pending_states_per_user.set(capacity.max_pending_per_user as i64);
+            self.state_utilization.set(capacity.utilization);
+            // 淘汰和拒绝次数由OAuth服务累计，导出时把计数器补齐到累计值
+            sync_counter(&self.states_evicted, capacity.evicted_total);
+            sync_counter(&self.states_rejected, capacity.rejected_total);
+            let mut buffer = Vec::new();
+            TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+            Ok(String::from_utf8_lossy(&buffer).into_owned())
+        }
+    }
+
+    fn sync_counter(counter: &IntCounter, total: u64) {
+        counter.inc_by(total.saturating_sub(counter.get()));
+    }
+
+    /// 统计请求数和耗时
+    ///
+    /// 路由标签使用匹配到的路由模板（如 `/api/auth-status/:state`），不包含state等路径参数；未匹配的请求记为 `unmatched`。
+    async fn track(request: Request, next: Next) -> Response {
+        let method = request.method().to_string();
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let started = Instant::now();
+
+        let response = next.run(request).await;
+
+        let status = response.status().as_u16().to_string();
+        let labels = [method.as_str(), route.as_str(), status.as_str()];
+        METRICS.http_requests.with_label_values(&labels).inc();
+        METRICS
+            .http_request_duration
+            .with_label_values(&labels)
+            .observe(started.elapsed().as_secs_f64());
+        response
+    }
+
+    /// 为服务路由加上请求统计
+    ///
+    /// 配置了管理端口时 `/metrics` 只在管理端口提供；否则挂在服务端口上，并要求 `metrics:read` 权限。
+    pub fn instrument(app: Router<AppState>, config: &MetricsConfig, state: &AppState) -> Router<AppState> {
+        if !config.enabled {
+            return app;
+        }
+        let app = match config.admin_port {
+            Some(_) => app,
+            None => app.route(
+                "/metrics",
+                get(render).layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    crate::auth::authenticate,
+                )),
+            ),
+        };
+        app.layer(axum::middleware::from_fn(track))
+    }
+
+    /// Prometheus抓取端点
+    pub async fn render(State(state): State<AppState>) -> Response {
+        match METRICS.encode(&state.oauth_service.state_capacity()) {
+            Ok(body) => (
+                [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                body,
+            )
+                .into_response(),
+            Err(e) => internal_server_error(format!("指标导出失败: {}", e)),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use axum::body::Body;
+        use tower::Service;
+
+        #[tokio::test]
+        async fn test_requests_are_labelled_by_route_template() {
+            let mut app = Router::new()
+                .route("/api/auth-status/:state", get(|| async { "ok" }))
+                .layer(axum::middleware::from_fn(track));
+
+            for path in ["/api/auth-status/state-a", "/api/auth-status/state-b", "/missing"] {
+                let request = Request::get(path).body(Body::empty()).unwrap();
+                app.call(request).await.unwrap();
+            }
+            super::super::completion("failed", "invalid_grant");
+            super::super::reaper_sweep(2);
+
+            let output = METRICS
+                .encode(&StateCapacity {
+                    pending: 3,
+                    max_pending: 4,
+                    max_pending_per_user: 2,
+                    utilization: 0.75,
+                    evicted_total: 5,
+                    rejected_total: 1,
+                })
+                .unwrap();
+            assert!(output.contains(
+                r#"http_requests_total{method="GET",route="/api/auth-status/:state",status="200"}"#
+            ));
+            assert!(output.contains(r#"route="unmatched",status="404""#));
+            assert!(!output.contains("state-a"));
+            assert!(output.contains(r#"oauth_completions_total{error_code="invalid_grant",outcome="failed"}"#));
+            assert!(output.contains("oauth_active_states 3"));
+            assert!(output.contains("oauth_max_pending_states 4"));
+            assert!(output.contains("oauth_max_pending_states_per_user 2"));
+            assert!(output.contains("oauth_state_utilization 0.75"));
+            assert!(output.contains("oauth_states_evicted_total 5"));
+            assert!(output.contains("oauth_states_rejected_total 1"));
+            assert!(output.contains("oauth_reaper_sweeps_total"));
+        }
+
+        #[test]
+        fn test_exchange_error_code_keeps_label_set_bounded() {
+            let endpoint_error = |error: &str| -> anyhow::Error {
+                crate::oauth::TokenEndpointError {
+                    status: reqwest::StatusCode::BAD_REQUEST,
+                    error: Some(error.to_string()),
+                    body: String::new(),
+                }
+                .into()
+            };
+            assert_eq!(super::super::exchange_error_code(&endpoint_error("invalid_grant")), "invalid_grant");
+            assert_eq!(
+                super::super::exchange_error_code(&endpoint_error("made_up_by_tenant")),
+                "token_endpoint_error"
+            );
+            assert_eq!(super::super::exchange_error_code(&anyhow::anyhow!("连接失败")), "exchange_error");
+        }
+    }
+}
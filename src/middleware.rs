@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -33,3 +33,27 @@ pub fn not_found(message: String) -> Response {
 pub fn unauthorized(message: String) -> Response {
     create_error_response(StatusCode::UNAUTHORIZED, message)
 }
+
+/// 创建禁止访问错误响应
+pub fn forbidden(message: String) -> Response {
+    create_error_response(StatusCode::FORBIDDEN, message)
+}
+
+/// 创建冲突错误响应
+pub fn conflict(message: String) -> Response {
+    create_error_response(StatusCode::CONFLICT, message)
+}
+
+/// 创建服务暂不可用错误响应
+pub fn service_unavailable(message: String) -> Response {
+    create_error_response(StatusCode::SERVICE_UNAVAILABLE, message)
+}
+
+/// 创建请求过多错误响应，附带 Retry-After（秒）
+pub fn too_many_requests(message: String, retry_after: u64) -> Response {
+    let mut response = create_error_response(StatusCode::TOO_MANY_REQUESTS, message);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, retry_after.into());
+    response
+}
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
 use base64::{Engine as _, engine::general_purpose};
 use serde_json::Value;
 
+use crate::secret::Secret;
+
 /// 统一API响应格式
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T> {
@@ -12,14 +15,6 @@ pub struct ApiResponse<T> {
 }
 
 impl<T> ApiResponse<T> {
-    pub fn success(data: T) -> Self {
-        Self {
-            success: true,
-            data,
-            message: "操作成功".to_string(),
-        }
-    }
-
     pub fn success_with_message(data: T, message: String) -> Self {
         Self {
             success: true,
@@ -29,12 +24,21 @@ impl<T> ApiResponse<T> {
     }
 }
 
+/// 授权服务器返回的OAuth错误 (RFC 6749 第5.2节)
+#[derive(Debug, Deserialize)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+}
+
 /// 错误响应（data为空对象）
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
     pub success: bool,
     pub data: Value,
     pub message: String,
+    /// 当前请求的ID，排查问题时提供给服务方
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 impl ErrorResponse {
@@ -43,6 +47,7 @@ impl ErrorResponse {
             success: false,
             data: Value::Object(serde_json::Map::new()),
             message,
+            request_id: crate::request_id::current(),
         }
     }
 }
@@ -50,83 +55,290 @@ impl ErrorResponse {
 /// OAuth状态信息
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OAuthState {
-    pub code_verifier: String,
+    pub code_verifier: Secret<String>,
     pub code_challenge: String,
-    pub state: String,
+    pub state: Secret<String>,
+    /// OIDC nonce，用于校验ID令牌
+    pub nonce: Secret<String>,
+    /// 发起授权的用户标识
+    pub user_id: Option<String>,
     pub creation_time: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// 调用方附加的元数据
+    #[serde(default)]
+    pub metadata: FlowMetadata,
+}
+
+/// 标签数量上限
+const MAX_METADATA_LABELS: usize = 32;
+/// 元数据中单个字符串的长度上限
+const MAX_METADATA_VALUE_LEN: usize = 512;
+
+/// 调用方附加在授权流程上的元数据，随完成结果和webhook原样返回
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlowMetadata {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// 授权完成后返回的应用地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_url: Option<String>,
+    /// 调用方自己的关联标识
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_reference: Option<String>,
+}
+
+impl FlowMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.return_url.is_none() && self.client_reference.is_none()
+    }
+
+    /// 检查元数据大小
+    pub fn validate(&self) -> Result<(), String> {
+        if self.labels.len() > MAX_METADATA_LABELS {
+            return Err(format!("labels 最多 {} 个", MAX_METADATA_LABELS));
+        }
+
+        let too_long = self
+            .labels
+            .iter()
+            .flat_map(|(key, value)| [key, value])
+            .chain(self.return_url.iter())
+            .chain(self.client_reference.iter())
+            .any(|value| value.len() > MAX_METADATA_VALUE_LEN);
+        if too_long {
+            return Err(format!("元数据字段长度不能超过 {} 字节", MAX_METADATA_VALUE_LEN));
+        }
+
+        Ok(())
+    }
 }
 
 /// 获取授权链接的响应数据
 #[derive(Debug, Serialize)]
 pub struct AuthUrlData {
     pub authorize_url: String,
-    pub state: String,
+    pub state: Secret<String>,
+    /// 查询流程状态的凭据，只返回给发起方
+    pub status_token: Secret<String>,
 }
 
 /// 完成授权的请求
 #[derive(Debug, Deserialize)]
 pub struct CompleteAuthRequest {
-    pub code: String,
-    pub state: String,
+    pub code: Secret<String>,
+    pub state: Secret<String>,
     pub tenant_url: String,
 }
 
+/// 用取回码换取令牌的请求
+#[derive(Debug, Deserialize)]
+pub struct RedeemRequest {
+    pub retrieval_code: Secret<String>,
+}
+
 /// 完成授权的响应数据
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CompleteAuthData {
     pub status: String,
-    pub token: String,
+    pub token: Secret<String>,
     pub tenant_url: String,
     pub token_info: TokenInfo,
+    /// DPoP私钥JWK，使用DPoP绑定的令牌时需要用它签名证明
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpop_key: Option<Secret<Value>>,
+    /// 经过校验的ID令牌身份声明
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<IdentityClaims>,
+    /// 获取授权链接时附加的元数据
+    #[serde(skip_serializing_if = "FlowMetadata::is_empty")]
+    pub metadata: FlowMetadata,
+}
+
+/// 经过校验的ID令牌身份声明
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
 }
 
 /// Token信息
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TokenInfo {
     pub id: String,
+    pub token_type: String,
     pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// DPoP密钥指纹 (cnf.jkt)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpop_jkt: Option<String>,
 }
 
+/// 已签发的token记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRecord {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub tenant_url: String,
+    pub access_token: Secret<String>,
+    pub token_type: String,
+    pub refresh_token: Option<Secret<String>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// 绑定的DPoP私钥JWK
+    pub dpop_key: Option<Secret<Value>>,
+    /// 绑定的DPoP密钥指纹
+    pub dpop_jkt: Option<String>,
+    /// 经过校验的ID令牌身份声明
+    pub identity: Option<IdentityClaims>,
+    /// 是否已吊销
+    #[serde(default)]
+    pub revoked: bool,
+    /// 刷新令牌已失效，需要用户重新授权
+    #[serde(default)]
+    pub needs_reauth: bool,
+    /// 最近一次后台刷新时间
+    #[serde(default)]
+    pub last_refresh_at: Option<DateTime<Utc>>,
+    /// 最近一次后台刷新失败的原因
+    #[serde(default)]
+    pub last_refresh_error: Option<String>,
+}
 
+/// 后台刷新状态
+#[derive(Debug, Serialize)]
+pub struct TokenRefreshStatus {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub tenant_url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub has_refresh_token: bool,
+    pub needs_reauth: bool,
+    pub last_refresh_at: Option<DateTime<Utc>>,
+    pub last_refresh_error: Option<String>,
+}
+
+/// Token刷新请求
+#[derive(Debug, Serialize)]
+pub struct TokenRefreshRequest {
+    pub grant_type: String,
+    pub client_id: String,
+    pub refresh_token: Secret<String>,
+}
+
+impl TokenRecord {
+    /// 令牌是否仍然有效（未吊销且未过期）
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.expires_at.is_none_or(|expires_at| expires_at > Utc::now())
+    }
+
+    pub fn refresh_status(&self) -> TokenRefreshStatus {
+        TokenRefreshStatus {
+            id: self.id.clone(),
+            user_id: self.user_id.clone(),
+            tenant_url: self.tenant_url.clone(),
+            expires_at: self.expires_at,
+            has_refresh_token: self.refresh_token.is_some(),
+            needs_reauth: self.needs_reauth,
+            last_refresh_at: self.last_refresh_at,
+            last_refresh_error: self.last_refresh_error.clone(),
+        }
+    }
+
+    pub fn token_info(&self) -> TokenInfo {
+        TokenInfo {
+            id: self.id.clone(),
+            token_type: self.token_type.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            dpop_jkt: self.dpop_jkt.clone(),
+        }
+    }
+}
 
 /// Token交换请求
 #[derive(Debug, Serialize)]
 pub struct TokenExchangeRequest {
     pub grant_type: String,
     pub client_id: String,
-    pub code_verifier: String,
+    pub code_verifier: Secret<String>,
     pub redirect_uri: String,
-    pub code: String,
+    pub code: Secret<String>,
 }
 
 /// Token交换响应
 #[derive(Debug, Deserialize)]
 pub struct TokenExchangeResponse {
-    pub access_token: String,
+    pub access_token: Secret<String>,
     pub token_type: Option<String>,
     pub expires_in: Option<u64>,
-    pub refresh_token: Option<String>,
+    pub refresh_token: Option<Secret<String>>,
+    pub id_token: Option<Secret<String>>,
+}
+
+/// 授权服务器元数据 (RFC 8414)
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthServerMetadata {
+    pub issuer: Option<String>,
+    pub jwks_uri: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+    pub mtls_endpoint_aliases: Option<MtlsEndpointAliases>,
+}
+
+/// mTLS端点别名 (RFC 8705 第5节)
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MtlsEndpointAliases {
+    pub token_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+}
+
+/// 令牌自省请求
+#[derive(Debug, Deserialize)]
+pub struct IntrospectRequest {
+    pub token: Secret<String>,
+    /// 本地没有该令牌记录时，用于向授权服务器自省的租户URL
+    pub tenant_url: Option<String>,
+}
+
+/// 令牌自省结果
+#[derive(Debug, Clone, Serialize)]
+pub struct IntrospectionData {
+    pub active: bool,
+    /// 结果来源: provider 或 local
+    pub source: String,
+    pub exp: Option<i64>,
+    pub user_id: Option<String>,
+    pub tenant_url: Option<String>,
+    pub revoked: bool,
+    /// 授权服务器返回的原始自省结果 (RFC 7662)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider_response: Option<Value>,
 }
 
 impl OAuthState {
-    pub fn new() -> Self {
+    pub fn new(user_id: Option<String>, ttl: Duration, metadata: FlowMetadata) -> Self {
         let code_verifier = generate_code_verifier();
         let code_challenge = generate_code_challenge(&code_verifier);
         let state = generate_state();
+        let nonce = generate_nonce();
+        let creation_time = Utc::now();
 
         Self {
-            code_verifier,
+            code_verifier: Secret::new(code_verifier),
             code_challenge,
-            state,
-            creation_time: Utc::now(),
+            state: Secret::new(state),
+            nonce: Secret::new(nonce),
+            user_id,
+            creation_time,
+            expires_at: creation_time + ttl,
+            metadata,
         }
     }
 
-    /// 检查状态是否过期（30分钟）
+    /// 检查状态是否过期
     pub fn is_expired(&self) -> bool {
-        let now = Utc::now();
-        let duration = now.signed_duration_since(self.creation_time);
-        duration.num_minutes() > 30
+        Utc::now() > self.expires_at
     }
 }
 
@@ -154,3 +366,11 @@ fn generate_state() -> String {
     let bytes: Vec<u8> = (0..8).map(|_| rng.gen()).collect();
     general_purpose::URL_SAFE_NO_PAD.encode(bytes)
 }
+
+/// 生成OIDC nonce参数
+fn generate_nonce() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let bytes: Vec<u8> = (0..16).map(|_| rng.gen()).collect();
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -1,122 +1,765 @@
-use crate::models::{OAuthState, TokenExchangeRequest, TokenExchangeResponse};
+use crate::config::{ClientCertConfig, OAuthConfig, StateOverflowPolicy};
+use crate::dpop::DpopKey;
+use crate::flow::state_id;
+use crate::metrics;
+use crate::models::{
+    AuthServerMetadata, FlowMetadata, OAuthErrorResponse, OAuthState, TokenExchangeRequest,
+    TokenExchangeResponse, TokenRecord, TokenRefreshRequest, IdentityClaims,
+};
+use crate::oidc::{IdTokenExpectations, IdTokenValidator};
+use crate::request_id;
+use crate::secret::Secret;
+use crate::telemetry;
 use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, Utc};
 use dashmap::DashMap;
-use std::sync::Arc;
-use tracing::{info, error};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, error, warn, Instrument};
 use url::Url;
+use uuid::Uuid;
+
+/// token端点返回的错误
+#[derive(Debug)]
+pub struct TokenEndpointError {
+    pub status: reqwest::StatusCode,
+    /// OAuth错误码，例如 `invalid_grant`
+    pub error: Option<String>,
+    pub body: String,
+}
+
+impl std::fmt::Display for TokenEndpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "请求令牌失败: {} {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for TokenEndpointError {}
+
+/// 等待授权的OAuth状态已达上限
+#[derive(Debug)]
+pub struct StateCapacityError {
+    pub message: String,
+}
+
+impl std::fmt::Display for StateCapacityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StateCapacityError {}
+
+/// 生成的授权链接
+#[derive(Debug)]
+pub struct GeneratedAuthUrl {
+    pub authorize_url: String,
+    pub oauth_state: OAuthState,
+    /// 为腾出容量被淘汰的状态
+    pub evicted: Vec<OAuthState>,
+}
+
+/// 等待授权的OAuth状态容量
+#[derive(Debug, Serialize)]
+pub struct StateCapacity {
+    pub pending: usize,
+    pub max_pending: usize,
+    pub max_pending_per_user: usize,
+    /// 占全局上限的比例，不限制时为0
+    pub utilization: f64,
+    pub evicted_total: u64,
+    pub rejected_total: u64,
+}
+
+/// 授权服务器元数据缓存上限，超过后不再缓存新的tenant
+const MAX_CACHED_METADATA: usize = 1000;
+
+/// 缓存的授权服务器元数据
+struct CachedMetadata {
+    metadata: AuthServerMetadata,
+    fetched_at: DateTime<Utc>,
+}
+
+/// 按创建时间排序的等待授权状态索引，容量检查和淘汰不需要遍历全部状态
+#[derive(Default)]
+struct PendingIndex {
+    /// (创建时间, state)
+    all: BTreeSet<(DateTime<Utc>, String)>,
+    /// user_id -> (创建时间, state)
+    by_user: HashMap<String, BTreeSet<(DateTime<Utc>, String)>>,
+}
+
+impl PendingIndex {
+    fn insert(&mut self, oauth_state: &OAuthState) {
+        let entry = (oauth_state.creation_time, oauth_state.state.expose().clone());
+        if let Some(user_id) = &oauth_state.user_id {
+            self.by_user.entry(user_id.clone()).or_default().insert(entry.clone());
+        }
+        self.all.insert(entry);
+    }
+
+    fn remove(&mut self, oauth_state: &OAuthState) {
+        let entry = (oauth_state.creation_time, oauth_state.state.expose().clone());
+        if let Some(user_id) = &oauth_state.user_id {
+            if let Some(states) = self.by_user.get_mut(user_id) {
+                states.remove(&entry);
+                if states.is_empty() {
+                    self.by_user.remove(user_id);
+                }
+            }
+        }
+        self.all.remove(&entry);
+    }
+
+    fn user_count(&self, user_id: &str) -> usize {
+        self.by_user.get(user_id).map_or(0, BTreeSet::len)
+    }
+
+    /// 最早的状态，指定用户时只在该用户的状态中查找
+    fn oldest(&self, user_id: Option<&str>) -> Option<String> {
+        let states = match user_id {
+            Some(user_id) => self.by_user.get(user_id)?,
+            None => &self.all,
+        };
+        states.first().map(|(_, state)| state.clone())
+    }
+}
 
 /// OAuth服务
 pub struct OAuthService {
+    /// OAuth配置
+    config: OAuthConfig,
     /// 存储OAuth状态的内存映射 (state -> OAuthState)
     oauth_states: Arc<DashMap<String, OAuthState>>,
+    /// 等待授权状态的索引；增删状态时都持有该锁，同时串行化容量检查和写入，保证不超过上限
+    pending: Mutex<PendingIndex>,
+    /// 因容量被淘汰的状态数
+    evicted_states: AtomicU64,
+    /// 因容量被拒绝的请求数
+    rejected_states: AtomicU64,
+    /// 访问授权服务器的HTTP客户端
+    http_client: reqwest::Client,
+    /// 各tenant携带自己mTLS客户端证书的HTTP客户端 (去掉末尾 `/` 的tenant_url -> 客户端)
+    mtls_clients: HashMap<String, reqwest::Client>,
+    /// 受信任的tenant地址（去掉末尾的 `/`）
+    trusted_tenants: Vec<String>,
+    /// 授权服务器元数据缓存 (tenant_url -> 元数据)，只缓存获取成功的结果
+    server_metadata: DashMap<String, CachedMetadata>,
+    /// 授权服务器下发的最新DPoP nonce (token_url -> nonce)
+    dpop_nonces: DashMap<String, String>,
+    /// OIDC ID令牌校验器
+    id_token_validator: IdTokenValidator,
 }
 
 impl OAuthService {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(config: OAuthConfig) -> Result<Self> {
+        let http_client = http_client_builder(&config)?.build()?;
+        let trusted_tenants: Vec<String> = config
+            .trusted_tenants
+            .iter()
+            .map(|tenant| tenant.trim_end_matches('/').to_string())
+            .collect();
+
+        let mut mtls_clients = HashMap::new();
+        for (tenant_url, cert) in &config.client_certs {
+            let tenant_key = tenant_url.trim_end_matches('/');
+            if !trusted_tenants.iter().any(|trusted| trusted == tenant_key) {
+                return Err(anyhow!(
+                    "tenant {} 配置了mTLS客户端证书，但不在 OAUTH_TRUSTED_TENANTS 中",
+                    tenant_url
+                ));
+            }
+            let identity = load_client_identity(cert)?;
+            info!("已为tenant {} 启用mTLS客户端认证, 证书: {}", tenant_url, cert.cert_path);
+            mtls_clients.insert(
+                tenant_key.to_string(),
+                http_client_builder(&config)?.identity(identity).build()?,
+            );
+        }
+        let id_token_validator = IdTokenValidator::new(
+            http_client.clone(),
+            config.id_token_clock_skew_seconds,
+            config.jwks_cache_seconds,
+        );
+
+        Ok(Self {
+            config,
             oauth_states: Arc::new(DashMap::new()),
+            pending: Mutex::new(PendingIndex::default()),
+            evicted_states: AtomicU64::new(0),
+            rejected_states: AtomicU64::new(0),
+            http_client,
+            mtls_clients,
+            trusted_tenants,
+            server_metadata: DashMap::new(),
+            dpop_nonces: DashMap::new(),
+            id_token_validator,
+        })
+    }
+
+    /// tenant是否在 `OAUTH_TRUSTED_TENANTS` 中
+    pub fn is_trusted_tenant(&self, tenant_url: &str) -> bool {
+        let tenant_key = tenant_url.trim_end_matches('/');
+        self.trusted_tenants.iter().any(|trusted| trusted == tenant_key)
+    }
+
+    /// 访问该tenant时是否使用mTLS客户端认证
+    fn mtls_enabled(&self, tenant_url: &str) -> bool {
+        self.mtls_clients.contains_key(tenant_url.trim_end_matches('/'))
+    }
+
+    /// 选择访问该tenant的HTTP客户端，每个tenant只会收到为它配置的客户端证书
+    fn client_for(&self, tenant_url: &str) -> &reqwest::Client {
+        self.mtls_clients
+            .get(tenant_url.trim_end_matches('/'))
+            .unwrap_or(&self.http_client)
+    }
+
+    /// 计算OAuth状态有效期，未指定时使用默认值，超过上限时报错
+    pub fn state_ttl(&self, ttl_seconds: Option<u64>) -> Result<Duration> {
+        let max_seconds = self.config.state_max_ttl_minutes as u64 * 60;
+        match ttl_seconds {
+            None => Ok(Duration::minutes(self.config.state_expire_minutes as i64)),
+            Some(0) => Err(anyhow!("ttl_seconds 必须大于0")),
+            Some(ttl) if ttl > max_seconds => {
+                Err(anyhow!("ttl_seconds 不能超过 {} 秒", max_seconds))
+            }
+            Some(ttl) => Ok(Duration::seconds(ttl as i64)),
         }
     }
 
-    /// 生成授权URL
-    pub fn generate_auth_url(&self) -> Result<(String, String)> {
+    /// 生成授权URL，返回授权链接和保存的OAuth状态
+    ///
+    /// 等待授权的状态达到上限时按配置的策略淘汰最早的状态，或返回 [`StateCapacityError`]。
+    pub fn generate_auth_url(
+        &self,
+        user_id: Option<String>,
+        ttl: Duration,
+        metadata: FlowMetadata,
+    ) -> Result<GeneratedAuthUrl> {
         // 创建OAuth状态
-        let oauth_state = OAuthState::new();
-        let state = oauth_state.state.clone();
+        let oauth_state = OAuthState::new(user_id, ttl, metadata);
+        let state = oauth_state.state.expose().clone();
 
         // 构建授权URL参数
-        let mut url = Url::parse("https://auth.augmentcode.com/authorize")?;
+        let mut url = Url::parse(&self.config.auth_url)?;
         url.query_pairs_mut()
             .append_pair("response_type", "code")
             .append_pair("code_challenge", &oauth_state.code_challenge)
-            .append_pair("client_id", "v")
-            .append_pair("state", &oauth_state.state)
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("state", oauth_state.state.as_str())
+            .append_pair("nonce", oauth_state.nonce.as_str())
             .append_pair("prompt", "login");
 
         let auth_url = url.to_string();
 
         // 保存OAuth状态
-        self.oauth_states.insert(state.clone(), oauth_state);
+        let evicted = {
+            let mut pending = self.lock_pending();
+            let evicted = self.make_room(&mut pending, oauth_state.user_id.as_deref())?;
+            pending.insert(&oauth_state);
+            self.oauth_states.insert(state, oauth_state.clone());
+            evicted
+        };
 
-        info!("生成授权链接: {}", auth_url);
+        info!("生成授权链接, 当前活跃状态数: {}", self.oauth_states.len());
+        metrics::auth_url_generated();
 
-        Ok((auth_url, state))
+        Ok(GeneratedAuthUrl {
+            authorize_url: auth_url,
+            oauth_state,
+            evicted,
+        })
     }
 
-    /// 验证state并获取OAuth状态
-    pub fn get_oauth_state(&self, state: &str) -> Result<OAuthState> {
-        // 获取OAuth状态
-        let oauth_state = self.oauth_states
-            .get(state)
-            .ok_or_else(|| anyhow!("未找到OAuth状态，请重新获取授权链接"))?
-            .clone();
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, PendingIndex> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 检查全局和单用户上限，需要时淘汰最早的状态
+    fn make_room(&self, pending: &mut PendingIndex, user_id: Option<&str>) -> Result<Vec<OAuthState>> {
+        let mut evicted = Vec::new();
+
+        if let Some(user_id) = user_id {
+            let max = self.config.max_pending_states_per_user;
+            while max > 0 && pending.user_count(user_id) >= max {
+                let scope = format!("用户 {} 等待授权的请求已达上限 {}", user_id, max);
+                evicted.push(self.evict_oldest(pending, &scope, Some(user_id))?);
+            }
+        }
 
-        // 检查是否过期
-        if oauth_state.is_expired() {
-            self.oauth_states.remove(state);
-            return Err(anyhow!("OAuth状态已过期，请重新获取授权链接"));
+        let max = self.config.max_pending_states;
+        while max > 0 && self.oauth_states.len() >= max {
+            let scope = format!("等待授权的请求已达上限 {}", max);
+            evicted.push(self.evict_oldest(pending, &scope, None)?);
+        }
+
+        Ok(evicted)
+    }
+
+    /// 按策略淘汰最早的状态（指定用户时只淘汰该用户的状态），拒绝策略下返回错误
+    fn evict_oldest(&self, pending: &mut PendingIndex, scope: &str, user_id: Option<&str>) -> Result<OAuthState> {
+        if self.config.state_overflow_policy == StateOverflowPolicy::Reject {
+            self.rejected_states.fetch_add(1, Ordering::Relaxed);
+            warn!("{}，拒绝新的授权请求", scope);
+            return Err(StateCapacityError {
+                message: format!("{}，请稍后重试", scope),
+            }
+            .into());
         }
 
+        let oldest = pending
+            .oldest(user_id)
+            .ok_or_else(|| anyhow!("{}，但没有可淘汰的状态", scope))?;
+        let (_, oauth_state) = self
+            .oauth_states
+            .remove(&oldest)
+            .ok_or_else(|| anyhow!("{}，但没有可淘汰的状态", scope))?;
+        pending.remove(&oauth_state);
+
+        self.evicted_states.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "{}，淘汰最早的OAuth状态, state_id: {}, user_id: {:?}",
+            scope,
+            state_id(oauth_state.state.as_str()),
+            oauth_state.user_id
+        );
         Ok(oauth_state)
     }
 
-    /// 清除OAuth状态
-    pub fn clear_oauth_state(&self, state: &str) {
-        self.oauth_states.remove(state);
+    /// 当前容量使用情况
+    pub fn state_capacity(&self) -> StateCapacity {
+        let pending = self.oauth_states.len();
+        let max_pending = self.config.max_pending_states;
+        StateCapacity {
+            pending,
+            max_pending,
+            max_pending_per_user: self.config.max_pending_states_per_user,
+            utilization: if max_pending > 0 {
+                pending as f64 / max_pending as f64
+            } else {
+                0.0
+            },
+            evicted_total: self.evicted_states.load(Ordering::Relaxed),
+            rejected_total: self.rejected_states.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 验证state并原子地取出OAuth状态，每个state只能成功取出一次
+    pub fn take_oauth_state(&self, state: &str) -> Result<OAuthState> {
+        // 过期状态保留给清理任务移除并通知
+        let mut pending = self.lock_pending();
+        if let Some((_, oauth_state)) = self
+            .oauth_states
+            .remove_if(state, |_, oauth_state| !oauth_state.is_expired())
+        {
+            pending.remove(&oauth_state);
+            return Ok(oauth_state);
+        }
+
+        if self.oauth_states.contains_key(state) {
+            Err(anyhow!("OAuth状态已过期，请重新获取授权链接"))
+        } else {
+            Err(anyhow!("未找到OAuth状态，请重新获取授权链接"))
+        }
+    }
+
+    /// 校验返回地址的源是否在允许列表中，防止开放重定向
+    pub fn validate_return_url(&self, return_url: &str) -> Result<Url> {
+        let url = Url::parse(return_url).map_err(|e| anyhow!("无效的 return_url: {}", e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("return_url 只支持 http 和 https"));
+        }
+
+        let origin = url.origin().ascii_serialization();
+        let allowed = self.config.return_url_allowed_origins.iter().any(|allowed| {
+            Url::parse(allowed).is_ok_and(|allowed| allowed.origin().ascii_serialization() == origin)
+        });
+        if !allowed {
+            return Err(anyhow!("return_url 的源 {} 不在允许列表中", origin));
+        }
+
+        Ok(url)
+    }
+
+    /// 放弃尚未使用的OAuth状态
+    pub fn cancel_oauth_state(&self, state: &str) -> Option<OAuthState> {
+        let mut pending = self.lock_pending();
+        let (_, oauth_state) = self.oauth_states.remove(state)?;
+        pending.remove(&oauth_state);
+        Some(oauth_state)
+    }
+
+    /// 放回刚刚放弃的OAuth状态，用于撤销失败的续期
+    pub fn restore_oauth_state(&self, oauth_state: OAuthState) {
+        let mut pending = self.lock_pending();
+        pending.insert(&oauth_state);
+        self.oauth_states.insert(oauth_state.state.expose().clone(), oauth_state);
     }
 
     /// 使用授权码交换访问令牌
+    ///
+    /// 启用DPoP时为新令牌生成专属密钥，并随token记录一起返回；
+    /// 授权服务器返回ID令牌时校验通过后才会签发记录。无法确认签发者可信时不校验ID令牌，记录中不带身份。
     pub async fn exchange_token(
         &self,
         tenant_url: &str,
-        code_verifier: &str,
+        oauth_state: &OAuthState,
         code: &str,
-    ) -> Result<String> {
-        // 构建token端点URL
-        let token_url = if tenant_url.ends_with('/') {
-            format!("{}token", tenant_url)
-        } else {
-            format!("{}/token", tenant_url)
-        };
-
+    ) -> Result<TokenRecord> {
         // 构建请求数据
         let request_data = TokenExchangeRequest {
             grant_type: "authorization_code".to_string(),
-            client_id: "v".to_string(),
-            code_verifier: code_verifier.to_string(),
+            client_id: self.config.client_id.clone(),
+            code_verifier: oauth_state.code_verifier.clone(),
             redirect_uri: "".to_string(),
-            code: code.to_string(),
+            code: code.into(),
+        };
+
+        let dpop_key = self.config.dpop_enabled.then(DpopKey::generate);
+
+        let token_response = self
+            .request_token(tenant_url, &request_data, dpop_key.as_ref())
+            .await?;
+
+        info!("Token交换成功");
+
+        let identity = match &token_response.id_token {
+            Some(id_token) if self.can_validate_id_token(tenant_url) => Some(
+                self.validate_id_token(tenant_url, id_token.as_str(), oauth_state.nonce.as_str())
+                    .await?,
+            ),
+            Some(_) => {
+                warn!(
+                    "未配置 OIDC_ISSUER/OIDC_JWKS_URL 且tenant不受信任，忽略ID令牌: {}",
+                    tenant_url
+                );
+                None
+            }
+            None => None,
         };
 
+        let now = Utc::now();
+        Ok(TokenRecord {
+            id: Uuid::new_v4().to_string(),
+            user_id: oauth_state.user_id.clone(),
+            tenant_url: tenant_url.to_string(),
+            access_token: token_response.access_token,
+            token_type: token_response
+                .token_type
+                .unwrap_or_else(|| default_token_type(dpop_key.as_ref())),
+            refresh_token: token_response.refresh_token,
+            expires_at: token_response
+                .expires_in
+                .map(|secs| now + Duration::seconds(secs as i64)),
+            created_at: now,
+            dpop_jkt: dpop_key.as_ref().map(DpopKey::thumbprint),
+            dpop_key: dpop_key.as_ref().map(|key| Secret::new(key.private_jwk())),
+            identity,
+            revoked: false,
+            needs_reauth: false,
+            last_refresh_at: None,
+            last_refresh_error: None,
+        })
+    }
+
+    /// 使用刷新令牌获取新的访问令牌
+    ///
+    /// DPoP令牌使用记录中保存的密钥签发证明；授权服务器未轮换刷新令牌时沿用原刷新令牌。
+    pub async fn refresh_token(&self, record: &TokenRecord) -> Result<TokenRecord> {
+        let refresh_token = record
+            .refresh_token
+            .clone()
+            .ok_or_else(|| anyhow!("token记录没有刷新令牌"))?;
+        let dpop_key = record
+            .dpop_key
+            .as_ref()
+            .map(|jwk| DpopKey::from_jwk(jwk.expose()))
+            .transpose()?;
+
+        let request_data = TokenRefreshRequest {
+            grant_type: "refresh_token".to_string(),
+            client_id: self.config.client_id.clone(),
+            refresh_token,
+        };
+
+        let token_response = self
+            .request_token(&record.tenant_url, &request_data, dpop_key.as_ref())
+            .await?;
+
+        let now = Utc::now();
+        let mut refreshed = record.clone();
+        refreshed.access_token = token_response.access_token;
+        if let Some(token_type) = token_response.token_type {
+            refreshed.token_type = token_type;
+        }
+        if let Some(refresh_token) = token_response.refresh_token {
+            refreshed.refresh_token = Some(refresh_token);
+        }
+        refreshed.expires_at = token_response
+            .expires_in
+            .map(|secs| now + Duration::seconds(secs as i64));
+
+        Ok(refreshed)
+    }
+
+    /// ID令牌的签发者和JWKS是否可信：两者都已配置，或tenant在受信任列表中
+    ///
+    /// `tenant_url` 由调用方传入，任何人都可以搭建自己的授权服务器并用自己的JWKS签发ID令牌，
+    /// 因此不受信任的tenant不能通过元数据决定签发者和公钥。
+    fn can_validate_id_token(&self, tenant_url: &str) -> bool {
+        (self.config.oidc_issuer.is_some() && self.config.oidc_jwks_url.is_some())
+            || self.is_trusted_tenant(tenant_url)
+    }
+
+    /// 校验ID令牌，issuer和jwks_uri优先使用配置，其次使用受信任tenant的授权服务器元数据
+    async fn validate_id_token(
+        &self,
+        tenant_url: &str,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdentityClaims> {
+        let metadata = match (&self.config.oidc_issuer, &self.config.oidc_jwks_url) {
+            (Some(_), Some(_)) => AuthServerMetadata::default(),
+            _ => self.server_metadata(tenant_url).await,
+        };
+
+        let issuer = self
+            .config
+            .oidc_issuer
+            .clone()
+            .or(metadata.issuer)
+            .ok_or_else(|| anyhow!("无法确定ID令牌签发者，请配置 OIDC_ISSUER"))?;
+        let jwks_uri = self
+            .config
+            .oidc_jwks_url
+            .clone()
+            .or(metadata.jwks_uri)
+            .ok_or_else(|| anyhow!("无法确定JWKS地址，请配置 OIDC_JWKS_URL"))?;
+
+        let identity = self
+            .id_token_validator
+            .validate(
+                id_token,
+                &IdTokenExpectations {
+                    issuer: &issuer,
+                    audience: &self.config.client_id,
+                    nonce,
+                    jwks_uri: &jwks_uri,
+                },
+            )
+            .await?;
+
+        info!("ID令牌校验成功, sub: {}", identity.sub);
+        Ok(identity)
+    }
+
+    /// 向token端点发送请求，授权码交换与刷新令牌共用此路径
+    ///
+    /// 传入DPoP密钥时附带DPoP证明，授权服务器要求 `use_dpop_nonce` 时携带新nonce重试一次。
+    async fn request_token<T: Serialize>(
+        &self,
+        tenant_url: &str,
+        request_data: &T,
+        dpop_key: Option<&DpopKey>,
+    ) -> Result<TokenExchangeResponse> {
+        let token_url = self.resolve_token_endpoint(tenant_url).await;
+
         info!("请求token交换: {}", token_url);
+        let host = Url::parse(&token_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        // 租户地址由调用方传入，只为受信任的租户单独建立标签，避免指标标签数量失控
+        let host_label = if self.is_trusted_tenant(tenant_url) { host.as_str() } else { "other" };
+
+        let mut nonce_retried = false;
+        loop {
+            // 发送HTTP请求
+            let mut request = self
+                .client_for(tenant_url)
+                .post(&token_url)
+                .header("Content-Type", "application/json")
+                .json(request_data);
+
+            // 授权服务器日志可以用同一个请求ID关联
+            if let Some(request_id) = request_id::current() {
+                request = request.header("X-Request-Id", request_id);
+            }
 
-        // 发送HTTP请求
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&token_url)
-            .header("Content-Type", "application/json")
-            .json(&request_data)
+            if let Some(key) = dpop_key {
+                let nonce = self.dpop_nonces.get(&token_url).map(|n| n.clone());
+                request = request.header("DPoP", key.proof("POST", &token_url, nonce.as_deref(), None)?);
+            }
+
+            // 链路中对token端点的调用，授权服务器的span接在它之后
+            let span = tracing::info_span!(
+                "token_request",
+                host = %host,
+                otel.name = "POST token",
+                otel.kind = "client",
+            );
+            let request = span.in_scope(|| telemetry::inject(request));
+
+            let started = std::time::Instant::now();
+            let response = request.send().instrument(span).await;
+            metrics::upstream_token_request(host_label, started.elapsed());
+            let response = response?;
+
+            // 记录授权服务器下发的nonce，后续请求直接使用
+            let fresh_nonce = response
+                .headers()
+                .get("DPoP-Nonce")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            if let Some(nonce) = &fresh_nonce {
+                self.dpop_nonces.insert(token_url.clone(), nonce.clone());
+            }
+
+            // 检查响应状态
+            if !response.status().is_success() {
+                let status = response.status();
+                let error_text = response.text().await.unwrap_or_default();
+
+                let oauth_error = serde_json::from_str::<OAuthErrorResponse>(&error_text)
+                    .ok()
+                    .map(|e| e.error);
+                let use_dpop_nonce = oauth_error.as_deref() == Some("use_dpop_nonce");
+                if dpop_key.is_some() && use_dpop_nonce && fresh_nonce.is_some() && !nonce_retried {
+                    debug!("授权服务器要求DPoP nonce，携带新nonce重试");
+                    nonce_retried = true;
+                    continue;
+                }
+
+                error!("Token交换失败: {} - {}", status, error_text);
+                return Err(TokenEndpointError {
+                    status,
+                    error: oauth_error,
+                    body: error_text,
+                }
+                .into());
+            }
+
+            // 解析响应
+            return Ok(response.json().await?);
+        }
+    }
+
+    /// 通过授权服务器的自省端点 (RFC 7662) 查询令牌状态
+    ///
+    /// 授权服务器未声明自省端点时返回 `None`。
+    pub async fn introspect_token(&self, tenant_url: &str, token: &str) -> Result<Option<Value>> {
+        let metadata = self.server_metadata(tenant_url).await;
+        let mtls_alias = metadata
+            .mtls_endpoint_aliases
+            .and_then(|aliases| aliases.introspection_endpoint)
+            .filter(|_| self.mtls_enabled(tenant_url));
+        let Some(endpoint) = mtls_alias.or(metadata.introspection_endpoint) else {
+            return Ok(None);
+        };
+
+        debug!("请求令牌自省: {}", endpoint);
+
+        let response = telemetry::inject(self.client_for(tenant_url).post(&endpoint))
+            .form(&[
+                ("token", token),
+                ("token_type_hint", "access_token"),
+                ("client_id", &self.config.client_id),
+            ])
             .send()
             .await?;
 
-        // 检查响应状态
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            error!("Token交换失败: {} - {}", status, error_text);
-            return Err(anyhow!("请求令牌失败: {} {}", status, error_text));
+            return Err(anyhow!("令牌自省失败: {} {}", status, error_text));
         }
 
-        // 解析响应
-        let token_response: TokenExchangeResponse = response.json().await?;
+        Ok(Some(response.json().await?))
+    }
 
-        info!("Token交换成功");
+    /// 解析token端点URL
+    ///
+    /// 对受信任的tenant启用mTLS时优先使用授权服务器元数据中的 `mtls_endpoint_aliases.token_endpoint`。
+    async fn resolve_token_endpoint(&self, tenant_url: &str) -> String {
+        let default_url = format!("{}/token", tenant_url.trim_end_matches('/'));
+
+        if !self.mtls_enabled(tenant_url) {
+            return default_url;
+        }
 
-        Ok(token_response.access_token)
+        let metadata = self.server_metadata(tenant_url).await;
+        metadata
+            .mtls_endpoint_aliases
+            .and_then(|aliases| aliases.token_endpoint)
+            .or(metadata.token_endpoint)
+            .unwrap_or(default_url)
     }
 
-    /// 清理过期的OAuth状态
-    pub fn cleanup_expired_states(&self) {
+    /// 获取授权服务器元数据，成功的结果按tenant缓存；获取失败时返回空元数据以回退到默认配置
+    ///
+    /// 依次尝试 RFC 8414 和 OpenID Connect Discovery 的元数据地址。失败不缓存，下次请求重新获取。
+    async fn server_metadata(&self, tenant_url: &str) -> AuthServerMetadata {
+        let tenant_key = tenant_url.trim_end_matches('/');
+        let cache_ttl = Duration::seconds(self.config.metadata_cache_seconds as i64);
+        if let Some(cached) = self.server_metadata.get(tenant_key) {
+            if Utc::now() - cached.fetched_at < cache_ttl {
+                return cached.metadata.clone();
+            }
+        }
+
+        for well_known in ["oauth-authorization-server", "openid-configuration"] {
+            let discovery_url = format!("{}/.well-known/{}", tenant_key, well_known);
+            match self.fetch_server_metadata(tenant_url, &discovery_url).await {
+                Ok(metadata) => {
+                    self.cache_server_metadata(tenant_key, &metadata, cache_ttl);
+                    return metadata;
+                }
+                Err(e) => debug!("获取授权服务器元数据失败 {}: {}", discovery_url, e),
+            }
+        }
+
+        warn!("未获取到授权服务器元数据，使用默认配置: {}", tenant_key);
+        AuthServerMetadata::default()
+    }
+
+    /// 缓存元数据，达到上限时先清理过期项，仍然没有空间则不缓存
+    fn cache_server_metadata(&self, tenant_key: &str, metadata: &AuthServerMetadata, cache_ttl: Duration) {
+        if self.server_metadata.len() >= MAX_CACHED_METADATA && !self.server_metadata.contains_key(tenant_key) {
+            self.server_metadata
+                .retain(|_, cached| Utc::now() - cached.fetched_at < cache_ttl);
+            if self.server_metadata.len() >= MAX_CACHED_METADATA {
+                return;
+            }
+        }
+
+        self.server_metadata.insert(
+            tenant_key.to_string(),
+            CachedMetadata {
+                metadata: metadata.clone(),
+                fetched_at: Utc::now(),
+            },
+        );
+    }
+
+    async fn fetch_server_metadata(&self, tenant_url: &str, discovery_url: &str) -> Result<AuthServerMetadata> {
+        debug!("获取授权服务器元数据: {}", discovery_url);
+
+        let response = telemetry::inject(self.client_for(tenant_url).get(discovery_url))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("元数据请求返回 {}", response.status()));
+        }
+
+        Ok(response.json().await?)
+    }
+
+    /// 清理过期的OAuth状态，返回被清理的状态
+    pub fn cleanup_expired_states(&self) -> Vec<OAuthState> {
         let mut expired_keys = Vec::new();
 
         // 收集过期的key
@@ -127,13 +770,19 @@ impl OAuthService {
         }
 
         // 删除过期的状态
-        for key in expired_keys {
-            self.oauth_states.remove(&key);
-        }
+        let mut pending = self.lock_pending();
+        let expired: Vec<OAuthState> = expired_keys
+            .iter()
+            .filter_map(|key| self.oauth_states.remove(key).map(|(_, state)| state))
+            .inspect(|state| pending.remove(state))
+            .collect();
+        drop(pending);
 
         if !self.oauth_states.is_empty() {
             info!("清理过期OAuth状态，当前活跃状态数: {}", self.oauth_states.len());
         }
+
+        expired
     }
 
     /// 获取当前活跃的OAuth状态数量
@@ -142,8 +791,713 @@ impl OAuthService {
     }
 }
 
-impl Default for OAuthService {
-    fn default() -> Self {
-        Self::new()
+/// 授权服务器未返回token_type时的默认值
+fn default_token_type(dpop_key: Option<&DpopKey>) -> String {
+    if dpop_key.is_some() { "DPoP" } else { "Bearer" }.to_string()
+}
+
+/// 访问授权服务器的HTTP客户端配置，信任额外配置的CA证书
+fn http_client_builder(config: &OAuthConfig) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder().use_rustls_tls();
+
+    if let Some(ca_path) = &config.ca_cert_path {
+        let pem = std::fs::read(ca_path)
+            .map_err(|e| anyhow!("无法读取CA证书 '{}': {}", ca_path, e))?;
+        let certificate = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| anyhow!("无效的CA证书 '{}': {}", ca_path, e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    Ok(builder)
+}
+
+/// 读取mTLS客户端证书和私钥
+fn load_client_identity(cert: &ClientCertConfig) -> Result<reqwest::Identity> {
+    let mut pem = std::fs::read(&cert.key_path)
+        .map_err(|e| anyhow!("无法读取mTLS客户端私钥 '{}': {}", cert.key_path, e))?;
+    pem.push(b'\n');
+    pem.extend(
+        std::fs::read(&cert.cert_path)
+            .map_err(|e| anyhow!("无法读取mTLS客户端证书 '{}': {}", cert.cert_path, e))?,
+    );
+
+    reqwest::Identity::from_pem(&pem)
+        .map_err(|e| anyhow!("无效的mTLS客户端证书或私钥 '{}': {}", cert.cert_path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::test_support::spawn_server;
+    use base64::{engine::general_purpose, Engine as _};
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+        SanType,
+    };
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls;
+
+    fn oauth_state() -> OAuthState {
+        OAuthState::new(None, Duration::minutes(30), FlowMetadata::default())
+    }
+
+    /// 测试用PKI：CA、服务端证书和客户端证书
+    struct TestPki {
+        dir: PathBuf,
+        ca: Certificate,
+        server_cert_der: Vec<u8>,
+        server_key_der: Vec<u8>,
+        ca_der: Vec<u8>,
+    }
+
+    impl TestPki {
+        fn generate() -> Self {
+            let dir = std::env::temp_dir().join(format!("oauth-mtls-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut ca_params = CertificateParams::new(vec![]);
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            ca_params.distinguished_name.push(DnType::CommonName, "test ca");
+            let ca = Certificate::from_params(ca_params).unwrap();
+
+            let mut server_params = CertificateParams::new(vec!["localhost".to_string()]);
+            server_params
+                .subject_alt_names
+                .push(SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+            server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+            let server = Certificate::from_params(server_params).unwrap();
+
+            std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+            Self {
+                server_cert_der: server.serialize_der_with_signer(&ca).unwrap(),
+                server_key_der: server.serialize_private_key_der(),
+                ca_der: ca.serialize_der().unwrap(),
+                ca,
+                dir,
+            }
+        }
+
+        fn path(&self, name: &str) -> Option<String> {
+            Some(self.dir.join(name).to_string_lossy().into_owned())
+        }
+
+        /// 签发CN为 `name` 的客户端证书
+        fn client_cert(&self, name: &str) -> ClientCertConfig {
+            let mut client_params = CertificateParams::new(vec![name.to_string()]);
+            client_params.distinguished_name.push(DnType::CommonName, name);
+            client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let client = Certificate::from_params(client_params).unwrap();
+
+            let cert_path = self.dir.join(format!("{}.pem", name));
+            let key_path = self.dir.join(format!("{}.key", name));
+            std::fs::write(&cert_path, client.serialize_pem_with_signer(&self.ca).unwrap()).unwrap();
+            std::fs::write(&key_path, client.serialize_private_key_pem()).unwrap();
+            ClientCertConfig {
+                cert_path: cert_path.to_string_lossy().into_owned(),
+                key_path: key_path.to_string_lossy().into_owned(),
+            }
+        }
+
+        /// 传入tenant地址时为它配置客户端证书，并把该tenant加入受信任列表
+        fn oauth_config(&self, trusted_tenant: Option<SocketAddr>) -> OAuthConfig {
+            let mut config = AppConfig::default().oauth;
+            config.ca_cert_path = self.path("ca.pem");
+            if let Some(addr) = trusted_tenant {
+                let tenant_url = format!("https://{}/", addr);
+                config.client_certs.insert(tenant_url.clone(), self.client_cert("oauth-client"));
+                config.trusted_tenants = vec![tenant_url];
+            }
+            config
+        }
+
+        /// 启动要求客户端证书的本地TLS服务器，`route` 根据请求路径返回状态码和响应体
+        async fn spawn_server(&self, route: fn(&str, SocketAddr) -> (u16, String)) -> SocketAddr {
+            self.spawn_server_for_client(None, route).await
+        }
+
+        /// 同上，指定 `client` 时只接受该CN的客户端证书，其他证书返回 invalid_client
+        async fn spawn_server_for_client(
+            &self,
+            client: Option<&'static str>,
+            route: fn(&str, SocketAddr) -> (u16, String),
+        ) -> SocketAddr {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(&rustls::Certificate(self.ca_der.clone())).unwrap();
+            let verifier = rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed();
+
+            let tls_config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(
+                    vec![rustls::Certificate(self.server_cert_der.clone())],
+                    rustls::PrivateKey(self.server_key_der.clone()),
+                )
+                .unwrap();
+            let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let acceptor = acceptor.clone();
+                    tokio::spawn(async move {
+                        let Ok(mut stream) = acceptor.accept(stream).await else {
+                            return;
+                        };
+                        let path = read_request_path(&mut stream).await;
+                        let presented = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .map(|cert| cert.0.clone())
+                            .unwrap_or_default();
+                        let expected_client = client.is_none_or(|name| {
+                            presented.windows(name.len()).any(|window| window == name.as_bytes())
+                        });
+                        let (status, body) = if expected_client {
+                            route(&path, addr)
+                        } else {
+                            (400, r#"{"error":"invalid_client"}"#.to_string())
+                        };
+                        let response = format!(
+                            "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body
+                        );
+                        let _ = stream.write_all(response.as_bytes()).await;
+                        let _ = stream.shutdown().await;
+                    });
+                }
+            });
+
+            addr
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// 读取HTTP请求（含请求体）并返回请求路径
+    async fn read_request_path<S: AsyncReadExt + Unpin>(stream: &mut S) -> String {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        let header_end = loop {
+            let n = stream.read(&mut chunk).await.unwrap_or(0);
+            if n == 0 {
+                return String::new();
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+        let content_length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        while buf.len() < header_end + content_length {
+            let n = stream.read(&mut chunk).await.unwrap_or(0);
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+
+        head.split_whitespace().nth(1).unwrap_or_default().to_string()
+    }
+
+    fn default_token_route(path: &str, _addr: SocketAddr) -> (u16, String) {
+        match path {
+            "/token" => (200, r#"{"access_token":"mtls-token"}"#.to_string()),
+            _ => (404, "{}".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_with_client_certificate() {
+        let pki = TestPki::generate();
+        let addr = pki.spawn_server(default_token_route).await;
+        let service = OAuthService::new(pki.oauth_config(Some(addr))).unwrap();
+
+        let token = service
+            .exchange_token(&format!("https://{}/", addr), &oauth_state(), "code")
+            .await
+            .unwrap();
+        assert_eq!(token.access_token.as_str(), "mtls-token");
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_without_client_certificate_is_rejected() {
+        let pki = TestPki::generate();
+        let addr = pki.spawn_server(default_token_route).await;
+        let service = OAuthService::new(pki.oauth_config(None)).unwrap();
+
+        let result = service
+            .exchange_token(&format!("https://{}/", addr), &oauth_state(), "code")
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_uses_mtls_endpoint_alias() {
+        let pki = TestPki::generate();
+        let addr = pki
+            .spawn_server(|path, addr| match path {
+                "/.well-known/oauth-authorization-server" => (
+                    200,
+                    serde_json::json!({
+                        "token_endpoint": format!("https://{}/token", addr),
+                        "mtls_endpoint_aliases": {
+                            "token_endpoint": format!("https://{}/mtls/token", addr)
+                        }
+                    })
+                    .to_string(),
+                ),
+                "/mtls/token" => (200, r#"{"access_token":"alias-token"}"#.to_string()),
+                _ => (400, r#"{"error":"invalid_client"}"#.to_string()),
+            })
+            .await;
+        let service = OAuthService::new(pki.oauth_config(Some(addr))).unwrap();
+
+        let token = service
+            .exchange_token(&format!("https://{}", addr), &oauth_state(), "code")
+            .await
+            .unwrap();
+        assert_eq!(token.access_token.as_str(), "alias-token");
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_is_not_presented_to_untrusted_tenant() {
+        let pki = TestPki::generate();
+        let addr = pki.spawn_server(default_token_route).await;
+        let mut config = pki.oauth_config(None);
+        config
+            .client_certs
+            .insert("https://tenant.example".to_string(), pki.client_cert("oauth-client"));
+        config.trusted_tenants = vec!["https://tenant.example".to_string()];
+        let service = OAuthService::new(config).unwrap();
+
+        let result = service
+            .exchange_token(&format!("https://{}/", addr), &oauth_state(), "code")
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_mtls_requires_trusted_tenants() {
+        let pki = TestPki::generate();
+        let mut config = pki.oauth_config(None);
+        config
+            .client_certs
+            .insert("https://tenant.example".to_string(), pki.client_cert("oauth-client"));
+        assert!(OAuthService::new(config.clone()).is_err());
+
+        config.trusted_tenants = vec!["https://other.example".to_string()];
+        assert!(OAuthService::new(config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_each_tenant_gets_its_own_client_certificate() {
+        let pki = TestPki::generate();
+        let tenant_a = pki.spawn_server_for_client(Some("client-a"), default_token_route).await;
+        let tenant_b = pki.spawn_server_for_client(Some("client-b"), default_token_route).await;
+        let (url_a, url_b) = (format!("https://{}/", tenant_a), format!("https://{}", tenant_b));
+
+        let service_with = |cert_a: &str, cert_b: &str| {
+            let mut config = pki.oauth_config(None);
+            config.trusted_tenants = vec![url_a.clone(), url_b.clone()];
+            config.client_certs.insert(url_a.clone(), pki.client_cert(cert_a));
+            config.client_certs.insert(url_b.clone(), pki.client_cert(cert_b));
+            OAuthService::new(config).unwrap()
+        };
+
+        let service = service_with("client-a", "client-b");
+        for tenant_url in [&url_a, &url_b] {
+            let token = service.exchange_token(tenant_url, &oauth_state(), "code").await.unwrap();
+            assert_eq!(token.access_token.as_str(), "mtls-token");
+        }
+
+        // 证书配置对调后两个tenant都拒绝
+        let swapped = service_with("client-b", "client-a");
+        for tenant_url in [&url_a, &url_b] {
+            assert!(swapped.exchange_token(tenant_url, &oauth_state(), "code").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_metadata_discovery_is_not_cached() {
+        use axum::{http::StatusCode, routing::get, Json, Router};
+        use std::sync::atomic::AtomicUsize;
+
+        // 第一次请求失败，之后正常返回
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/.well-known/oauth-authorization-server",
+            get(move || async move {
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+                Ok(Json(serde_json::json!({ "issuer": "https://issuer.example" })))
+            }),
+        );
+        let tenant_url = format!("http://{}/", spawn_server(app).await);
+        let service = OAuthService::new(AppConfig::default().oauth).unwrap();
+
+        assert!(service.server_metadata(&tenant_url).await.issuer.is_none());
+        assert_eq!(
+            service.server_metadata(&tenant_url).await.issuer.as_deref(),
+            Some("https://issuer.example")
+        );
+    }
+
+    /// 启动要求DPoP nonce的本地token端点，返回地址和收到的DPoP证明
+    async fn spawn_dpop_server() -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>) {
+        use axum::{http::HeaderMap, http::StatusCode, routing::post, Json, Router};
+
+        let proofs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = proofs.clone();
+        let app = Router::new().route(
+            "/token",
+            post(move |headers: HeaderMap| {
+                let recorded = recorded.clone();
+                async move {
+                    let proof = headers
+                        .get("DPoP")
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    recorded.lock().unwrap().push(proof.clone());
+
+                    let claims: serde_json::Value = proof
+                        .split('.')
+                        .nth(1)
+                        .and_then(|c| general_purpose::URL_SAFE_NO_PAD.decode(c).ok())
+                        .and_then(|c| serde_json::from_slice(&c).ok())
+                        .unwrap_or_default();
+
+                    let nonce_header = [("DPoP-Nonce", "server-nonce")];
+                    if claims["nonce"] != "server-nonce" {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            nonce_header,
+                            Json(serde_json::json!({"error": "use_dpop_nonce"})),
+                        );
+                    }
+                    (
+                        StatusCode::OK,
+                        nonce_header,
+                        Json(serde_json::json!({
+                            "access_token": "dpop-token",
+                            "token_type": "DPoP",
+                            "expires_in": 3600,
+                            "refresh_token": "refresh-1"
+                        })),
+                    )
+                }
+            }),
+        );
+
+        (spawn_server(app).await, proofs)
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_with_dpop_retries_with_nonce() {
+        let (addr, proofs) = spawn_dpop_server().await;
+        let mut config = AppConfig::default().oauth;
+        config.dpop_enabled = true;
+        let service = OAuthService::new(config).unwrap();
+
+        let record = service
+            .exchange_token(&format!("http://{}/", addr), &oauth_state(), "code")
+            .await
+            .unwrap();
+
+        assert_eq!(record.access_token.as_str(), "dpop-token");
+        assert_eq!(record.token_type, "DPoP");
+        assert_eq!(record.refresh_token.as_ref().unwrap().as_str(), "refresh-1");
+        assert!(record.expires_at.is_some());
+        assert!(record.dpop_key.as_ref().unwrap().expose()["d"].is_string());
+        assert_eq!(proofs.lock().unwrap().len(), 2);
+
+        // 缓存的nonce直接用于下一次交换
+        service
+            .exchange_token(&format!("http://{}/", addr), &oauth_state(), "code")
+            .await
+            .unwrap();
+        assert_eq!(proofs.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_without_dpop_sends_no_proof() {
+        let (addr, proofs) = spawn_dpop_server().await;
+        let service = OAuthService::new(AppConfig::default().oauth).unwrap();
+
+        let result = service
+            .exchange_token(&format!("http://{}/", addr), &oauth_state(), "code")
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(proofs.lock().unwrap().as_slice(), [String::new()]);
+    }
+
+    /// 启动返回ID令牌的本地授权服务器，ID令牌使用给定的nonce
+    async fn spawn_oidc_server(nonce: String) -> SocketAddr {
+        use crate::oidc::tests::TestSigner;
+        use axum::{http::{header, HeaderMap}, routing::{get, post}, Json, Router};
+
+        fn issuer(headers: &HeaderMap) -> String {
+            format!("http://{}", headers[header::HOST].to_str().unwrap())
+        }
+
+        let signer = Arc::new(TestSigner::generate("key-1"));
+        let jwks = serde_json::json!({ "keys": [signer.jwk.clone()] });
+
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(|headers: HeaderMap| async move {
+                    let issuer = issuer(&headers);
+                    Json(serde_json::json!({
+                        "issuer": issuer,
+                        "jwks_uri": format!("{}/jwks", issuer),
+                    }))
+                }),
+            )
+            .route("/jwks", get(move || async move { Json(jwks) }))
+            .route(
+                "/token",
+                post(move |headers: HeaderMap| async move {
+                    let now = Utc::now().timestamp();
+                    let id_token = signer.sign(&serde_json::json!({
+                        "iss": issuer(&headers),
+                        "aud": "v",
+                        "sub": "user-1",
+                        "email": "user@example.com",
+                        "nonce": nonce,
+                        "iat": now,
+                        "exp": now + 300,
+                    }));
+                    Json(serde_json::json!({ "access_token": "oidc-token", "id_token": id_token }))
+                }),
+            );
+
+        spawn_server(app).await
+    }
+
+    /// 信任给定tenant的OAuth服务
+    fn trusting_service(addr: SocketAddr) -> OAuthService {
+        let mut config = AppConfig::default().oauth;
+        config.trusted_tenants = vec![format!("http://{}", addr)];
+        OAuthService::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_validates_id_token() {
+        let oauth_state = oauth_state();
+        let addr = spawn_oidc_server(oauth_state.nonce.expose().clone()).await;
+        let service = trusting_service(addr);
+
+        let record = service
+            .exchange_token(&format!("http://{}/", addr), &oauth_state, "code")
+            .await
+            .unwrap();
+
+        let identity = record.identity.unwrap();
+        assert_eq!(identity.sub, "user-1");
+        assert_eq!(identity.email.as_deref(), Some("user@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_tenant_id_token_yields_no_identity() {
+        let oauth_state = oauth_state();
+        let addr = spawn_oidc_server(oauth_state.nonce.expose().clone()).await;
+        let service = OAuthService::new(AppConfig::default().oauth).unwrap();
+
+        let record = service
+            .exchange_token(&format!("http://{}/", addr), &oauth_state, "code")
+            .await
+            .unwrap();
+
+        assert!(record.identity.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_token_rejects_id_token_for_other_flow() {
+        let addr = spawn_oidc_server("other-nonce".to_string()).await;
+        let service = trusting_service(addr);
+
+        let result = service
+            .exchange_token(&format!("http://{}/", addr), &oauth_state(), "code")
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_unreadable_client_key_is_rejected() {
+        let pki = TestPki::generate();
+        let addr: SocketAddr = "127.0.0.1:8443".parse().unwrap();
+        let mut config = pki.oauth_config(Some(addr));
+        for cert in config.client_certs.values_mut() {
+            cert.key_path = pki.path("missing.key").unwrap();
+        }
+
+        assert!(OAuthService::new(config).is_err());
+    }
+
+    #[test]
+    fn test_oauth_state_can_only_be_taken_once() {
+        let service = OAuthService::new(AppConfig::default().oauth).unwrap();
+        let oauth_state = service
+            .generate_auth_url(None, Duration::minutes(30), FlowMetadata::default())
+            .unwrap()
+            .oauth_state;
+
+        assert!(service.take_oauth_state(oauth_state.state.as_str()).is_ok());
+        assert!(service.take_oauth_state(oauth_state.state.as_str()).is_err());
+        assert_eq!(service.active_states_count(), 0);
+    }
+
+    #[test]
+    fn test_return_url_must_match_allowed_origin() {
+        let mut config = AppConfig::default().oauth;
+        config.return_url_allowed_origins = vec!["https://app.example.com".to_string()];
+        let service = OAuthService::new(config).unwrap();
+
+        assert!(service.validate_return_url("https://app.example.com/done?x=1").is_ok());
+        assert!(service.validate_return_url("https://app.example.com:8443/done").is_err());
+        assert!(service.validate_return_url("https://app.example.com.evil.test/").is_err());
+        assert!(service.validate_return_url("http://app.example.com/").is_err());
+        assert!(service.validate_return_url("javascript:alert(1)").is_err());
+        assert!(service.validate_return_url("/relative").is_err());
+    }
+
+    #[test]
+    fn test_state_ttl_is_bounded() {
+        let service = OAuthService::new(AppConfig::default().oauth).unwrap();
+
+        assert_eq!(service.state_ttl(None).unwrap(), Duration::minutes(30));
+        assert_eq!(service.state_ttl(Some(300)).unwrap(), Duration::seconds(300));
+        assert!(service.state_ttl(Some(0)).is_err());
+        assert!(service.state_ttl(Some(120 * 60 + 1)).is_err());
+    }
+
+    #[test]
+    fn test_short_ttl_state_expires() {
+        let service = OAuthService::new(AppConfig::default().oauth).unwrap();
+        let oauth_state = service
+            .generate_auth_url(None, Duration::seconds(-1), FlowMetadata::default())
+            .unwrap()
+            .oauth_state;
+
+        assert!(service.take_oauth_state(oauth_state.state.as_str()).is_err());
+        assert_eq!(service.cleanup_expired_states().len(), 1);
+    }
+
+    fn capped_service(policy: StateOverflowPolicy) -> OAuthService {
+        let mut config = AppConfig::default().oauth;
+        config.max_pending_states = 3;
+        config.max_pending_states_per_user = 2;
+        config.state_overflow_policy = policy;
+        OAuthService::new(config).unwrap()
+    }
+
+    fn generate(service: &OAuthService, user_id: &str) -> Result<GeneratedAuthUrl> {
+        service.generate_auth_url(
+            Some(user_id.to_string()),
+            Duration::minutes(30),
+            FlowMetadata::default(),
+        )
+    }
+
+    #[test]
+    fn test_pending_states_are_capped_with_reject_policy() {
+        let service = capped_service(StateOverflowPolicy::Reject);
+
+        generate(&service, "alice").unwrap();
+        generate(&service, "alice").unwrap();
+        let error = generate(&service, "alice").unwrap_err();
+        assert!(error.downcast_ref::<StateCapacityError>().is_some());
+
+        generate(&service, "bob").unwrap();
+        assert!(generate(&service, "carol").is_err());
+
+        let capacity = service.state_capacity();
+        assert_eq!(capacity.pending, 3);
+        assert_eq!(capacity.utilization, 1.0);
+        assert_eq!(capacity.rejected_total, 2);
+        assert_eq!(capacity.evicted_total, 0);
+    }
+
+    #[test]
+    fn test_pending_states_evict_oldest() {
+        let service = capped_service(StateOverflowPolicy::EvictOldest);
+
+        let first = generate(&service, "alice").unwrap().oauth_state;
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        generate(&service, "alice").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        // 单用户上限只淘汰该用户自己的状态
+        let generated = generate(&service, "alice").unwrap();
+        assert_eq!(generated.evicted.len(), 1);
+        assert_eq!(generated.evicted[0].state, first.state);
+        assert!(service.take_oauth_state(first.state.as_str()).is_err());
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let bob = generate(&service, "bob").unwrap();
+        assert!(bob.evicted.is_empty());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let carol = generate(&service, "carol").unwrap();
+        assert_eq!(carol.evicted.len(), 1);
+        assert_eq!(carol.evicted[0].user_id.as_deref(), Some("alice"));
+
+        let capacity = service.state_capacity();
+        assert_eq!(capacity.pending, 3);
+        assert_eq!(capacity.evicted_total, 2);
+    }
+    #[test]
+    fn test_pending_index_follows_state_changes() {
+        let service = capped_service(StateOverflowPolicy::Reject);
+
+        let first = generate(&service, "alice").unwrap().oauth_state;
+        let second = generate(&service, "alice").unwrap().oauth_state;
+        assert!(generate(&service, "alice").is_err());
+
+        // 取消、取出后释放该用户的名额，放回后重新占用
+        let cancelled = service.cancel_oauth_state(first.state.as_str()).unwrap();
+        generate(&service, "alice").unwrap();
+        assert!(generate(&service, "alice").is_err());
+        service.take_oauth_state(second.state.as_str()).unwrap();
+        service.restore_oauth_state(cancelled);
+        assert!(generate(&service, "alice").is_err());
+
+        let expired = service
+            .generate_auth_url(Some("bob".to_string()), Duration::seconds(-1), FlowMetadata::default())
+            .unwrap();
+        assert!(expired.evicted.is_empty());
+        assert_eq!(service.cleanup_expired_states().len(), 1);
+
+        let pending = service.lock_pending();
+        assert_eq!(pending.all.len(), service.active_states_count());
+        assert_eq!(pending.user_count("alice"), 2);
+        assert!(!pending.by_user.contains_key("bob"));
     }
 }
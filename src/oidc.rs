@@ -0,0 +1,374 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{models::IdentityClaims, telemetry};
+
+/// 允许的JWT签名算法（不接受对称算法）
+pub const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// 校验ID令牌时的期望值
+pub struct IdTokenExpectations<'a> {
+    pub issuer: &'a str,
+    pub audience: &'a str,
+    pub nonce: &'a str,
+    pub jwks_uri: &'a str,
+}
+
+/// ID令牌中校验和提取的声明
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    iat: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+/// 因未知kid重新拉取同一JWKS的最小间隔（秒）
+const MIN_JWKS_REFETCH_SECONDS: i64 = 30;
+
+/// 最多缓存的JWKS数量
+const MAX_CACHED_JWKS: usize = 100;
+
+/// 缓存的JWKS
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: DateTime<Utc>,
+    /// 最近一次向jwks_uri发起请求的时间
+    checked_at: DateTime<Utc>,
+}
+
+/// 按jwks_uri缓存的签名密钥
+pub struct JwksCache {
+    http_client: reqwest::Client,
+    /// JWKS缓存时间
+    ttl: Duration,
+    cache: DashMap<String, CachedJwks>,
+}
+
+impl JwksCache {
+    pub fn new(http_client: reqwest::Client, cache_seconds: u64) -> Self {
+        Self {
+            http_client,
+            ttl: Duration::seconds(cache_seconds as i64),
+            cache: DashMap::new(),
+        }
+    }
+
+    /// 查找签名密钥，缓存中找不到kid时重新拉取JWKS以支持密钥轮换
+    ///
+    /// 同一jwks_uri因未知kid重新拉取的间隔不少于 `MIN_JWKS_REFETCH_SECONDS`，
+    /// 携带随机kid的令牌不能让每个请求都访问JWKS端点。
+    pub async fn decoding_key(&self, jwks_uri: &str, kid: Option<&str>) -> Result<DecodingKey> {
+        let now = Utc::now();
+        if let Some(mut entry) = self.cache.get_mut(jwks_uri) {
+            if now - entry.fetched_at < self.ttl {
+                if let Some(key) = find_key(&entry.keys, kid) {
+                    return key;
+                }
+                if now - entry.checked_at < Duration::seconds(MIN_JWKS_REFETCH_SECONDS) {
+                    return Err(anyhow!("JWKS中未找到签名密钥: {:?}", kid));
+                }
+                // 先记录本次请求时间，并发的未知kid请求不再重复拉取
+                entry.checked_at = now;
+            }
+        }
+
+        let keys = self.fetch(jwks_uri).await?;
+        find_key(&keys, kid).unwrap_or_else(|| Err(anyhow!("JWKS中未找到签名密钥: {:?}", kid)))
+    }
+
+    async fn fetch(&self, jwks_uri: &str) -> Result<JwkSet> {
+        debug!("获取JWKS: {}", jwks_uri);
+
+        let response = telemetry::inject(self.http_client.get(jwks_uri)).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("JWKS请求返回 {}", response.status()));
+        }
+        let keys: JwkSet = response.json().await?;
+
+        self.cache_keys(jwks_uri, &keys);
+        Ok(keys)
+    }
+
+    /// 缓存JWKS，缓存已满时先清理过期条目，仍然已满则不缓存
+    fn cache_keys(&self, jwks_uri: &str, keys: &JwkSet) {
+        if self.cache.len() >= MAX_CACHED_JWKS && !self.cache.contains_key(jwks_uri) {
+            self.cache.retain(|_, cached| Utc::now() - cached.fetched_at < self.ttl);
+            if self.cache.len() >= MAX_CACHED_JWKS {
+                return;
+            }
+        }
+
+        let now = Utc::now();
+        self.cache.insert(
+            jwks_uri.to_string(),
+            CachedJwks {
+                keys: keys.clone(),
+                fetched_at: now,
+                checked_at: now,
+            },
+        );
+    }
+}
+
+/// OIDC ID令牌校验器
+pub struct IdTokenValidator {
+    /// 允许的时钟偏差（秒）
+    clock_skew_seconds: u64,
+    jwks: JwksCache,
+}
+
+impl IdTokenValidator {
+    pub fn new(http_client: reqwest::Client, clock_skew_seconds: u64, jwks_cache_seconds: u64) -> Self {
+        Self {
+            clock_skew_seconds,
+            jwks: JwksCache::new(http_client, jwks_cache_seconds),
+        }
+    }
+
+    /// 校验ID令牌的签名、iss、aud、exp、iat和nonce，返回身份声明
+    pub async fn validate(
+        &self,
+        id_token: &str,
+        expected: &IdTokenExpectations<'_>,
+    ) -> Result<IdentityClaims> {
+        let header = decode_header(id_token).map_err(|e| anyhow!("无效的ID令牌: {}", e))?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(anyhow!("不支持的ID令牌签名算法: {:?}", header.alg));
+        }
+
+        let decoding_key = self
+            .jwks
+            .decoding_key(expected.jwks_uri, header.kid.as_deref())
+            .await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.clock_skew_seconds;
+        validation.set_issuer(&[expected.issuer]);
+        validation.set_audience(&[expected.audience]);
+        validation.set_required_spec_claims(&["exp", "iat", "iss", "aud", "sub"]);
+
+        let claims = decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+            .map_err(|e| anyhow!("ID令牌校验失败: {}", e))?
+            .claims;
+
+        if claims.iat > Utc::now().timestamp() + self.clock_skew_seconds as i64 {
+            return Err(anyhow!("ID令牌校验失败: iat 晚于当前时间"));
+        }
+
+        if claims.nonce.as_deref() != Some(expected.nonce) {
+            return Err(anyhow!("ID令牌校验失败: nonce 不匹配"));
+        }
+
+        Ok(IdentityClaims {
+            sub: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+        })
+    }
+}
+
+/// 按kid查找密钥；令牌未携带kid时仅在JWKS只有一把密钥时使用它
+pub fn find_key(keys: &JwkSet, kid: Option<&str>) -> Option<Result<DecodingKey>> {
+    let jwk = match kid {
+        Some(kid) => keys.find(kid)?,
+        None if keys.keys.len() == 1 => &keys.keys[0],
+        None => return None,
+    };
+
+    Some(DecodingKey::from_jwk(jwk).map_err(|e| anyhow!("无效的JWK: {}", e)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use base64::{engine::general_purpose, Engine as _};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use crate::test_support::spawn_server;
+    use std::net::SocketAddr;
+
+    /// 测试用的ES256签名密钥
+    pub(crate) struct TestSigner {
+        encoding_key: EncodingKey,
+        pub(crate) jwk: Value,
+    }
+
+    impl TestSigner {
+        pub(crate) fn generate(kid: &str) -> Self {
+            let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+            let public = key_pair.public_key_raw();
+            let encoding_key = EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+
+            Self {
+                encoding_key,
+                jwk: json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "alg": "ES256",
+                    "use": "sig",
+                    "kid": kid,
+                    "x": general_purpose::URL_SAFE_NO_PAD.encode(&public[1..33]),
+                    "y": general_purpose::URL_SAFE_NO_PAD.encode(&public[33..65]),
+                }),
+            }
+        }
+
+        pub(crate) fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = self.jwk["kid"].as_str().map(str::to_string);
+            encode(&header, claims, &self.encoding_key).unwrap()
+        }
+    }
+
+    /// 启动提供JWKS的本地服务器
+    pub(crate) async fn spawn_jwks_server(jwks: Value) -> SocketAddr {
+        use axum::{routing::get, Json, Router};
+
+        let app = Router::new().route("/jwks", get(move || async move { Json(jwks) }));
+        spawn_server(app).await
+    }
+
+    fn claims(now: i64) -> Value {
+        json!({
+            "iss": "https://issuer.example",
+            "aud": "v",
+            "sub": "user-1",
+            "email": "user@example.com",
+            "email_verified": true,
+            "nonce": "nonce-1",
+            "iat": now,
+            "exp": now + 300,
+        })
+    }
+
+    async fn validate(signer: &TestSigner, claims: &Value) -> Result<IdentityClaims> {
+        let addr = spawn_jwks_server(json!({ "keys": [signer.jwk.clone()] })).await;
+        let validator = IdTokenValidator::new(reqwest::Client::new(), 60, 3600);
+        let jwks_uri = format!("http://{}/jwks", addr);
+
+        validator
+            .validate(
+                &signer.sign(claims),
+                &IdTokenExpectations {
+                    issuer: "https://issuer.example",
+                    audience: "v",
+                    nonce: "nonce-1",
+                    jwks_uri: &jwks_uri,
+                },
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_valid_id_token() {
+        let signer = TestSigner::generate("key-1");
+        let identity = validate(&signer, &claims(Utc::now().timestamp())).await.unwrap();
+
+        assert_eq!(identity.sub, "user-1");
+        assert_eq!(identity.email.as_deref(), Some("user@example.com"));
+        assert_eq!(identity.email_verified, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_wrong_claims() {
+        let signer = TestSigner::generate("key-1");
+        let now = Utc::now().timestamp();
+
+        let cases = [
+            ("iss", json!("https://evil.example")),
+            ("aud", json!("other-client")),
+            ("nonce", json!("nonce-2")),
+            ("exp", json!(now - 120)),
+            ("iat", json!(now + 120)),
+        ];
+        for (claim, value) in cases {
+            let mut claims = claims(now);
+            claims[claim] = value;
+            assert!(validate(&signer, &claims).await.is_err(), "{} 应校验失败", claim);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accepts_expiry_within_clock_skew() {
+        let signer = TestSigner::generate("key-1");
+        let now = Utc::now().timestamp();
+        let mut claims = claims(now);
+        claims["exp"] = json!(now - 30);
+
+        assert!(validate(&signer, &claims).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_signature_from_unknown_key() {
+        let trusted = TestSigner::generate("key-1");
+        let attacker = TestSigner::generate("key-1");
+        let addr = spawn_jwks_server(json!({ "keys": [trusted.jwk.clone()] })).await;
+        let validator = IdTokenValidator::new(reqwest::Client::new(), 60, 3600);
+        let jwks_uri = format!("http://{}/jwks", addr);
+
+        let result = validator
+            .validate(
+                &attacker.sign(&claims(Utc::now().timestamp())),
+                &IdTokenExpectations {
+                    issuer: "https://issuer.example",
+                    audience: "v",
+                    nonce: "nonce-1",
+                    jwks_uri: &jwks_uri,
+                },
+            )
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_refetch_is_throttled() {
+        use axum::{routing::get, Json, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let signer = TestSigner::generate("key-1");
+        let jwks = json!({ "keys": [signer.jwk.clone()] });
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let app = Router::new().route(
+            "/jwks",
+            get(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let jwks = jwks.clone();
+                async move { Json(jwks) }
+            }),
+        );
+        let jwks_uri = format!("http://{}/jwks", spawn_server(app).await);
+        let cache = JwksCache::new(reqwest::Client::new(), 3600);
+
+        assert!(cache.decoding_key(&jwks_uri, Some("key-1")).await.is_ok());
+        for kid in ["random-1", "random-2", "random-3"] {
+            assert!(cache.decoding_key(&jwks_uri, Some(kid)).await.is_err());
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // 超过最小间隔后允许再拉取一次
+        cache.cache.get_mut(&jwks_uri).unwrap().checked_at -=
+            Duration::seconds(MIN_JWKS_REFETCH_SECONDS);
+        assert!(cache.decoding_key(&jwks_uri, Some("random-4")).await.is_err());
+        assert!(cache.decoding_key(&jwks_uri, Some("random-5")).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,449 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, Method},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::auth::Principal;
+use crate::config::{RateLimitConfig, RouteLimit};
+use crate::middleware::{bad_request, too_many_requests};
+use crate::AppState;
+
+/// 读取 `POST /api/auth-url` 请求体以获取 user_id 时的大小上限
+const MAX_BUFFERED_BODY_BYTES: usize = 64 * 1024;
+
+/// 令牌桶状态的存储后端
+///
+/// 与OAuth状态一样保存在进程内存中；多实例部署时可以实现该trait接入共享存储，
+/// 所有实例共用同一组令牌桶。
+pub trait RateLimitStore: Send + Sync {
+    /// 从 `keys` 对应的每个令牌桶各取出一个令牌
+    ///
+    /// 必须先检查所有桶再统一扣减：任一桶为空时不扣减任何桶，返回需要等待的秒数。
+    fn take(&self, keys: &[String], limit: RouteLimit, now: DateTime<Utc>) -> Result<(), u64>;
+
+    /// 清理已经回满的令牌桶
+    fn prune(&self, now: DateTime<Utc>);
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+    limit: RouteLimit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: DateTime<Utc>) {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * refill_rate(self.limit)).min(self.limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+/// 每秒补充的令牌数
+fn refill_rate(limit: RouteLimit) -> f64 {
+    limit.per_minute as f64 / 60.0
+}
+
+/// 进程内存中的令牌桶
+///
+/// 同一个请求的多个桶在一把锁内检查和扣减，并发请求不会只扣减其中一部分。
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryRateLimitStore {
+    fn lock_buckets(&self) -> std::sync::MutexGuard<'_, HashMap<String, Bucket>> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn take(&self, keys: &[String], limit: RouteLimit, now: DateTime<Utc>) -> Result<(), u64> {
+        let mut buckets = self.lock_buckets();
+
+        let mut wait: f64 = 0.0;
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.limit = limit;
+                bucket.refill(now);
+                if bucket.tokens < 1.0 {
+                    wait = wait.max((1.0 - bucket.tokens) / refill_rate(limit));
+                }
+            }
+        }
+        if wait > 0.0 {
+            return Err((wait.ceil() as u64).max(1));
+        }
+
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket {
+                tokens: limit.burst as f64,
+                updated_at: now,
+                limit,
+            });
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    fn prune(&self, now: DateTime<Utc>) {
+        self.lock_buckets().retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.limit.burst as f64
+        });
+    }
+}
+
+/// 受信任的代理网段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TrustedProxy {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl TrustedProxy {
+    fn parse(value: &str) -> Result<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("无效的受信任代理地址 '{}': {}", value, e))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| anyhow!("无效的受信任代理网段 '{}'", value))?,
+            None => max_prefix,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// 请求限流
+///
+/// 获取授权链接和完成授权分别按客户端IP、已认证的调用方和调用方传入的 user_id 使用独立的令牌桶，
+/// 任何一个桶为空时拒绝请求，且不扣减其他桶。
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    trusted_proxies: Vec<TrustedProxy>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Result<Self> {
+        let trusted_proxies = config
+            .trusted_proxies
+            .iter()
+            .map(|proxy| TrustedProxy::parse(proxy))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            config,
+            trusted_proxies,
+            store,
+        })
+    }
+
+    /// 路由对应的限制，不受限的路由返回 None
+    fn route_limit(&self, path: &str) -> Option<(&'static str, RouteLimit)> {
+        if !self.config.enabled {
+            return None;
+        }
+        let (route, limit) = match path {
+            "/api/auth-url" => ("auth_url", self.config.auth_url),
+            "/api/complete-auth" | "/api/oauth/callback" => {
+                ("complete_auth", self.config.complete_auth)
+            }
+            _ => return None,
+        };
+        (limit.per_minute > 0 && limit.burst > 0).then_some((route, limit))
+    }
+
+    /// 识别客户端IP：直连地址是受信任的代理时，取 X-Forwarded-For 中最后一个不受信任的地址
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|proxy| proxy.contains(ip))
+    }
+
+    /// 检查并扣减各个令牌桶，返回需要等待的秒数
+    fn check(&self, route: &str, limit: RouteLimit, identities: &[String]) -> Result<(), u64> {
+        let keys: Vec<String> = identities
+            .iter()
+            .map(|identity| format!("{}:{}", route, identity))
+            .collect();
+        self.store.take(&keys, limit, Utc::now())
+    }
+
+    pub fn prune(&self) {
+        self.store.prune(Utc::now());
+    }
+}
+
+/// 限流中间件
+///
+/// 在调用方认证之后运行：调用方和用户维度的桶只按认证通过的调用方建立，
+/// 随意构造的凭据和 user_id 不会创建新桶，也不能耗尽其他调用方的额度。
+pub async fn enforce(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limiter = &state.rate_limiter;
+    let Some((route, limit)) = limiter.route_limit(request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    let mut identities = Vec::new();
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        identities.push(format!("ip:{}", limiter.client_ip(peer.ip(), request.headers())));
+    }
+
+    let principal = request.extensions().get::<Principal>().cloned();
+    let request = match principal {
+        Some(principal) => {
+            // JWT调用方的流程固定绑定到sub，已按调用方计数；API密钥调用方再按其代理的用户细分
+            if let Some(subject) = &principal.subject {
+                identities.push(format!("sub:{}", subject));
+                request
+            } else {
+                identities.push(format!("key:{}", principal.name));
+                let (request, user_id) = match extract_user_id(request).await {
+                    Ok(extracted) => extracted,
+                    Err(response) => return *response,
+                };
+                if let Some(user_id) = user_id {
+                    identities.push(format!("user:{}:{}", principal.name, user_id));
+                }
+                request
+            }
+        }
+        None => request,
+    };
+
+    match limiter.check(route, limit, &identities) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            warn!("请求过于频繁, 路由: {}, 标识: {:?}", route, identities);
+            too_many_requests(
+                format!("请求过于频繁，请在 {} 秒后重试", retry_after),
+                retry_after,
+            )
+        }
+    }
+}
+
+/// 从查询参数或JSON请求体中取出 user_id，读取过的请求体会原样放回
+async fn extract_user_id(request: Request) -> Result<(Request, Option<String>), Box<Response>> {
+    let from_query = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "user_id")
+            .map(|(_, value)| value.into_owned())
+    });
+    if from_query.is_some() || request.method() != Method::POST {
+        return Ok((request, from_query.filter(|user_id| !user_id.is_empty())));
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_BUFFERED_BODY_BYTES)
+        .await
+        .map_err(|_| Box::new(bad_request("请求体过大".to_string())))?;
+    let user_id = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|body| body.get("user_id")?.as_str().map(str::to_string))
+        .filter(|user_id| !user_id.is_empty());
+    Ok((Request::from_parts(parts, Body::from(bytes)), user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn limiter(trusted_proxies: &[&str]) -> RateLimiter {
+        let mut config = crate::config::AppConfig::default().rate_limit;
+        config.trusted_proxies = trusted_proxies.iter().map(|proxy| proxy.to_string()).collect();
+        RateLimiter::new(config, Arc::new(MemoryRateLimitStore::default())).unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket_refills_over_time() {
+        let store = MemoryRateLimitStore::default();
+        let limit = RouteLimit {
+            per_minute: 60,
+            burst: 2,
+        };
+        let now = Utc::now();
+        let ip1 = keys(&["ip:1"]);
+
+        assert!(store.take(&ip1, limit, now).is_ok());
+        assert!(store.take(&ip1, limit, now).is_ok());
+        assert_eq!(store.take(&ip1, limit, now), Err(1));
+        // 其他标识使用独立的桶
+        assert!(store.take(&keys(&["ip:2"]), limit, now).is_ok());
+
+        assert!(store.take(&ip1, limit, now + Duration::seconds(1)).is_ok());
+        assert!(store.take(&ip1, limit, now + Duration::seconds(1)).is_err());
+
+        store.prune(now + Duration::seconds(60));
+        assert!(store.lock_buckets().is_empty());
+    }
+
+    #[test]
+    fn test_rejected_request_consumes_no_tokens() {
+        let store = MemoryRateLimitStore::default();
+        let limit = RouteLimit {
+            per_minute: 1,
+            burst: 1,
+        };
+        let now = Utc::now();
+
+        assert!(store.take(&keys(&["user:u"]), limit, now).is_ok());
+        // user桶已空，ip桶不应被扣减
+        assert!(store.take(&keys(&["ip:1", "user:u"]), limit, now).is_err());
+        assert!(store.take(&keys(&["ip:1"]), limit, now).is_ok());
+    }
+
+    #[test]
+    fn test_retry_after_reflects_refill_rate() {
+        let store = MemoryRateLimitStore::default();
+        let limit = RouteLimit {
+            per_minute: 6,
+            burst: 1,
+        };
+        let now = Utc::now();
+        let user = keys(&["user:u"]);
+
+        assert!(store.take(&user, limit, now).is_ok());
+        assert_eq!(store.take(&user, limit, now), Err(10));
+        assert_eq!(store.take(&user, limit, now + Duration::seconds(4)), Err(6));
+    }
+
+    #[test]
+    fn test_client_ip_honours_trusted_proxies_only() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 198.51.100.7, 10.0.0.2".parse().unwrap());
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // 不受信任的直连地址不能伪造客户端IP
+        assert_eq!(limiter(&[]).client_ip(proxy, &headers), proxy);
+
+        // 跳过受信任的代理，取最近一个外部地址
+        let expected: IpAddr = "198.51.100.7".parse().unwrap();
+        assert_eq!(limiter(&["10.0.0.0/8"]).client_ip(proxy, &headers), expected);
+    }
+
+    #[test]
+    fn test_trusted_proxy_parsing() {
+        let network = TrustedProxy::parse("192.168.0.0/16").unwrap();
+        assert!(network.contains("192.168.10.1".parse().unwrap()));
+        assert!(!network.contains("192.169.0.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+
+        let single = TrustedProxy::parse("::1").unwrap();
+        assert!(single.contains("::1".parse().unwrap()));
+
+        assert!(TrustedProxy::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxy::parse("proxy.internal").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_bucket_returns_429_with_retry_after() {
+        use axum::{http::StatusCode, routing::post, Router};
+        use tower::Service;
+
+        let mut state = crate::handlers::tests::app_state();
+        let mut config = crate::config::AppConfig::default().rate_limit;
+        config.auth_url = RouteLimit {
+            per_minute: 1,
+            burst: 1,
+        };
+        state.rate_limiter =
+            Arc::new(RateLimiter::new(config, Arc::new(MemoryRateLimitStore::default())).unwrap());
+        let mut app = Router::new()
+            .route("/api/auth-url", post(|body: String| async move { body }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), enforce))
+            .with_state(state);
+
+        // 认证中间件在限流之前运行，这里直接附上认证结果
+        let request = |caller: &str, user_id: &str| {
+            let mut request = Request::post("/api/auth-url")
+                .body(Body::from(format!(r#"{{"user_id":"{}"}}"#, user_id)))
+                .unwrap();
+            request.extensions_mut().insert(Principal {
+                name: caller.to_string(),
+                scopes: Vec::new(),
+                subject: None,
+            });
+            request
+        };
+
+        let response = app.call(request("backend", "user-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // 读取过的请求体原样交给处理函数
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, r#"{"user_id":"user-1"}"#);
+
+        let response = app.call(request("backend", "user-1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "60");
+
+        // 用户维度的桶按调用方区分，其他调用方不能耗尽它
+        assert_eq!(app.call(request("other", "user-1")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_only_expensive_routes_are_limited() {
+        let limiter = limiter(&[]);
+        assert_eq!(limiter.route_limit("/api/auth-url").unwrap().0, "auth_url");
+        assert_eq!(limiter.route_limit("/api/oauth/callback").unwrap().0, "complete_auth");
+        assert!(limiter.route_limit("/health").is_none());
+    }
+}
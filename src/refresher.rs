@@ -0,0 +1,326 @@
+use chrono::{Duration, Utc};
+use dashmap::DashSet;
+use rand::Rng;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::config::RefreshConfig;
+use crate::models::TokenRecord;
+use crate::oauth::{OAuthService, TokenEndpointError};
+use crate::shutdown::Shutdown;
+use crate::token_store::TokenStore;
+use crate::webhook::{WebhookDispatcher, WebhookEventType};
+
+/// 单次刷新的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefreshOutcome {
+    Refreshed,
+    /// 刷新令牌已失效 (invalid_grant)，需要用户重新授权
+    NeedsReauth,
+    /// 暂时性失败，下次扫描时重试
+    Failed,
+}
+
+/// 令牌后台刷新任务
+///
+/// 定期扫描token记录，在过期前的刷新窗口内使用刷新令牌换取新令牌。
+pub struct TokenRefresher {
+    oauth_service: Arc<OAuthService>,
+    token_store: Arc<TokenStore>,
+    webhooks: Arc<WebhookDispatcher>,
+    config: RefreshConfig,
+    /// 限制同时进行的刷新请求数
+    semaphore: Arc<Semaphore>,
+    /// 正在刷新的token_id，避免同一记录被重复刷新
+    in_flight: DashSet<String>,
+}
+
+impl TokenRefresher {
+    pub fn new(
+        oauth_service: Arc<OAuthService>,
+        token_store: Arc<TokenStore>,
+        webhooks: Arc<WebhookDispatcher>,
+        config: RefreshConfig,
+    ) -> Self {
+        let semaphore = Arc::new(Semaphore::new(config.max_concurrency.max(1)));
+        Self {
+            oauth_service,
+            token_store,
+            webhooks,
+            config,
+            semaphore,
+            in_flight: DashSet::new(),
+        }
+    }
+
+    /// 启动后台刷新循环，收到停止通知后不再开始新的刷新，等待进行中的刷新结束再退出
+    pub fn spawn(self: Arc<Self>, shutdown: Shutdown) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs(self.config.interval_seconds.max(1)));
+            let mut refreshing = Vec::new();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.wait() => break,
+                }
+                refreshing.retain(|handle: &tokio::task::JoinHandle<RefreshOutcome>| !handle.is_finished());
+                refreshing.extend(self.clone().sweep());
+            }
+
+            // 还在等待抖动或并发许可的刷新不再发起
+            self.semaphore.close();
+            for handle in refreshing {
+                let _ = handle.await;
+            }
+        })
+    }
+
+    /// 扫描一次，为每条需要刷新的记录启动刷新任务
+    pub fn sweep(self: Arc<Self>) -> Vec<tokio::task::JoinHandle<RefreshOutcome>> {
+        let deadline = Utc::now() + Duration::seconds(self.config.window_seconds as i64);
+
+        self.token_store
+            .list()
+            .into_iter()
+            .filter(|record| is_due(record, deadline))
+            .filter(|record| self.in_flight.insert(record.id.clone()))
+            .map(|record| {
+                let refresher = self.clone();
+                tokio::spawn(async move {
+                    let jitter = match refresher.config.jitter_seconds {
+                        0 => 0,
+                        max => rand::thread_rng().gen_range(0..=max * 1000),
+                    };
+                    tokio::time::sleep(std::time::Duration::from_millis(jitter)).await;
+
+                    let outcome = match refresher.semaphore.clone().acquire_owned().await {
+                        Ok(_permit) => refresher.refresh(&record.id).await,
+                        Err(_) => RefreshOutcome::Failed,
+                    };
+                    refresher.in_flight.remove(&record.id);
+                    outcome
+                })
+            })
+            .collect()
+    }
+
+    /// 刷新一条记录并保存结果
+    async fn refresh(&self, id: &str) -> RefreshOutcome {
+        // 重新读取，确保使用最新的刷新令牌
+        let Some(record) = self.token_store.get(id) else {
+            return RefreshOutcome::Failed;
+        };
+
+        let result = self.oauth_service.refresh_token(&record).await;
+        let now = Utc::now();
+
+        match result {
+            Ok(refreshed) => {
+                self.token_store.update(id, |record| {
+                    record.access_token = refreshed.access_token;
+                    record.token_type = refreshed.token_type;
+                    record.refresh_token = refreshed.refresh_token;
+                    record.expires_at = refreshed.expires_at;
+                    record.last_refresh_at = Some(now);
+                    record.last_refresh_error = None;
+                });
+                info!("token刷新成功, token_id: {}, user_id: {:?}", id, record.user_id);
+                self.webhooks.emit(
+                    WebhookEventType::TokenRefreshed,
+                    serde_json::json!({
+                        "token_id": id,
+                        "user_id": record.user_id,
+                        "tenant_url": record.tenant_url,
+                        "expires_at": refreshed.expires_at,
+                    }),
+                );
+                RefreshOutcome::Refreshed
+            }
+            Err(e) => {
+                let invalid_grant = e
+                    .downcast_ref::<TokenEndpointError>()
+                    .is_some_and(|e| e.error.as_deref() == Some("invalid_grant"));
+
+                self.token_store.update(id, |record| {
+                    record.last_refresh_at = Some(now);
+                    record.last_refresh_error = Some(e.to_string());
+                    // 授权服务器拒绝了刷新令牌，与 token.revoked 事件一致地把令牌标记为已吊销
+                    if invalid_grant {
+                        record.revoked = true;
+                        record.needs_reauth = true;
+                    }
+                });
+
+                if invalid_grant {
+                    warn!(
+                        "刷新令牌已失效，需要重新授权, token_id: {}, user_id: {:?}",
+                        id, record.user_id
+                    );
+                    self.webhooks.emit(
+                        WebhookEventType::TokenRevoked,
+                        serde_json::json!({
+                            "token_id": id,
+                            "user_id": record.user_id,
+                            "tenant_url": record.tenant_url,
+                            "reason": "invalid_grant",
+                            "needs_reauth": true,
+                        }),
+                    );
+                    RefreshOutcome::NeedsReauth
+                } else {
+                    warn!("token刷新失败, token_id: {}: {}", id, e);
+                    RefreshOutcome::Failed
+                }
+            }
+        }
+    }
+}
+
+/// 记录是否需要刷新：有刷新令牌、仍然有效且在刷新窗口内过期
+fn is_due(record: &TokenRecord, deadline: chrono::DateTime<Utc>) -> bool {
+    record.refresh_token.is_some()
+        && !record.revoked
+        && !record.needs_reauth
+        && record.expires_at.is_some_and(|expires_at| expires_at <= deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::test_support::spawn_server;
+    use crate::token_store::tests::{record, temp_store_path};
+    use axum::{http::StatusCode, routing::post, Json, Router};
+    use std::net::SocketAddr;
+
+    /// 启动token端点：refresh-ok 轮换刷新令牌，其余刷新令牌返回 invalid_grant
+    async fn spawn_token_server() -> SocketAddr {
+        let app = Router::new().route(
+            "/token",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                if body["refresh_token"] == "refresh-ok" {
+                    (
+                        StatusCode::OK,
+                        Json(serde_json::json!({
+                            "access_token": "access-2",
+                            "expires_in": 3600,
+                            "refresh_token": "refresh-rotated"
+                        })),
+                    )
+                } else {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        Json(serde_json::json!({ "error": "invalid_grant" })),
+                    )
+                }
+            }),
+        );
+        spawn_server(app).await
+    }
+
+    fn refresher(token_store: Arc<TokenStore>) -> Arc<TokenRefresher> {
+        let config = AppConfig::default();
+        let mut refresh = config.refresh;
+        refresh.jitter_seconds = 0;
+        Arc::new(TokenRefresher::new(
+            Arc::new(OAuthService::new(config.oauth).unwrap()),
+            token_store,
+            Arc::new(WebhookDispatcher::new(config.webhook).unwrap()),
+            refresh,
+        ))
+    }
+
+    fn expiring_record(tenant_url: &str, refresh_token: &str) -> TokenRecord {
+        let mut record = record(tenant_url, "access-1");
+        record.refresh_token = Some(refresh_token.into());
+        record.expires_at = Some(Utc::now() + Duration::seconds(60));
+        record
+    }
+
+    async fn run_sweep(refresher: Arc<TokenRefresher>) -> Vec<RefreshOutcome> {
+        let mut outcomes = Vec::new();
+        for handle in refresher.sweep() {
+            outcomes.push(handle.await.unwrap());
+        }
+        outcomes
+    }
+
+    #[tokio::test]
+    async fn test_refreshes_expiring_token_and_persists_rotation() {
+        let addr = spawn_token_server().await;
+        let path = temp_store_path();
+        let token_store = Arc::new(TokenStore::open(&path).unwrap());
+        let record = expiring_record(&format!("http://{}", addr), "refresh-ok");
+        let id = record.id.clone();
+        token_store.insert(record);
+
+        let outcomes = run_sweep(refresher(token_store.clone())).await;
+        assert_eq!(outcomes, vec![RefreshOutcome::Refreshed]);
+
+        let reopened = TokenStore::open(&path).unwrap();
+        let refreshed = reopened.get(&id).unwrap();
+        assert_eq!(refreshed.access_token.as_str(), "access-2");
+        assert_eq!(refreshed.refresh_token.as_ref().unwrap().as_str(), "refresh-rotated");
+        assert!(refreshed.last_refresh_at.is_some());
+        assert!(refreshed.expires_at.unwrap() > Utc::now() + Duration::minutes(50));
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_grant_marks_token_for_reauth() {
+        let addr = spawn_token_server().await;
+        let token_store = Arc::new(TokenStore::new());
+        let record = expiring_record(&format!("http://{}", addr), "refresh-revoked");
+        let id = record.id.clone();
+        token_store.insert(record);
+        let refresher = refresher(token_store.clone());
+
+        let outcomes = run_sweep(refresher.clone()).await;
+        assert_eq!(outcomes, vec![RefreshOutcome::NeedsReauth]);
+
+        let record = token_store.get(&id).unwrap();
+        assert!(record.revoked);
+        assert!(record.needs_reauth);
+        assert!(record.last_refresh_error.unwrap().contains("invalid_grant"));
+
+        // 已标记为需要重新授权的记录不再刷新
+        assert!(run_sweep(refresher).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_introspection_reflects_invalid_grant_revocation() {
+        let addr = spawn_token_server().await;
+        let token_store = Arc::new(TokenStore::new());
+        token_store.insert(expiring_record(&format!("http://{}", addr), "refresh-revoked"));
+        let introspection = crate::introspection::IntrospectionService::new(
+            Arc::new(OAuthService::new(AppConfig::default().oauth).unwrap()),
+            token_store.clone(),
+            30,
+        );
+        assert!(introspection.introspect("access-1", None).await.active);
+
+        run_sweep(refresher(token_store)).await;
+
+        // 缓存中的有效结果不会掩盖吊销
+        let data = introspection.introspect("access-1", None).await;
+        assert!(!data.active);
+        assert!(data.revoked);
+    }
+
+    #[tokio::test]
+    async fn test_skips_tokens_outside_refresh_window() {
+        let addr = spawn_token_server().await;
+        let token_store = Arc::new(TokenStore::new());
+        let mut record = expiring_record(&format!("http://{}", addr), "refresh-ok");
+        record.expires_at = Some(Utc::now() + Duration::hours(2));
+        token_store.insert(record);
+        let mut without_refresh_token = expiring_record(&format!("http://{}", addr), "unused");
+        without_refresh_token.refresh_token = None;
+        token_store.insert(without_refresh_token);
+
+        assert!(run_sweep(refresher(token_store)).await.is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::future::Future;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::telemetry;
+
+/// 请求ID请求头
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// 调用方提供的请求ID最大长度，超过或包含其他字符时重新生成
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// 当前请求的ID，不在请求处理过程中时返回 `None`
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// 让交给后台任务执行的future沿用当前请求的ID和日志span，需要在请求处理过程中调用
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let request_id = current();
+    let future = future.instrument(tracing::Span::current());
+    async move {
+        match request_id {
+            Some(request_id) => REQUEST_ID.scope(request_id, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// 为每个请求确定请求ID
+///
+/// 沿用调用方传入的 `X-Request-Id`，没有或格式不合法时生成新的ID；请求ID记录在请求的日志span中，
+/// 并通过响应头和错误响应返回给调用方。启用链路导出时请求span沿用调用方 `traceparent` 所在的链路。
+pub async fn propagate(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid(value))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    // 链路中的span名使用路由模板，不包含state等路径参数
+    let operation = match request.extensions().get::<MatchedPath>() {
+        Some(route) => format!("{} {}", request.method(), route.as_str()),
+        None => request.method().to_string(),
+    };
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        otel.name = %operation,
+        otel.kind = "server",
+    );
+    span.set_parent(telemetry::extract(request.headers()));
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .instrument(span)
+        .await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER.clone(), value);
+    }
+    response
+}
+
+fn is_valid(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::bad_request;
+    use axum::{body::Body, routing::get, Router};
+    use tower::Service;
+
+    async fn call(app: &mut Router, request_id: Option<&str>) -> (String, serde_json::Value) {
+        let mut request = Request::get("/fail");
+        if let Some(request_id) = request_id {
+            request = request.header("x-request-id", request_id);
+        }
+        let response = app.call(request.body(Body::empty()).unwrap()).await.unwrap();
+        let header = response.headers()["x-request-id"].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (header, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed_in_header_and_error_body() {
+        let mut app = Router::new()
+            .route("/fail", get(|| async { bad_request("无效的请求".to_string()) }))
+            .layer(axum::middleware::from_fn(propagate));
+
+        let (header, body) = call(&mut app, Some("req-123")).await;
+        assert_eq!(header, "req-123");
+        assert_eq!(body["request_id"], "req-123");
+
+        // 不合法的请求ID被替换为新生成的ID
+        let (header, body) = call(&mut app, Some("bad id\twith spaces")).await;
+        assert!(uuid::Uuid::parse_str(&header).is_ok());
+        assert_eq!(body["request_id"], header.as_str());
+
+        let (header, _) = call(&mut app, None).await;
+        assert!(uuid::Uuid::parse_str(&header).is_ok());
+        assert!(current().is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use chrono::{DateTime, Duration, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use crate::models::CompleteAuthData;
+use crate::secret::hex_digest;
+
+struct PendingRetrieval {
+    data: CompleteAuthData,
+    expires_at: DateTime<Utc>,
+}
+
+/// 一次性取回码 (sha256(code) -> 完成结果)
+///
+/// 浏览器回调完成授权后，只把取回码带回应用地址；应用在服务端用取回码换取令牌，
+/// 令牌不会出现在浏览器地址栏和历史记录中。每个令牌只签发一个取回码。
+pub struct RetrievalCodes {
+    pending: DashMap<String, PendingRetrieval>,
+    /// 已签发取回码的token记录 (token id -> 签发时间)
+    issued: DashMap<String, DateTime<Utc>>,
+    ttl: Duration,
+    /// 签发记录的保留时间，不短于完成授权的重放窗口
+    issued_retention: Duration,
+}
+
+impl RetrievalCodes {
+    pub fn new(ttl_seconds: u64, replay_seconds: u64) -> Self {
+        Self {
+            pending: DashMap::new(),
+            issued: DashMap::new(),
+            ttl: Duration::seconds(ttl_seconds as i64),
+            issued_retention: Duration::seconds(ttl_seconds.max(replay_seconds) as i64),
+        }
+    }
+
+    /// 保存完成结果，返回取回码；该令牌已签发过取回码时返回 `None`
+    ///
+    /// 重放窗口内重复的回调会得到同一个完成结果，不能再为它签发新的取回码。
+    pub fn issue(&self, data: CompleteAuthData) -> Option<String> {
+        match self.issued.entry(data.token_info.id.clone()) {
+            Entry::Occupied(_) => return None,
+            Entry::Vacant(entry) => {
+                entry.insert(Utc::now());
+            }
+        }
+
+        let code = generate_code();
+        self.pending.insert(
+            hex_digest(&code),
+            PendingRetrieval {
+                data,
+                expires_at: Utc::now() + self.ttl,
+            },
+        );
+        Some(code)
+    }
+
+    /// 用取回码换取完成结果，每个取回码只能使用一次
+    pub fn redeem(&self, code: &str) -> Option<CompleteAuthData> {
+        let (_, pending) = self.pending.remove(&hex_digest(code))?;
+        (Utc::now() <= pending.expires_at).then_some(pending.data)
+    }
+
+    /// 清理过期的取回码和签发记录
+    pub fn prune(&self) {
+        let now = Utc::now();
+        self.pending.retain(|_, pending| now <= pending.expires_at);
+        self.issued
+            .retain(|_, issued_at| now - *issued_at <= self.issued_retention);
+    }
+}
+
+fn generate_code() -> String {
+    use base64::{engine::general_purpose, Engine as _};
+    use rand::Rng;
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::completed_data;
+
+    #[test]
+    fn test_code_can_only_be_redeemed_once() {
+        let codes = RetrievalCodes::new(60, 60);
+        let code = codes.issue(completed_data()).unwrap();
+
+        assert!(codes.redeem("unknown").is_none());
+        assert_eq!(codes.redeem(&code).unwrap().token.as_str(), "access-token");
+        assert!(codes.redeem(&code).is_none());
+    }
+
+    #[test]
+    fn test_expired_code_is_rejected() {
+        let codes = RetrievalCodes::new(0, 0);
+        let code = codes.issue(completed_data()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        assert!(codes.redeem(&code).is_none());
+
+        codes.issue(completed_data());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        codes.prune();
+        assert!(codes.pending.is_empty());
+        assert!(codes.issued.is_empty());
+    }
+
+    #[test]
+    fn test_one_code_per_token() {
+        let codes = RetrievalCodes::new(60, 60);
+        let data = completed_data();
+
+        assert!(codes.issue(data.clone()).is_some());
+        assert!(codes.issue(data).is_none());
+        assert_eq!(codes.pending.len(), 1);
+    }
+}
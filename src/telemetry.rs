@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use axum::http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::TracerProvider as _,
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
+use std::collections::HashMap;
+use tracing::{info, warn, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::config::{OtlpProtocol, TelemetryConfig};
+
+/// 导出链路时使用的instrumentation名称
+const TRACER_NAME: &str = "augment-oauth-service";
+
+/// 按配置创建OTLP链路导出，未配置收集器地址时返回 `None`
+///
+/// 启用后同时注册W3C Trace Context传播器，[`extract`] 和 [`inject`] 才会读写 `traceparent`。
+/// 进程退出前需要调用 [`shutdown`]，否则缓冲中的span会丢失。
+pub fn init(config: &TelemetryConfig) -> Result<Option<TracerProvider>> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let exporter = match config.otlp_protocol {
+        OtlpProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build(),
+        // 与 OTEL_EXPORTER_OTLP_ENDPOINT 的语义一致：配置的是收集器根地址
+        OtlpProtocol::HttpProtobuf => SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build(),
+    }
+    .map_err(|e| anyhow!("无法创建OTLP导出 '{}': {}", endpoint, e))?;
+
+    let mut attributes = vec![
+        KeyValue::new("service.name", config.service_name.clone()),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ];
+    attributes.extend(
+        config
+            .resource_attributes
+            .iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+    );
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sampling_ratio,
+        ))))
+        .with_resource(Resource::new(attributes))
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    info!(
+        "链路导出已启用: {} ({:?}), 采样比例: {}",
+        endpoint, config.otlp_protocol, config.sampling_ratio
+    );
+    Ok(Some(provider))
+}
+
+/// 把tracing的span导出为OpenTelemetry span的日志层
+pub fn layer<S>(provider: &TracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(TRACER_NAME))
+}
+
+/// 导出剩余的span并停止导出
+pub async fn shutdown(provider: TracerProvider) {
+    // 关闭时同步等待批量导出任务，不能阻塞运行时的工作线程
+    let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    if let Ok(Err(e)) = result {
+        warn!("链路导出关闭失败: {}", e);
+    }
+}
+
+/// 读取调用方传入的 `traceparent`，作为当前请求span的上级
+pub fn extract(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// 在发出的请求中附带当前span的 `traceparent`，授权服务器的链路可以接在本服务之后
+pub fn inject(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut headers)
+    });
+    headers
+        .into_iter()
+        .fold(request, |request, (name, value)| request.header(name, value))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id;
+    use axum::{body::Body, extract::Request, routing::get, Router};
+    use opentelemetry::trace::SpanKind;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use tower::Service;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    const PARENT_SPAN_ID: &str = "b7ad6b7169203331";
+
+    /// 返回本服务向下游发出请求时会附带的 `traceparent`
+    async fn outgoing_traceparent() -> String {
+        let request = inject(reqwest::Client::new().get("http://tenant.example/token"))
+            .build()
+            .unwrap();
+        request.headers()["traceparent"].to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_traceparent_is_continued_and_forwarded() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(layer(&provider)));
+
+        let mut app = Router::new()
+            .route("/api/auth-status/:state", get(outgoing_traceparent))
+            .layer(axum::middleware::from_fn(request_id::propagate));
+        let request = Request::get("/api/auth-status/state-a")
+            .header("traceparent", format!("00-{}-{}-01", TRACE_ID, PARENT_SPAN_ID))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let forwarded = String::from_utf8(body.to_vec()).unwrap();
+
+        // 下游收到的是同一条链路，上级为本服务的span
+        let parts: Vec<_> = forwarded.split('-').collect();
+        assert_eq!(parts[1], TRACE_ID, "{}", forwarded);
+        assert_ne!(parts[2], PARENT_SPAN_ID);
+        assert_eq!(parts[3], "01");
+
+        provider.force_flush();
+        let spans = exporter.get_finished_spans().unwrap();
+        let span = spans.iter().find(|span| span.span_kind == SpanKind::Server).unwrap();
+        assert_eq!(span.name, "GET /api/auth-status/:state");
+        assert_eq!(span.span_context.trace_id().to_string(), TRACE_ID);
+        assert_eq!(span.parent_span_id.to_string(), PARENT_SPAN_ID);
+        assert_eq!(span.span_context.span_id().to_string(), parts[2]);
+    }
+}
@@ -0,0 +1,32 @@
+//! 测试辅助：在本地随机端口上启动模拟的授权服务器、webhook接收端等，以及共用的测试数据
+
+use axum::Router;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+use crate::models::CompleteAuthData;
+use crate::token_store::tests::record;
+
+/// 在127.0.0.1的随机端口上后台运行路由，返回监听地址
+pub(crate) async fn spawn_server(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+/// 成功完成授权的结果，令牌为 `access-token`
+pub(crate) fn completed_data() -> CompleteAuthData {
+    let record = record("https://tenant.example/", "access-token");
+    CompleteAuthData {
+        status: "success".to_string(),
+        token_info: record.token_info(),
+        token: record.access_token,
+        tenant_url: record.tenant_url,
+        dpop_key: None,
+        identity: None,
+        metadata: Default::default(),
+    }
+}
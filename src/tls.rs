@@ -0,0 +1,480 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, sync::watch};
+use tokio_rustls::{rustls, TlsAcceptor};
+use tracing::{debug, error, info, warn};
+
+use crate::shutdown::Shutdown;
+
+/// TLS握手的最长等待时间，避免慢速客户端长期占用连接
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 接受连接失败后的等待时间，与 `axum::serve` 一致
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// 可热更新的TLS证书
+///
+/// 重新加载只影响之后的握手，已建立的连接继续使用原来的会话。
+pub struct TlsReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+    modified: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl TlsReloader {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let modified = (modified_time(&cert_path), modified_time(&key_path));
+        let acceptor = load_acceptor(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            acceptor: RwLock::new(acceptor),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    /// 当前用于新连接的握手配置
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// 重新读取证书和私钥，失败时继续使用原来的证书
+    pub fn reload(&self) -> Result<()> {
+        let modified = (
+            modified_time(&self.cert_path),
+            modified_time(&self.key_path),
+        );
+        let acceptor = load_acceptor(&self.cert_path, &self.key_path)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        *self.modified.write().unwrap() = modified;
+        info!("已重新加载TLS证书: {}", self.cert_path.display());
+        Ok(())
+    }
+
+    /// 证书或私钥文件的修改时间变化时重新加载
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = (
+            modified_time(&self.cert_path),
+            modified_time(&self.key_path),
+        );
+        if *self.modified.read().unwrap() == modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// 定期检查证书文件，并在收到SIGHUP时立即重新加载
+    pub fn spawn_watcher(self: &Arc<Self>, interval: Duration) {
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval.max(Duration::from_secs(1)));
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            #[cfg(unix)]
+            let mut hangup =
+                match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                    Ok(signal) => Some(signal),
+                    Err(e) => {
+                        warn!("无法监听SIGHUP，只能通过文件变化重新加载证书: {}", e);
+                        None
+                    }
+                };
+
+            loop {
+                #[cfg(unix)]
+                let forced = tokio::select! {
+                    _ = ticker.tick() => false,
+                    Some(()) = async {
+                        match hangup.as_mut() {
+                            Some(signal) => signal.recv().await,
+                            None => std::future::pending().await,
+                        }
+                    } => true,
+                };
+                #[cfg(not(unix))]
+                let forced = {
+                    ticker.tick().await;
+                    false
+                };
+
+                let result = if forced {
+                    info!("收到SIGHUP，重新加载TLS证书");
+                    reloader.reload()
+                } else {
+                    reloader.reload_if_changed().map(|_| ())
+                };
+                if let Err(e) = result {
+                    error!("重新加载TLS证书失败，继续使用当前证书: {:#}", e);
+                }
+            }
+        });
+    }
+}
+
+fn modified_time(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn load_acceptor(cert_path: &std::path::Path, key_path: &std::path::Path) -> Result<TlsAcceptor> {
+    let cert_pem = std::fs::read(cert_path)
+        .with_context(|| format!("无法读取TLS证书 {}", cert_path.display()))?;
+    let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .with_context(|| format!("无法解析TLS证书 {}", cert_path.display()))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("TLS证书文件 {} 中没有证书", cert_path.display()));
+    }
+
+    let key_pem = std::fs::read(key_path)
+        .with_context(|| format!("无法读取TLS私钥 {}", key_path.display()))?;
+    let key = rustls_pemfile::read_all(&mut key_pem.as_slice())
+        .with_context(|| format!("无法解析TLS私钥 {}", key_path.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(rustls::PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("TLS私钥文件 {} 中没有私钥", key_path.display()))?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("TLS证书与私钥不匹配: {}", e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// 在HTTPS上提供服务
+///
+/// 收到停止通知后不再接受新连接，等待已有连接处理完当前请求后返回。
+pub async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    reloader: Arc<TlsReloader>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    // 每个连接持有一个接收端，全部连接结束后 closed() 完成
+    let (drained_tx, drained_rx) = watch::channel(());
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // 文件描述符耗尽等错误会立即再次出现，等待一段时间避免空转占满CPU
+                    warn!("接受连接失败: {}", e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => continue,
+                        _ = shutdown.wait() => break,
+                    }
+                }
+            },
+            _ = shutdown.wait() => break,
+        };
+        let acceptor = reloader.acceptor();
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+        let drained_rx = drained_rx.clone();
+
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS握手失败 ({}): {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS握手超时 ({})", peer);
+                    return;
+                }
+            };
+
+            let service = hyper::service::service_fn(move |mut request: Request<hyper::body::Incoming>| {
+                // 与 into_make_service_with_connect_info 一致，供限流读取客户端地址
+                request.extensions_mut().insert(ConnectInfo(peer));
+                tower::Service::call(&mut app.clone(), request)
+            });
+            let connection = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.wait() => {
+                    // 处理完当前请求后关闭连接
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("HTTPS连接异常结束 ({}): {}", peer, e);
+            }
+            drop(drained_rx);
+        });
+    }
+
+    drop(listener);
+    drop(drained_rx);
+    drained_tx.closed().await;
+    Ok(())
+}
+
+/// 将HTTP请求重定向到HTTPS端口
+pub async fn serve_redirect(listener: TcpListener, https_port: u16, shutdown: Shutdown) -> Result<()> {
+    let app = Router::new().fallback(move |request: Request| async move {
+        redirect_to_https(
+            request.headers().get(header::HOST),
+            request.uri(),
+            https_port,
+        )
+    });
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.wait())
+        .await?;
+    Ok(())
+}
+
+fn redirect_to_https(host: Option<&header::HeaderValue>, uri: &Uri, https_port: u16) -> Response {
+    let Some(host) = host
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<axum::http::uri::Authority>().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "缺少Host请求头").into_response();
+    };
+
+    let authority = if https_port == 443 {
+        host.host().to_string()
+    } else {
+        format!("{}:{}", host.host(), https_port)
+    };
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    struct TestCerts {
+        dir: PathBuf,
+    }
+
+    impl TestCerts {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("oauth-tls-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        fn cert_path(&self) -> PathBuf {
+            self.dir.join("server.pem")
+        }
+
+        fn key_path(&self) -> PathBuf {
+            self.dir.join("server.key")
+        }
+
+        /// 写入新的自签名证书，返回证书DER
+        fn rotate(&self) -> Vec<u8> {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            // 每次序列化都会重新签名，PEM需要从同一份DER生成
+            let der = cert.serialize_der().unwrap();
+            let pem = format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &der)
+            );
+            std::fs::write(self.cert_path(), pem).unwrap();
+            std::fs::write(self.key_path(), cert.serialize_private_key_pem()).unwrap();
+            der
+        }
+    }
+
+    impl Drop for TestCerts {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        trusted: &[u8],
+    ) -> std::io::Result<TlsStream<tokio::net::TcpStream>> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(trusted.to_vec())).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(rustls::ServerName::try_from("localhost").unwrap(), stream)
+            .await
+    }
+
+    /// 在保持连接上发送一个请求，返回响应体
+    async fn get_body<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+        stream: &mut S,
+        path: &str,
+    ) -> String {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "连接意外关闭");
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(str::to_string)
+                    })
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+                    return body.to_string();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_serves_new_certificate_without_dropping_connections() {
+        let certs = TestCerts::new();
+        let first = certs.rotate();
+        let reloader = Arc::new(TlsReloader::new(certs.cert_path(), certs.key_path()).unwrap());
+
+        let app = Router::new().route(
+            "/peer",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tls(listener, app, reloader.clone(), Shutdown::new()));
+
+        let mut old = connect(addr, &first).await.unwrap();
+        assert_eq!(get_body(&mut old, "/peer").await, "127.0.0.1");
+
+        let second = certs.rotate();
+        reloader.reload().unwrap();
+        assert!(!reloader.reload_if_changed().unwrap());
+
+        // 新连接使用新证书
+        let mut new = connect(addr, &second).await.unwrap();
+        let presented = new.get_ref().1.peer_certificates().unwrap()[0].0.clone();
+        assert_eq!(presented, second);
+        assert_eq!(get_body(&mut new, "/peer").await, "127.0.0.1");
+        assert!(connect(addr, &first).await.is_err());
+
+        // 已建立的连接不受影响
+        assert_eq!(get_body(&mut old, "/peer").await, "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_requests() {
+        let certs = TestCerts::new();
+        let cert = certs.rotate();
+        let reloader = Arc::new(TlsReloader::new(certs.cert_path(), certs.key_path()).unwrap());
+
+        let app = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "done"
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve_tls(listener, app, reloader, shutdown.clone()));
+
+        let mut stream = connect(addr, &cert).await.unwrap();
+        let request = tokio::spawn(async move { get_body(&mut stream, "/slow").await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.trigger();
+
+        // 进行中的请求正常完成，之后服务返回且不再接受新连接
+        assert_eq!(request.await.unwrap(), "done");
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(connect(addr, &cert).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_certificate_keeps_current_one() {
+        let certs = TestCerts::new();
+        let current = certs.rotate();
+        let reloader = TlsReloader::new(certs.cert_path(), certs.key_path()).unwrap();
+
+        std::fs::write(certs.key_path(), "not a key").unwrap();
+        assert!(reloader.reload().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tls(listener, Router::new(), Arc::new(reloader), Shutdown::new()));
+        assert!(connect(addr, &current).await.is_ok());
+
+        assert!(TlsReloader::new(certs.dir.join("missing.pem"), certs.key_path()).is_err());
+    }
+
+    #[test]
+    fn test_redirect_to_https() {
+        let uri: Uri = "/api/auth-url?user_id=u1".parse().unwrap();
+        let location = |host: &str, port: u16| {
+            let host = header::HeaderValue::from_str(host).unwrap();
+            let response = redirect_to_https(Some(&host), &uri, port);
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+            response.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            location("example.com:8080", 443),
+            "https://example.com/api/auth-url?user_id=u1"
+        );
+        assert_eq!(
+            location("example.com", 8443),
+            "https://example.com:8443/api/auth-url?user_id=u1"
+        );
+        assert_eq!(
+            redirect_to_https(None, &uri, 443).status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
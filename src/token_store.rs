@@ -0,0 +1,236 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info};
+
+use crate::models::TokenRecord;
+
+/// 已签发token的存储 (token_id -> TokenRecord)
+///
+/// 配置了持久化文件时，每次变更都会以“写临时文件 + 重命名”的方式原子地写入磁盘。
+pub struct TokenStore {
+    tokens: DashMap<String, TokenRecord>,
+    /// 访问令牌索引 (access_token -> token_id)
+    by_access_token: DashMap<String, String>,
+    /// 持久化文件路径
+    path: Option<PathBuf>,
+    /// 串行化写盘
+    persist_lock: Mutex<()>,
+}
+
+impl TokenStore {
+    pub fn new() -> Self {
+        Self {
+            tokens: DashMap::new(),
+            by_access_token: DashMap::new(),
+            path: None,
+            persist_lock: Mutex::new(()),
+        }
+    }
+
+    /// 创建持久化到文件的存储，文件存在时加载已有记录
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut store = Self::new();
+
+        if path.exists() {
+            let records: Vec<TokenRecord> = serde_json::from_slice(&std::fs::read(&path)?)?;
+            info!("从 {} 加载了 {} 条token记录", path.display(), records.len());
+            for record in records {
+                store.index(record);
+            }
+        }
+
+        store.path = Some(path);
+        Ok(store)
+    }
+
+    /// 保存token记录
+    pub fn insert(&self, record: TokenRecord) {
+        self.index(record);
+        self.persist();
+    }
+
+    /// 更新token记录，记录内容整体替换后再写盘
+    pub fn update<F>(&self, id: &str, f: F) -> Option<TokenRecord>
+    where
+        F: FnOnce(&mut TokenRecord),
+    {
+        let updated = {
+            let mut entry = self.tokens.get_mut(id)?;
+            let old_access_token = entry.access_token.clone();
+            f(&mut entry);
+
+            if entry.access_token != old_access_token {
+                self.by_access_token.remove(old_access_token.as_str());
+                self.by_access_token
+                    .insert(entry.access_token.expose().clone(), entry.id.clone());
+            }
+            entry.clone()
+        };
+
+        self.persist();
+        Some(updated)
+    }
+
+    /// 获取token记录
+    pub fn get(&self, id: &str) -> Option<TokenRecord> {
+        self.tokens.get(id).map(|record| record.clone())
+    }
+
+    /// 按访问令牌查找token记录
+    pub fn find_by_access_token(&self, access_token: &str) -> Option<TokenRecord> {
+        let id = self.by_access_token.get(access_token)?.clone();
+        self.tokens.get(&id).map(|record| record.clone())
+    }
+
+    /// 所有token记录
+    pub fn list(&self) -> Vec<TokenRecord> {
+        self.tokens.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    /// 当前存储的token数量
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// 将全部记录写入持久化文件
+    pub fn flush(&self) {
+        self.persist();
+    }
+
+    fn index(&self, record: TokenRecord) {
+        self.by_access_token
+            .insert(record.access_token.expose().clone(), record.id.clone());
+        self.tokens.insert(record.id.clone(), record);
+    }
+
+    /// 将全部记录写入持久化文件
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let _guard = self.persist_lock.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = write_atomically(path, &self.list()) {
+            error!("token记录持久化失败 {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Default for TokenStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 先写入同目录下的临时文件并同步到磁盘，再重命名覆盖目标文件
+///
+/// 文件中保存令牌和DPoP私钥，Unix上只允许所有者读写 (0600)。
+pub fn write_atomically<T: serde::Serialize>(path: &Path, value: &T) -> Result<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    // 上次写入中断时残留的临时文件可能带有宽松的权限，先删除再以0600创建
+    let _ = std::fs::remove_file(&tmp_path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(value)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    pub(crate) fn record(tenant_url: &str, access_token: &str) -> TokenRecord {
+        TokenRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: Some("user-1".to_string()),
+            tenant_url: tenant_url.to_string(),
+            access_token: access_token.into(),
+            token_type: "Bearer".to_string(),
+            refresh_token: None,
+            expires_at: Some(Utc::now() + Duration::hours(1)),
+            created_at: Utc::now(),
+            dpop_key: None,
+            dpop_jkt: None,
+            identity: None,
+            revoked: false,
+            needs_reauth: false,
+            last_refresh_at: None,
+            last_refresh_error: None,
+        }
+    }
+
+    pub(crate) fn temp_store_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("token-store-{}", uuid::Uuid::new_v4()))
+            .join("tokens.json")
+    }
+
+    #[test]
+    fn test_update_reindexes_access_token() {
+        let store = TokenStore::new();
+        let record = record("https://tenant.example/", "old-token");
+        let id = record.id.clone();
+        store.insert(record);
+
+        store.update(&id, |record| record.access_token = "new-token".into());
+
+        assert!(store.find_by_access_token("old-token").is_none());
+        assert_eq!(store.find_by_access_token("new-token").unwrap().id, id);
+    }
+
+    #[test]
+    fn test_persisted_records_survive_reopen() {
+        let path = temp_store_path();
+        let mut record = record("https://tenant.example/", "access-token");
+        record.refresh_token = Some("refresh-1".into());
+        let id = record.id.clone();
+
+        {
+            let store = TokenStore::open(&path).unwrap();
+            store.insert(record);
+            store.update(&id, |record| record.refresh_token = Some("refresh-2".into()));
+        }
+
+        let reopened = TokenStore::open(&path).unwrap();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened.get(&id).unwrap().refresh_token.unwrap().as_str(), "refresh-2");
+        assert!(reopened.find_by_access_token("access-token").is_some());
+        assert!(!path.with_extension("tmp").exists());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_persisted_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_store_path();
+        let store = TokenStore::open(&path).unwrap();
+        store.insert(record("https://tenant.example/", "access-token"));
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,423 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::config::WebhookConfig;
+use crate::secret::Secret;
+use crate::shutdown::Shutdown;
+use crate::token_store::write_atomically;
+
+/// 授权生命周期事件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEventType {
+    AuthUrlCreated,
+    AuthCompleted,
+    AuthFailed,
+    AuthCancelled,
+    StateExpired,
+    TokenRefreshed,
+    TokenRevoked,
+}
+
+impl WebhookEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AuthUrlCreated => "auth_url.created",
+            Self::AuthCompleted => "auth.completed",
+            Self::AuthFailed => "auth.failed",
+            Self::AuthCancelled => "auth.cancelled",
+            Self::StateExpired => "state.expired",
+            Self::TokenRefreshed => "token.refreshed",
+            Self::TokenRevoked => "token.revoked",
+        }
+    }
+}
+
+/// 推送给订阅方的事件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created_at: DateTime<Utc>,
+    pub data: Value,
+}
+
+/// 待投递的webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WebhookDelivery {
+    url: String,
+    event: WebhookEvent,
+    attempts: u32,
+    next_attempt_at: DateTime<Utc>,
+    last_error: Option<String>,
+}
+
+/// webhook投递器
+///
+/// 事件先写入持久化队列，由后台任务按指数退避重试投递，服务重启后继续投递未完成的事件。
+pub struct WebhookDispatcher {
+    config: WebhookConfig,
+    http_client: reqwest::Client,
+    queue: Mutex<Vec<WebhookDelivery>>,
+    queue_path: Option<PathBuf>,
+    notify: Notify,
+}
+
+impl WebhookDispatcher {
+    pub fn new(config: WebhookConfig) -> Result<Self> {
+        if !config.urls.is_empty() && config.secret.is_none() {
+            return Err(anyhow!("已配置 WEBHOOK_URLS，但未配置签名密钥 WEBHOOK_SECRET"));
+        }
+
+        let queue_path = config.queue_path.as_ref().map(PathBuf::from);
+        let queue = match &queue_path {
+            Some(path) if path.exists() => {
+                let queue: Vec<WebhookDelivery> = serde_json::from_slice(&std::fs::read(path)?)?;
+                if !queue.is_empty() {
+                    info!("从 {} 恢复了 {} 条待投递的webhook", path.display(), queue.len());
+                }
+                queue
+            }
+            _ => Vec::new(),
+        };
+
+        let http_client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(config.timeout_seconds))
+            .build()?;
+
+        Ok(Self {
+            config,
+            http_client,
+            queue: Mutex::new(queue),
+            queue_path,
+            notify: Notify::new(),
+        })
+    }
+
+    /// 发布事件，为每个订阅地址加入投递队列
+    pub fn emit(&self, event_type: WebhookEventType, data: Value) {
+        if self.config.urls.is_empty() || !self.subscribed(event_type) {
+            return;
+        }
+
+        let event = WebhookEvent {
+            id: Uuid::new_v4().to_string(),
+            event_type: event_type.as_str().to_string(),
+            created_at: Utc::now(),
+            data,
+        };
+        debug!("发布webhook事件: {} {}", event.event_type, event.id);
+
+        {
+            let mut queue = self.lock_queue();
+            for url in &self.config.urls {
+                queue.push(WebhookDelivery {
+                    url: url.clone(),
+                    event: event.clone(),
+                    attempts: 0,
+                    next_attempt_at: event.created_at,
+                    last_error: None,
+                });
+            }
+            self.persist(&queue);
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// 待投递的webhook数量
+    pub fn pending_count(&self) -> usize {
+        self.lock_queue().len()
+    }
+
+    /// 启动后台投递任务，收到停止通知后投递完当前到期的事件再退出
+    pub fn spawn(self: Arc<Self>, shutdown: Shutdown) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.process_due().await;
+                tokio::select! {
+                    _ = self.notify.notified() => {}
+                    _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
+                    _ = shutdown.wait() => {
+                        self.process_due().await;
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// 将投递队列写入持久化文件
+    pub fn flush(&self) {
+        let queue = self.lock_queue();
+        self.persist(&queue);
+    }
+
+    /// 投递所有到期的webhook
+    pub async fn process_due(&self) {
+        let now = Utc::now();
+        let due: Vec<WebhookDelivery> = self
+            .lock_queue()
+            .iter()
+            .filter(|delivery| delivery.next_attempt_at <= now)
+            .cloned()
+            .collect();
+
+        for delivery in due {
+            let result = self.deliver(&delivery).await;
+
+            let mut queue = self.lock_queue();
+            let Some(index) = queue
+                .iter()
+                .position(|d| d.event.id == delivery.event.id && d.url == delivery.url)
+            else {
+                continue;
+            };
+
+            match result {
+                Ok(()) => {
+                    debug!("webhook投递成功: {} -> {}", delivery.event.event_type, delivery.url);
+                    queue.remove(index);
+                }
+                Err(e) => {
+                    let entry = &mut queue[index];
+                    entry.attempts += 1;
+                    entry.last_error = Some(e.to_string());
+
+                    if entry.attempts >= self.config.max_attempts {
+                        error!(
+                            "webhook投递失败次数达到上限，放弃投递: {} {} -> {}: {}",
+                            entry.event.event_type, entry.event.id, entry.url, e
+                        );
+                        queue.remove(index);
+                    } else {
+                        entry.next_attempt_at = Utc::now() + self.backoff(entry.attempts);
+                        warn!(
+                            "webhook投递失败，将于 {} 重试: {} -> {}: {}",
+                            entry.next_attempt_at, entry.event.event_type, entry.url, e
+                        );
+                    }
+                }
+            }
+            self.persist(&queue);
+        }
+    }
+
+    async fn deliver(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let body = serde_json::to_string(&delivery.event)?;
+        let timestamp = Utc::now().timestamp().to_string();
+        let secret = self.config.secret.as_ref().map(Secret::as_str).unwrap_or_default();
+
+        let response = self
+            .http_client
+            .post(&delivery.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Id", &delivery.event.id)
+            .header("X-Webhook-Event", &delivery.event.event_type)
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header("X-Webhook-Signature", sign(secret, &timestamp, &body))
+            .body(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("订阅方返回 {}", response.status()));
+        }
+        Ok(())
+    }
+
+    fn subscribed(&self, event_type: WebhookEventType) -> bool {
+        self.config.events.is_empty()
+            || self
+                .config
+                .events
+                .iter()
+                .any(|event| event == event_type.as_str())
+    }
+
+    /// 指数退避：base * 2^(attempts-1)，最长1小时
+    fn backoff(&self, attempts: u32) -> Duration {
+        let seconds = self
+            .config
+            .retry_base_seconds
+            .saturating_mul(1u64 << attempts.saturating_sub(1).min(16));
+        Duration::seconds(seconds.min(3600) as i64)
+    }
+
+    fn lock_queue(&self) -> std::sync::MutexGuard<'_, Vec<WebhookDelivery>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn persist(&self, queue: &[WebhookDelivery]) {
+        if let Some(path) = &self.queue_path {
+            if let Err(e) = write_atomically(path, &queue) {
+                error!("webhook队列持久化失败 {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// 计算签名: `sha256=` + hex(HMAC-SHA256(secret, "{timestamp}.{body}"))
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC接受任意长度的密钥");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::test_support::spawn_server;
+    use axum::{body::Bytes, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// 启动webhook接收方，前 `failures` 次请求返回500
+    async fn spawn_receiver(failures: usize) -> (SocketAddr, Received) {
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: Bytes| {
+                let recorded = recorded.clone();
+                let calls = calls.clone();
+                async move {
+                    if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                        return StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push((headers, String::from_utf8_lossy(&body).to_string()));
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        (spawn_server(app).await, received)
+    }
+
+    fn config(url: String) -> WebhookConfig {
+        let mut config = AppConfig::default().webhook;
+        config.urls = vec![url];
+        config.secret = Some("shared-secret".into());
+        config.retry_base_seconds = 0;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_event() {
+        let (addr, received) = spawn_receiver(0).await;
+        let dispatcher = WebhookDispatcher::new(config(format!("http://{}/hook", addr))).unwrap();
+
+        dispatcher.emit(
+            WebhookEventType::AuthCompleted,
+            serde_json::json!({ "state": "abc" }),
+        );
+        dispatcher.process_due().await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        let timestamp = headers["X-Webhook-Timestamp"].to_str().unwrap();
+        assert_eq!(headers["X-Webhook-Event"], "auth.completed");
+        assert_eq!(
+            headers["X-Webhook-Signature"].to_str().unwrap(),
+            sign("shared-secret", timestamp, body)
+        );
+
+        let event: WebhookEvent = serde_json::from_str(body).unwrap();
+        assert_eq!(event.event_type, "auth.completed");
+        assert_eq!(event.data["state"], "abc");
+        assert_eq!(dispatcher.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retries_failed_delivery() {
+        let (addr, received) = spawn_receiver(2).await;
+        let dispatcher = WebhookDispatcher::new(config(format!("http://{}/hook", addr))).unwrap();
+
+        dispatcher.emit(WebhookEventType::TokenRefreshed, serde_json::json!({}));
+        for _ in 0..3 {
+            dispatcher.process_due().await;
+        }
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(dispatcher.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let (addr, received) = spawn_receiver(usize::MAX).await;
+        let mut config = config(format!("http://{}/hook", addr));
+        config.max_attempts = 2;
+        let dispatcher = WebhookDispatcher::new(config).unwrap();
+
+        dispatcher.emit(WebhookEventType::AuthFailed, serde_json::json!({}));
+        dispatcher.process_due().await;
+        assert_eq!(dispatcher.pending_count(), 1);
+        dispatcher.process_due().await;
+
+        assert_eq!(dispatcher.pending_count(), 0);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_events_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("webhook-queue-{}", Uuid::new_v4()));
+        let mut config = config("http://127.0.0.1:9/hook".to_string());
+        config.queue_path = Some(dir.join("queue.json").to_string_lossy().into_owned());
+
+        {
+            let dispatcher = WebhookDispatcher::new(config.clone()).unwrap();
+            dispatcher.emit(WebhookEventType::StateExpired, serde_json::json!({ "state": "abc" }));
+        }
+
+        let restored = WebhookDispatcher::new(config).unwrap();
+        assert_eq!(restored.pending_count(), 1);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_filters_unsubscribed_events() {
+        let mut config = config("http://127.0.0.1:9/hook".to_string());
+        config.events = vec!["auth.completed".to_string()];
+        let dispatcher = WebhookDispatcher::new(config).unwrap();
+
+        dispatcher.emit(WebhookEventType::AuthUrlCreated, serde_json::json!({}));
+        assert_eq!(dispatcher.pending_count(), 0);
+        dispatcher.emit(WebhookEventType::AuthCompleted, serde_json::json!({}));
+        assert_eq!(dispatcher.pending_count(), 1);
+    }
+
+    #[test]
+    fn test_requires_secret_when_urls_configured() {
+        let mut config = config("http://127.0.0.1:9/hook".to_string());
+        config.secret = None;
+        assert!(WebhookDispatcher::new(config).is_err());
+    }
+}